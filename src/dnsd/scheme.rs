@@ -1,6 +1,7 @@
 use redox_netstack::error::{Error, Result};
 use event::{subscribe_to_fd, unsubscribe_from_fd};
 use std::borrow::ToOwned;
+use std::cmp;
 use std::collections::{BTreeMap, BTreeSet};
 use std::collections::VecDeque;
 use std::collections::btree_map::Entry;
@@ -11,7 +12,7 @@ use std::os::unix::io::RawFd;
 use std::str;
 use std::str::FromStr;
 use std::rc::Rc;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use syscall::data::TimeSpec;
 use syscall::{Error as SyscallError, EventFlags as SyscallEventFlags, Packet as SyscallPacket, Result as SyscallResult, SchemeMut};
 use syscall;
@@ -33,6 +34,12 @@ enum Domain {
     Requested {
         waiting_fds: BTreeSet<usize>,
         socket_fd: RawFd,
+        /// Index of the nameserver asked, the next ones serve as fallbacks
+        server: usize,
+        /// Query types still waiting for an answer
+        pending: Vec<QueryType>,
+        /// Addresses answered so far along with their TTL
+        answers: Vec<(IpAddr, u32)>,
     },
 }
 
@@ -41,8 +48,38 @@ enum DnsParsingResult {
     FailFiles(BTreeSet<usize>),
 }
 
+/// Splits a cache key into the domain name and whether all of its A and
+/// AAAA records were asked for.
+fn split_lookup(key: &str) -> (&str, bool) {
+    let mut parts = key.splitn(2, '?');
+    let name = parts.next().unwrap_or("");
+    (name, parts.next() == Some("all=1"))
+}
+
+/// Newline-separated addresses, IPv6 and IPv4 ones interleaved starting with
+/// IPv6 as Happy Eyeballs clients try them in that order.
+fn format_answers(answers: &[(IpAddr, u32)]) -> String {
+    let v6 = answers.iter().filter(|&&(ip, _)| ip.is_ipv6());
+    let mut v4 = answers.iter().filter(|&&(ip, _)| ip.is_ipv4());
+    let mut result = String::new();
+    for &(ip, _) in v6 {
+        result += &format!("{}\n", ip);
+        if let Some(&(ip, _)) = v4.next() {
+            result += &format!("{}\n", ip);
+        }
+    }
+    for &(ip, _) in v4 {
+        result += &format!("{}\n", ip);
+    }
+    result
+}
+
+fn time_before(a: &TimeSpec, b: &TimeSpec) -> bool {
+    a.tv_sec < b.tv_sec || (a.tv_sec == b.tv_sec && a.tv_nsec < b.tv_nsec)
+}
+
 struct Domains {
-    nameserver: Ipv4Addr,
+    nameservers: Vec<Ipv4Addr>,
     domains: BTreeMap<Rc<str>, Domain>,
     requests: BTreeMap<RawFd, Rc<str>>,
     resolved_timeouts: VecDeque<(TimeSpec, Rc<str>)>,
@@ -52,7 +89,7 @@ struct Domains {
 impl Domains {
     fn new() -> Domains {
         let mut domains = Domains {
-            nameserver: Ipv4Addr::new(8, 8, 8, 8),
+            nameservers: vec![Ipv4Addr::new(8, 8, 8, 8)],
             domains: BTreeMap::new(),
             requests: BTreeMap::new(),
             resolved_timeouts: VecDeque::new(),
@@ -66,39 +103,65 @@ impl Domains {
         if let Ok(mut file) = File::open("netcfg:resolv/nameserver") {
             let mut nameserver = String::new();
             if file.read_to_string(&mut nameserver).is_ok() {
-                if let Some(line) = nameserver.lines().next() {
-                    if let Ok(ip) = Ipv4Addr::from_str(line) {
-                        trace!("Changing nameserver to {}", ip);
-                        self.nameserver = ip;
-                    }
+                let nameservers: Vec<Ipv4Addr> = nameserver
+                    .lines()
+                    .filter_map(|line| Ipv4Addr::from_str(line.trim()).ok())
+                    .collect();
+                if !nameservers.is_empty() {
+                    trace!("Changing nameservers to {:?}", nameservers);
+                    self.nameservers = nameservers;
                 }
             }
         }
     }
 
-    fn request_domain(&mut self, domain: &str, queue_fd: RawFd) -> Option<RawFd> {
-        trace!("Requesting domain {}", domain);
-        let mut builder = Builder::new_query(1, true);
-        builder.add_question(domain, QueryType::A, QueryClass::IN);
-        let packet = builder.build().ok()?;
+    /// Sends a query for each of `qtypes` to the nameserver at index `server`.
+    fn request_domain(
+        &mut self,
+        key: &Rc<str>,
+        qtypes: &[QueryType],
+        server: usize,
+        queue_fd: RawFd,
+    ) -> Option<RawFd> {
+        let (name, _) = split_lookup(key);
+        let nameserver = *self.nameservers.get(server)?;
+        trace!("Requesting domain {} from {}", name, nameserver);
         let udp_fd = syscall::open(
-            &format!("udp:{}:53", self.nameserver),
+            &format!("udp:{}:53", nameserver),
             syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK,
         ).ok()? as RawFd;
-        if syscall::write(udp_fd as usize, &packet) != Ok(packet.len()) {
-            syscall::close(udp_fd as usize).ok()?;
-            return None;
+        for (id, &qtype) in qtypes.iter().enumerate() {
+            let mut builder = Builder::new_query(id as u16 + 1, true);
+            builder.add_question(name, qtype, QueryClass::IN);
+            let sent = match builder.build() {
+                Ok(packet) => syscall::write(udp_fd as usize, &packet) == Ok(packet.len()),
+                Err(_) => false,
+            };
+            if !sent {
+                syscall::close(udp_fd as usize).ok()?;
+                return None;
+            }
         }
         subscribe_to_fd(queue_fd, udp_fd, 0xFFFFFFFF).ok()?;
-        self.requests.insert(udp_fd, domain.to_owned().into());
+        self.requests.insert(udp_fd, Rc::clone(key));
         Some(udp_fd)
     }
 
-    fn on_time_event(&mut self, cur_time: &TimeSpec, queue_fd: RawFd) -> BTreeSet<usize> {
+    fn forget_request(&mut self, socket_fd: RawFd, queue_fd: RawFd) {
+        self.requests.remove(&socket_fd);
+        let _ = unsubscribe_from_fd(queue_fd, socket_fd, 0xFFFFFFFF);
+        let _ = syscall::close(socket_fd as usize);
+    }
+
+    /// Returns the fds to wake up with the answers received, then the fds of
+    /// the requests that timed out without any.
+    fn on_time_event(
+        &mut self,
+        cur_time: &TimeSpec,
+        queue_fd: RawFd,
+    ) -> (BTreeSet<usize>, BTreeSet<usize>) {
         while let Some((timeout, domain)) = self.resolved_timeouts.pop_front() {
-            if timeout.tv_sec > cur_time.tv_sec
-                || (timeout.tv_sec == cur_time.tv_sec && timeout.tv_nsec > cur_time.tv_nsec)
-            {
+            if time_before(cur_time, &timeout) {
                 self.resolved_timeouts.push_front((timeout, domain));
                 break;
             }
@@ -114,111 +177,154 @@ impl Domains {
         }
 
         let mut fds_to_wakeup = BTreeSet::new();
+        let mut fds_to_fail = BTreeSet::new();
 
         while let Some((timeout, domain)) = self.requested_timeouts.pop_front() {
-            if timeout.tv_sec > cur_time.tv_sec
-                || (timeout.tv_sec == cur_time.tv_sec && timeout.tv_nsec > cur_time.tv_nsec)
-            {
+            if time_before(cur_time, &timeout) {
                 self.requested_timeouts.push_front((timeout, domain));
                 break;
             }
-            trace!("Timing out requested domain {:?}", domain);
-            match self.domains.entry(domain) {
-                Entry::Vacant(_) => {}
-                Entry::Occupied(e) => {
-                    if let Domain::Requested { .. } = *e.get() {
-                        if let Domain::Requested {
-                            mut waiting_fds,
-                            socket_fd,
-                        } = e.remove()
-                        {
-                            fds_to_wakeup.append(&mut waiting_fds);
-                            let _ = unsubscribe_from_fd(queue_fd, socket_fd, 0xFFFFFFFF);
-                            let _ = syscall::close(socket_fd as usize);
-                        }
-                    }
+            let (socket_fd, server, pending) = match self.domains.get(&domain) {
+                Some(&Domain::Requested {
+                    socket_fd,
+                    server,
+                    ref pending,
+                    ..
+                }) => (socket_fd, server, pending.clone()),
+                _ => continue,
+            };
+            self.forget_request(socket_fd, queue_fd);
+
+            // Ask the next nameserver for whatever is still unanswered
+            let mut next_server = server + 1;
+            let mut fallback = None;
+            while next_server < self.nameservers.len() && fallback.is_none() {
+                fallback = self.request_domain(&domain, &pending, next_server, queue_fd);
+                if fallback.is_none() {
+                    next_server += 1;
+                }
+            }
+            if let Some(new_socket_fd) = fallback {
+                trace!("Retrying requested domain {:?} on another nameserver", domain);
+                if let Some(&mut Domain::Requested {
+                    ref mut socket_fd,
+                    ref mut server,
+                    ..
+                }) = self.domains.get_mut(&domain)
+                {
+                    *socket_fd = new_socket_fd;
+                    *server = next_server;
+                }
+                let mut timeout = *cur_time;
+                timeout.tv_sec += Dnsd::REQUEST_TIMEOUT_S;
+                self.requested_timeouts.push_back((timeout, domain));
+                continue;
+            }
+
+            if let Some(Domain::Requested {
+                mut waiting_fds,
+                answers,
+                ..
+            }) = self.domains.remove(&domain)
+            {
+                if answers.is_empty() {
+                    trace!("Timing out requested domain {:?}", domain);
+                    fds_to_fail.append(&mut waiting_fds);
+                } else {
+                    // Such as the A records of a lookup whose AAAA query the
+                    // nameservers ignored
+                    trace!("Resolving requested domain {:?} with the answers so far", domain);
+                    self.cache_answers(domain, &answers, cur_time);
+                    fds_to_wakeup.append(&mut waiting_fds);
                 }
             }
         }
 
-        fds_to_wakeup
+        (fds_to_wakeup, fds_to_fail)
     }
 
     fn on_fd_event(&mut self, fd: RawFd, cur_time: &TimeSpec, queue_fd: RawFd) -> Option<DnsParsingResult> {
-        let e = match self.requests.entry(fd) {
-            Entry::Vacant(_) => {
-                return None;
-            }
-            Entry::Occupied(e) => e,
-        };
+        let domain = Rc::clone(self.requests.get(&fd)?);
+        let (name, _) = split_lookup(&domain);
         let mut buf = [0u8; 0x1000];
-        let readed = syscall::read(fd as usize, &mut buf).ok()?;
-        if readed == 0 {
-            return None;
-        }
-        let pkt = DNSPacket::parse(&buf).ok()?;
-        if pkt.header.response_code != ResponseCode::NoError || pkt.answers.is_empty() {
-            if let Some(query) = pkt.questions.iter().next() {
-                if query.qname.to_string().to_lowercase() == e.get().as_ref() {
-                    unsubscribe_from_fd(queue_fd, fd, 0xFFFFFFFF).ok()?;
-                    syscall::close(fd as usize).ok()?;
-                    let domain = e.remove();
-                    self.requested_timeouts
-                        .retain(|&(_, ref d)| d.as_ref() != domain.as_ref());
-                    if let Entry::Occupied(e) = self.domains.entry(domain) {
-                        let domain_data = e.remove();
-                        return if let Domain::Requested { waiting_fds, .. } = domain_data {
-                            Some(DnsParsingResult::FailFiles(waiting_fds))
-                        } else {
-                            None
-                        };
+        // Both the A and AAAA answers may have arrived already
+        while let Ok(readed) = syscall::read(fd as usize, &mut buf) {
+            if readed == 0 {
+                break;
+            }
+            let pkt = match DNSPacket::parse(&buf[..readed]) {
+                Ok(pkt) => pkt,
+                Err(_) => continue,
+            };
+            let qtype = match pkt.questions.iter().next() {
+                Some(query) if query.qname.to_string().to_lowercase() == name => query.qtype,
+                _ => continue,
+            };
+            if let Some(&mut Domain::Requested {
+                ref mut pending,
+                ref mut answers,
+                ..
+            }) = self.domains.get_mut(&domain)
+            {
+                if !pending.contains(&qtype) {
+                    continue;
+                }
+                pending.retain(|&pending| pending != qtype);
+                if pkt.header.response_code != ResponseCode::NoError {
+                    continue;
+                }
+                for answer in pkt.answers {
+                    if answer.name.to_string().to_lowercase() != name {
+                        continue;
+                    }
+                    match answer.data {
+                        RRData::A(ip) => answers.push((IpAddr::V4(ip), answer.ttl)),
+                        RRData::AAAA(ip) => answers.push((IpAddr::V6(ip), answer.ttl)),
+                        _ => {}
                     }
                 }
             }
-            return None;
         }
-        let mut result = String::new();
-        for answer in pkt.answers {
-            if answer.name.to_string().to_lowercase() != e.get().as_ref() {
-                continue;
-            }
-            if let RRData::A(ip) = answer.data {
-                result += &format!("{}\n", ip);
-            }
+
+        match self.domains.get(&domain) {
+            Some(&Domain::Requested { ref pending, .. }) if pending.is_empty() => (),
+            _ => return None,
         }
-        if result.is_empty() {
-            return None;
+        let (waiting_fds, answers) = match self.domains.remove(&domain) {
+            Some(Domain::Requested {
+                waiting_fds,
+                answers,
+                ..
+            }) => (waiting_fds, answers),
+            _ => return None,
+        };
+        self.forget_request(fd, queue_fd);
+        self.requested_timeouts
+            .retain(|&(_, ref d)| d.as_ref() != domain.as_ref());
+
+        if answers.is_empty() {
+            return Some(DnsParsingResult::FailFiles(waiting_fds));
         }
-        let data = Rc::from(result.into_bytes());
-        unsubscribe_from_fd(queue_fd, fd, 0xFFFFFFFF).ok()?;
-        syscall::close(fd as usize).ok()?;
-        let domain = e.remove();
-        let mut domain_data = Domain::Resolved { data };
         trace!("On FD event {} {} resolved", fd, domain);
+        self.cache_answers(domain, &answers, cur_time);
+        Some(DnsParsingResult::WakeUpFiles(waiting_fds))
+    }
 
+    fn cache_answers(&mut self, domain: Rc<str>, answers: &[(IpAddr, u32)], cur_time: &TimeSpec) {
+        // Cached until the first of the records expires
+        let ttl = answers.iter().map(|&(_, ttl)| i64::from(ttl)).min().unwrap_or(0);
         let mut resolved_timeout = *cur_time;
-        resolved_timeout.tv_sec += Dnsd::RESOLVED_TIMEOUT_S;
-
+        resolved_timeout.tv_sec += cmp::min(ttl, Dnsd::RESOLVED_TIMEOUT_S);
+        let i = self
+            .resolved_timeouts
+            .iter()
+            .position(|&(ref timeout, _)| time_before(&resolved_timeout, timeout))
+            .unwrap_or(self.resolved_timeouts.len());
         self.resolved_timeouts
-            .push_back((resolved_timeout, Rc::clone(&domain)));
+            .insert(i, (resolved_timeout, Rc::clone(&domain)));
 
-        self.requested_timeouts
-            .retain(|&(_, ref d)| d.as_ref() != domain.as_ref());
-
-        match self.domains.entry(domain) {
-            Entry::Vacant(e) => {
-                e.insert(domain_data);
-                None
-            }
-            Entry::Occupied(mut e) => {
-                mem::swap(e.get_mut(), &mut domain_data);
-                if let Domain::Requested { waiting_fds, .. } = domain_data {
-                    Some(DnsParsingResult::WakeUpFiles(waiting_fds))
-                } else {
-                    None
-                }
-            }
-        }
+        let data = Rc::from(format_answers(answers).into_bytes());
+        self.domains.insert(domain, Domain::Resolved { data });
     }
 
     fn file_from_domain(&mut self, domain: &str, fd: usize, cur_time: &TimeSpec, queue_fd: RawFd) -> DnsFile {
@@ -239,20 +345,28 @@ impl Domains {
                 }
             }
         } else {
-            if let Some(socket_fd) = self.request_domain(domain, queue_fd) {
+            let key: Rc<str> = domain.to_owned().into();
+            let pending = if split_lookup(domain).1 {
+                vec![QueryType::A, QueryType::AAAA]
+            } else {
+                vec![QueryType::A]
+            };
+            if let Some(socket_fd) = self.request_domain(&key, &pending, 0, queue_fd) {
                 let mut waiting_fds = BTreeSet::new();
-                let domain = domain.to_owned().into();
                 waiting_fds.insert(fd);
                 self.domains.insert(
-                    Rc::clone(&domain),
+                    Rc::clone(&key),
                     Domain::Requested {
                         waiting_fds,
                         socket_fd,
+                        server: 0,
+                        pending,
+                        answers: Vec::new(),
                     },
                 );
                 let mut timeout = *cur_time;
                 timeout.tv_sec += Dnsd::REQUEST_TIMEOUT_S;
-                self.requested_timeouts.push_back((timeout, domain));
+                self.requested_timeouts.push_back((timeout, key));
             }
             DnsFile::Waiting {
                 domain: domain.to_owned(),
@@ -309,14 +423,17 @@ impl Dnsd {
             ));
         }
 
-        let fds_to_wakeup = self.domains.on_time_event(&time, self.queue_fd);
+        let (fds_to_wakeup, fds_to_fail) = self.domains.on_time_event(&time, self.queue_fd);
         if !fds_to_wakeup.is_empty() {
-            for fd in &fds_to_wakeup {
+            self.wakeup_fds(&fds_to_wakeup);
+        }
+        if !fds_to_fail.is_empty() {
+            for fd in &fds_to_fail {
                 if let Some(file) = self.files.get_mut(fd) {
                     *file = DnsFile::Timeout;
                 }
             }
-            self.wakeup_fds(&fds_to_wakeup);
+            self.wakeup_fds(&fds_to_fail);
         }
 
         time.tv_sec += Dnsd::TIME_EVENT_TIMEOUT_S;
@@ -418,10 +535,18 @@ impl Dnsd {
 
 impl SchemeMut for Dnsd {
     fn open(&mut self, url: &str, _flags: usize, _uid: u32, _gid: u32) -> SyscallResult<usize> {
-        let domain = url.to_lowercase();
-        if domain.is_empty() || !Dnsd::validate_domain(&domain) {
+        let url = url.to_lowercase();
+        let mut parts = url.splitn(2, '?');
+        let name = parts.next().unwrap_or("");
+        if name.is_empty() || !Dnsd::validate_domain(name) {
             return Err(SyscallError::new(syscall::EINVAL));
         }
+        // `?all=1` asks for the AAAA records along with the A ones
+        let domain = match parts.next() {
+            None | Some("all=0") => name.to_owned(),
+            Some("all=1") => format!("{}?all=1", name),
+            Some(_) => return Err(SyscallError::new(syscall::EINVAL)),
+        };
         let fd = self.next_fd;
         self.next_fd += 1;
         let mut cur_time = TimeSpec::default();
@@ -1,16 +1,125 @@
-use log::{set_logger_raw, Log, LogLevelFilter, LogMetadata, LogRecord};
+use log::{set_logger_raw, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+use std::collections::VecDeque;
+use std::env;
+use std::fmt::Write;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Records kept in memory unless `SMOLNETD_LOG_RING` says otherwise
+const DEFAULT_RING_SIZE: usize = 256;
+
+/// Window identical messages are collapsed within unless
+/// `SMOLNETD_LOG_REPEAT_MS` says otherwise
+const DEFAULT_REPEAT_WINDOW_MS: u64 = 500;
+
+/// The logger handed to `log`, for `recent_records` to reach its ring
+static LOGGER: AtomicPtr<Logger> = AtomicPtr::new(ptr::null_mut());
 
 struct Logger {
     // file: Mutex<File>,
+    /// One JSON object per line instead of `target: message`, picked with
+    /// `SMOLNETD_LOG_FORMAT=json`
+    json: bool,
+    /// Last records logged, oldest first
+    ring: Mutex<VecDeque<String>>,
+    ring_size: usize,
+    /// Last message printed and how often it was suppressed since
+    repeat: Mutex<Option<Repeat>>,
+    /// How long after an identical message was printed its repeats are
+    /// suppressed, zero printing every message
+    repeat_window: Duration,
+}
+
+struct Repeat {
+    level: LogLevel,
+    target: String,
+    msg: String,
+    printed_at: Instant,
+    count: usize,
 }
 
 impl Logger {
     fn new() -> Logger {
         // let mut file = File::create("/home/user/log.txt").unwrap(); 
+        let ring_size = env::var("SMOLNETD_LOG_RING")
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .unwrap_or(DEFAULT_RING_SIZE);
+        let repeat_window_ms = env::var("SMOLNETD_LOG_REPEAT_MS")
+            .ok()
+            .and_then(|ms| ms.trim().parse().ok())
+            .unwrap_or(DEFAULT_REPEAT_WINDOW_MS);
         Logger {
             // file: Mutex::new(file),
+            json: env::var("SMOLNETD_LOG_FORMAT").map_or(false, |format| format == "json"),
+            ring: Mutex::new(VecDeque::with_capacity(ring_size)),
+            ring_size,
+            repeat: Mutex::new(None),
+            repeat_window: Duration::from_millis(repeat_window_ms),
         }
     }
+
+    fn format(&self, level: LogLevel, target: &str, msg: &str) -> String {
+        if self.json {
+            // Seconds since the epoch, a clock set before it counting as zero
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!(
+                "{{\"ts\":{}.{:09},\"level\":{},\"target\":{},\"msg\":{}}}",
+                ts.as_secs(),
+                ts.subsec_nanos(),
+                json_string(&level.to_string()),
+                json_string(target),
+                json_string(msg)
+            )
+        } else {
+            format!("{}: {}", target, msg)
+        }
+    }
+
+    fn emit(&self, line: String) {
+        println!("{}", line);
+
+        if self.ring_size > 0 {
+            let mut ring = self.ring.lock().unwrap_or_else(|err| err.into_inner());
+            if ring.len() == self.ring_size {
+                ring.pop_front();
+            }
+            ring.push_back(line);
+        }
+    }
+
+    /// Prints how often the last message was suppressed, if it was at all.
+    fn emit_repeats(&self, repeat: &Repeat) {
+        if repeat.count > 0 {
+            let msg = format!("last message repeated {} times", repeat.count);
+            self.emit(self.format(repeat.level, &repeat.target, &msg));
+        }
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
 
 impl Log for Logger {
@@ -19,7 +128,35 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &LogRecord) {
-        println!("{}: {}", record.target(), record.args());
+        let msg = record.args().to_string();
+        if self.repeat_window == Duration::from_secs(0) {
+            self.emit(self.format(record.level(), record.target(), &msg));
+            return;
+        }
+
+        // Like syslog, a run of identical messages is printed once per
+        // window and summed up when another message arrives or the window
+        // lapses, so even an endless flood shows up now and then
+        let now = Instant::now();
+        let mut repeat = self.repeat.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(ref mut repeat) = *repeat {
+            if repeat.msg == msg
+                && repeat.target == record.target()
+                && now.duration_since(repeat.printed_at) < self.repeat_window
+            {
+                repeat.count += 1;
+                return;
+            }
+            self.emit_repeats(repeat);
+        }
+        self.emit(self.format(record.level(), record.target(), &msg));
+        *repeat = Some(Repeat {
+            level: record.level(),
+            target: record.target().to_owned(),
+            msg,
+            printed_at: now,
+            count: 0,
+        });
 
         // writeln!(&mut self.file.lock().unwrap(), "{}: {}", record.target(), record.args());
     }
@@ -29,7 +166,24 @@ pub fn init_logger() {
     unsafe {
         set_logger_raw(|max_log_level| {
             max_log_level.set(LogLevelFilter::Trace);
-            &Logger::new()
+            let logger = Box::into_raw(Box::new(Logger::new()));
+            LOGGER.store(logger, Ordering::SeqCst);
+            logger
         }).expect("Can't initialize logger");
     }
 }
+
+/// Snapshot of the last records logged, oldest first, in the format they
+/// were printed in. Reading doesn't drain the ring, so several readers each
+/// get the full history. `SMOLNETD_LOG_RING` sets how many records are kept,
+/// 256 by default and none with 0.
+pub fn recent_records() -> Vec<String> {
+    let logger = LOGGER.load(Ordering::SeqCst);
+    if logger.is_null() {
+        return Vec::new();
+    }
+    // Leaked by init_logger, so it lives as long as the process
+    let logger = unsafe { &*logger };
+    let ring = logger.ring.lock().unwrap_or_else(|err| err.into_inner());
+    ring.iter().cloned().collect()
+}
@@ -1,19 +1,231 @@
+use byteorder::{ByteOrder, NetworkEndian};
 use smoltcp;
+use smoltcp::iface::SocketHandle;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io::Write;
 use std::rc::Rc;
+use syscall;
+use syscall::data::TimeSpec;
 
 use smoltcp::time::Instant;
-use smoltcp::wire::EthernetAddress;
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    EthernetRepr, Icmpv4DstUnreachable, Icmpv4Message, Icmpv4Packet, IpAddress, IpEndpoint,
+    IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket, UdpPacket,
+};
 use buffer_pool::{Buffer, BufferPool};
 
+/// Copies of the frames seen by the device, queued per capture fd.
+#[derive(Default)]
+pub struct Captures {
+    queues: BTreeMap<usize, VecDeque<(TimeSpec, Vec<u8>)>>,
+}
+
+impl Captures {
+    /// Frames kept for a capture fd that isn't read fast enough, older ones get dropped
+    const MAX_QUEUED_FRAMES: usize = 256;
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub fn add(&mut self, fd: usize) {
+        self.queues.insert(fd, VecDeque::new());
+    }
+
+    pub fn remove(&mut self, fd: usize) {
+        self.queues.remove(&fd);
+    }
+
+    pub fn has_frames(&self, fd: usize) -> bool {
+        self.queues.get(&fd).map_or(false, |queue| !queue.is_empty())
+    }
+
+    pub fn pop(&mut self, fd: usize) -> Option<(TimeSpec, Vec<u8>)> {
+        self.queues.get_mut(&fd).and_then(|queue| queue.pop_front())
+    }
+
+    fn push(&mut self, frame: &[u8]) {
+        let mut time = TimeSpec::default();
+        if syscall::clock_gettime(syscall::CLOCK_REALTIME, &mut time).is_err() {
+            return;
+        }
+        for queue in self.queues.values_mut() {
+            if queue.len() >= Self::MAX_QUEUED_FRAMES {
+                queue.pop_front();
+            }
+            queue.push_back((time, frame.to_vec()));
+        }
+    }
+}
+
+/// Interface-wide packet counters, kept until reset.
+#[derive(Default, Clone, Copy)]
+pub struct DeviceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames lost in the device: received or sent while the link was down,
+    /// held back by a SYN limit or failing to reach the network
+    pub drops: u64,
+    /// Received IPv4 packets smoltcp discarded, whose header, TCP or UDP
+    /// checksum is wrong. Checksums left for the hardware to fill count as
+    /// wrong, when smoltcp turns the packet down for another reason.
+    pub checksum_errors: u64,
+    /// UDP datagrams received for a port without a socket, going by the ICMP
+    /// port unreachable messages answering them
+    pub no_socket: u64,
+}
+
+/// Packets of a socket, by IP protocol, local endpoint and remote endpoint.
+/// Unspecified addresses and a zero remote port match any, so the flow of a
+/// listener or of an unconnected UDP socket covers all of its peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Flow {
+    pub protocol: IpProtocol,
+    pub local: IpEndpoint,
+    pub remote: IpEndpoint,
+}
+
+impl Flow {
+    pub fn new(protocol: IpProtocol, local: IpEndpoint, remote: IpEndpoint) -> Flow {
+        Flow {
+            protocol,
+            local,
+            remote,
+        }
+    }
+
+    /// Whether the packets of `packet`, a flow with both endpoints
+    /// specified, belong to this one. The local port always has to match.
+    fn covers(&self, packet: &Flow) -> bool {
+        fn addr_covers(addr: &IpAddress, packet_addr: &IpAddress) -> bool {
+            !addr.is_specified() || addr == packet_addr
+        }
+        self.protocol == packet.protocol
+            && self.local.port == packet.local.port
+            && addr_covers(&self.local.addr, &packet.local.addr)
+            && addr_covers(&self.remote.addr, &packet.remote.addr)
+            && (self.remote.port == 0 || self.remote.port == packet.remote.port)
+    }
+
+    /// How many of the parts that can be left unspecified are given, the
+    /// narrowest flow covering a packet being the one it belongs to.
+    fn specificity(&self) -> usize {
+        self.local.addr.is_specified() as usize
+            + self.remote.addr.is_specified() as usize
+            + (self.remote.port != 0) as usize
+    }
+}
+
+/// Receive time of a packet, with the source and payload length a read
+/// tells the datagram it returned apart by.
+#[derive(Clone, Copy)]
+struct RxStamp {
+    time: TimeSpec,
+    source: IpEndpoint,
+    len: usize,
+}
+
+/// Packet hooks set up for a socket, and the flow they apply to.
+struct Hooks {
+    flow: Flow,
+    /// Type-of-Service byte of the IPv4 packets sent
+    tos: Option<u8>,
+    /// Clamp of the MSS option of TCP SYN segments
+    mss: Option<u16>,
+    /// Packets go out without the don't-fragment bit
+    no_df: bool,
+    /// UDP datagrams go out without a checksum
+    no_checksum: bool,
+    /// TCP connect SYN retransmissions allowed and SYNs sent so far
+    syn_limit: Option<(u32, u32)>,
+    /// TCP segments with the URG flag are watched for, and whether one
+    /// arrived since the flag was last taken
+    urgent: Option<bool>,
+    /// Received packets are timestamped, with the receive times not picked
+    /// up yet, oldest first
+    rx_stamps: Option<VecDeque<RxStamp>>,
+}
+
+impl Hooks {
+    fn new(flow: Flow) -> Hooks {
+        Hooks {
+            flow,
+            tos: None,
+            mss: None,
+            no_df: false,
+            no_checksum: false,
+            syn_limit: None,
+            urgent: None,
+            rx_stamps: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tos.is_none() && self.mss.is_none() && !self.no_df && !self.no_checksum
+            && self.syn_limit.is_none() && self.urgent.is_none() && self.rx_stamps.is_none()
+    }
+}
+
+/// Socket owning the hooks picked by `has` that apply to `flow`, the one set
+/// up for the narrowest flow covering it.
+fn hook_owner(
+    hooks: &BTreeMap<SocketHandle, Hooks>,
+    flow: &Flow,
+    has: fn(&Hooks) -> bool,
+) -> Option<SocketHandle> {
+    hooks
+        .iter()
+        .filter(|&(_, socket_hooks)| has(socket_hooks) && socket_hooks.flow.covers(flow))
+        .max_by_key(|&(_, socket_hooks)| socket_hooks.flow.specificity())
+        .map(|(&socket, _)| socket)
+}
+
+/// Value of the hook picked by `get` that applies to `flow`.
+fn find_hook<T>(
+    hooks: &BTreeMap<SocketHandle, Hooks>,
+    flow: &Flow,
+    get: fn(&Hooks) -> Option<T>,
+) -> Option<T> {
+    hooks
+        .values()
+        .filter(|socket_hooks| socket_hooks.flow.covers(flow))
+        .filter_map(|socket_hooks| {
+            get(socket_hooks).map(|value| (socket_hooks.flow.specificity(), value))
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+        .map(|(_, value)| value)
+}
+
 struct NetworkDeviceData {
     network_file: Rc<RefCell<File>>,
     input_queue: Rc<RefCell<VecDeque<Buffer>>>,
     local_hwaddr: smoltcp::wire::EthernetAddress,
     buffer_pool: Rc<RefCell<BufferPool>>,
+    /// Per-socket packet hooks. Sockets sharing a port, such as the
+    /// connections accepted from one listener, each keep their own.
+    hooks: BTreeMap<SocketHandle, Hooks>,
+    mtu: usize,
+    /// Static ARP entries, answered by the device itself so that they outlive
+    /// the aging of the interface neighbor cache
+    static_neighbors: BTreeMap<Ipv4Address, EthernetAddress>,
+    /// Frames only go in and out while the link is up
+    link_up: bool,
+    stats: DeviceStats,
+    captures: Rc<RefCell<Captures>>,
+}
+
+impl NetworkDeviceData {
+    fn capture(&self, frame: &[u8]) {
+        let mut captures = self.captures.borrow_mut();
+        if !captures.is_empty() {
+            captures.push(frame);
+        }
+    }
 }
 
 pub struct NetworkDevice {
@@ -21,13 +233,19 @@ pub struct NetworkDevice {
 }
 
 impl NetworkDevice {
+    /// Default and largest frame size, bounded by the buffer pool
     pub const MTU: usize = 1520;
+    /// Smallest frame still carrying the 576 bytes every IPv4 host accepts
+    pub const MIN_MTU: usize = 590;
+    /// Receive times kept for a flow that isn't read fast enough, older ones get dropped
+    const MAX_RX_STAMPS: usize = 256;
 
     pub fn new(
         network_file: Rc<RefCell<File>>,
         input_queue: Rc<RefCell<VecDeque<Buffer>>>,
         local_hwaddr: smoltcp::wire::EthernetAddress,
         buffer_pool: Rc<RefCell<BufferPool>>,
+        captures: Rc<RefCell<Captures>>,
     ) -> NetworkDevice {
         NetworkDevice {
             data: Rc::new(RefCell::new(NetworkDeviceData {
@@ -35,13 +253,561 @@ impl NetworkDevice {
                 input_queue,
                 local_hwaddr,
                 buffer_pool,
+                hooks: BTreeMap::new(),
+                mtu: Self::MTU,
+                static_neighbors: BTreeMap::new(),
+                link_up: true,
+                stats: DeviceStats::default(),
+                captures,
             })),
         }
     }
+
+    pub fn mtu(&self) -> usize {
+        self.data.borrow().mtu
+    }
+
+    pub fn set_mtu(&self, mtu: usize) -> bool {
+        if mtu < Self::MIN_MTU || mtu > Self::MTU {
+            return false;
+        }
+        self.data.borrow_mut().mtu = mtu;
+        true
+    }
+
+    pub fn static_neighbors(&self) -> Vec<(Ipv4Address, EthernetAddress)> {
+        self.data
+            .borrow()
+            .static_neighbors
+            .iter()
+            .map(|(&ip, &mac)| (ip, mac))
+            .collect()
+    }
+
+    /// Adds or replaces the static ARP entry of `ip`, or removes it. Returns
+    /// false when removing an entry that doesn't exist.
+    pub fn set_static_neighbor(&self, ip: Ipv4Address, mac: Option<EthernetAddress>) -> bool {
+        let mut data = self.data.borrow_mut();
+        match mac {
+            Some(mac) => {
+                data.static_neighbors.insert(ip, mac);
+                true
+            }
+            None => data.static_neighbors.remove(&ip).is_some(),
+        }
+    }
+
+    pub fn stats(&self) -> DeviceStats {
+        self.data.borrow().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.data.borrow_mut().stats = DeviceStats::default();
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.data.borrow().link_up
+    }
+
+    /// Brings the link up or down. The network scheme doesn't report a carrier,
+    /// so this is the administrative state.
+    pub fn set_link_up(&self, up: bool) {
+        self.data.borrow_mut().link_up = up;
+    }
+
+    /// Updates the hooks of `socket` through `update`, dropping them once none
+    /// is left. The flow they apply to is the one of the socket when it got
+    /// its first hook, so that it's still known once the socket gets reset.
+    fn update_hooks<F>(&self, socket: SocketHandle, flow: Flow, update: F)
+    where
+        F: FnOnce(&mut Hooks),
+    {
+        let mut data = self.data.borrow_mut();
+        let empty = {
+            let hooks = data.hooks.entry(socket).or_insert_with(|| Hooks::new(flow));
+            update(hooks);
+            hooks.is_empty()
+        };
+        if empty {
+            data.hooks.remove(&socket);
+        }
+    }
+
+    /// Points the hooks of `socket` to `flow`, as when an accepted connection
+    /// narrows down the flow of its listener to its peer.
+    pub fn move_hooks(&self, socket: SocketHandle, flow: Flow) {
+        if let Some(hooks) = self.data.borrow_mut().hooks.get_mut(&socket) {
+            hooks.flow = flow;
+        }
+    }
+
+    /// Drops all the hooks of `socket`.
+    pub fn remove_hooks(&self, socket: SocketHandle) {
+        self.data.borrow_mut().hooks.remove(&socket);
+    }
+
+    /// Clamps the maximum segment size of the TCP connections of `flow`, or
+    /// stops clamping it.
+    pub fn set_mss(&self, socket: SocketHandle, flow: Flow, mss: Option<u16>) {
+        self.update_hooks(socket, flow, |hooks| hooks.mss = mss);
+    }
+
+    /// Clears the don't-fragment bit of the IPv4 packets of `flow`, or leaves
+    /// it set again as smoltcp emits it.
+    pub fn set_dont_frag(&self, socket: SocketHandle, flow: Flow, enabled: bool) {
+        self.update_hooks(socket, flow, |hooks| hooks.no_df = !enabled);
+    }
+
+    /// Stops computing the checksum of the UDP datagrams of `flow`, or starts
+    /// again.
+    pub fn set_udp_checksum(&self, socket: SocketHandle, flow: Flow, enabled: bool) {
+        self.update_hooks(socket, flow, |hooks| hooks.no_checksum = !enabled);
+    }
+
+    /// Limits the SYNs of a connect of `flow` to `retries` retransmissions,
+    /// or lifts the limit. Setting a limit starts counting from zero.
+    pub fn set_syn_retries(&self, socket: SocketHandle, flow: Flow, retries: Option<u32>) {
+        self.update_hooks(socket, flow, |hooks| {
+            hooks.syn_limit = retries.map(|retries| (retries, 0))
+        });
+    }
+
+    /// Starts or stops noting TCP segments of `flow` that carry urgent data.
+    pub fn watch_urgent(&self, socket: SocketHandle, flow: Flow, enabled: bool) {
+        self.update_hooks(socket, flow, |hooks| {
+            hooks.urgent = if enabled {
+                Some(hooks.urgent.unwrap_or(false))
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Whether urgent data arrived for `socket` since the last call.
+    pub fn take_urgent(&self, socket: SocketHandle) -> bool {
+        self.data
+            .borrow_mut()
+            .hooks
+            .get_mut(&socket)
+            .and_then(|hooks| hooks.urgent.as_mut())
+            .map_or(false, |urgent| ::std::mem::replace(urgent, false))
+    }
+
+    /// Starts or stops noting when UDP datagrams, or TCP segments carrying
+    /// data, of `flow` arrive.
+    pub fn watch_rx_stamps(&self, socket: SocketHandle, flow: Flow, enabled: bool) {
+        self.update_hooks(socket, flow, |hooks| {
+            hooks.rx_stamps = if enabled {
+                Some(hooks.rx_stamps.take().unwrap_or_default())
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Receive time of what a read of `socket` returned. For a datagram,
+    /// given by its source and full length, that's the oldest stamp matching
+    /// it, the ones ahead of it belonging to datagrams dropped unread. For a
+    /// stream it's the newest stamp, all of the data having been read.
+    pub fn take_rx_stamp(
+        &self,
+        socket: SocketHandle,
+        datagram: Option<(IpEndpoint, usize)>,
+    ) -> Option<TimeSpec> {
+        let mut data = self.data.borrow_mut();
+        let stamps = data
+            .hooks
+            .get_mut(&socket)
+            .and_then(|hooks| hooks.rx_stamps.as_mut())?;
+        match datagram {
+            Some((source, len)) => {
+                let index = stamps
+                    .iter()
+                    .position(|stamp| stamp.source == source && stamp.len == len)?;
+                stamps.drain(..index);
+                stamps.pop_front().map(|stamp| stamp.time)
+            }
+            None => {
+                let stamp = stamps.pop_back();
+                stamps.clear();
+                stamp.map(|stamp| stamp.time)
+            }
+        }
+    }
+
+    /// Starts counting the SYNs of `socket` from zero again, for the next
+    /// connect once the last one is over.
+    pub fn reset_syns(&self, socket: SocketHandle) {
+        let mut data = self.data.borrow_mut();
+        if let Some(limit) = data
+            .hooks
+            .get_mut(&socket)
+            .and_then(|hooks| hooks.syn_limit.as_mut())
+        {
+            limit.1 = 0;
+        }
+    }
+
+    /// Whether the connect of `socket` wanted to send more SYNs than it was allowed.
+    pub fn syns_exhausted(&self, socket: SocketHandle) -> bool {
+        self.data
+            .borrow()
+            .hooks
+            .get(&socket)
+            .and_then(|hooks| hooks.syn_limit)
+            .map_or(false, |(retries, sent)| sent.saturating_sub(1) > retries)
+    }
+
+    /// Marks the IPv4 packets of `flow` with `tos`, or stops marking them.
+    pub fn set_tos(&self, socket: SocketHandle, flow: Flow, tos: Option<u8>) {
+        self.update_hooks(socket, flow, |hooks| hooks.tos = tos);
+    }
+}
+
+/// Flow of an IPv4 TCP or UDP packet, seen from the local socket.
+fn packet_flow(buffer: &[u8], outgoing: bool) -> Option<Flow> {
+    let frame = EthernetFrame::new_checked(buffer).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+    let (src_port, dst_port) = match packet.protocol() {
+        IpProtocol::Tcp => {
+            let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+            (segment.src_port(), segment.dst_port())
+        }
+        IpProtocol::Udp => {
+            let datagram = UdpPacket::new_checked(packet.payload()).ok()?;
+            (datagram.src_port(), datagram.dst_port())
+        }
+        _ => return None,
+    };
+    let src = IpEndpoint::new(IpAddress::Ipv4(packet.src_addr()), src_port);
+    let dst = IpEndpoint::new(IpAddress::Ipv4(packet.dst_addr()), dst_port);
+    Some(if outgoing {
+        Flow::new(packet.protocol(), src, dst)
+    } else {
+        Flow::new(packet.protocol(), dst, src)
+    })
+}
+
+/// Lowers the MSS option of a TCP SYN segment to `clamp`. A segment with a
+/// wrong checksum is left alone, rather than getting a valid one that would
+/// have smoltcp accept it.
+fn clamp_mss(buffer: &mut [u8], clamp: u16) {
+    let mut frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return;
+    }
+    let mut packet = match Ipv4Packet::new_checked(frame.payload_mut()) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    if packet.protocol() != IpProtocol::Tcp || !packet.verify_checksum() {
+        return;
+    }
+    let src_addr = IpAddress::Ipv4(packet.src_addr());
+    let dst_addr = IpAddress::Ipv4(packet.dst_addr());
+    let mut segment = match TcpPacket::new_checked(packet.payload_mut()) {
+        Ok(segment) => segment,
+        Err(_) => return,
+    };
+    if !segment.syn() {
+        return;
+    }
+
+    let mut mss = None;
+    {
+        let options = segment.options_mut();
+        let mut i = 0;
+        while i < options.len() {
+            match options[i] {
+                // End of option list
+                0 => break,
+                // No-operation
+                1 => i += 1,
+                kind => {
+                    if i + 1 >= options.len() {
+                        break;
+                    }
+                    let len = options[i + 1] as usize;
+                    if len < 2 || i + len > options.len() {
+                        break;
+                    }
+                    if kind == 2 && len == 4
+                        && NetworkEndian::read_u16(&options[i + 2..i + 4]) > clamp
+                    {
+                        mss = Some(i + 2);
+                    }
+                    i += len;
+                }
+            }
+        }
+    }
+    if let Some(i) = mss {
+        if segment.verify_checksum(&src_addr, &dst_addr) {
+            NetworkEndian::write_u16(&mut segment.options_mut()[i..i + 2], clamp);
+            segment.fill_checksum(&src_addr, &dst_addr);
+        }
+    }
+}
+
+/// Builds the reply to an outgoing ARP request for an address with a static
+/// entry. The interface learns it like any other reply and asks again once it
+/// expired, which gets answered the same way.
+fn static_arp_reply(
+    buffer: &[u8],
+    neighbors: &BTreeMap<Ipv4Address, EthernetAddress>,
+) -> Option<Vec<u8>> {
+    let frame = EthernetFrame::new_checked(buffer).ok()?;
+    if frame.ethertype() != EthernetProtocol::Arp {
+        return None;
+    }
+    let packet = ArpPacket::new_checked(frame.payload()).ok()?;
+    let (source_hardware_addr, source_protocol_addr, target_protocol_addr) =
+        match ArpRepr::parse(&packet).ok()? {
+            ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Request,
+                source_hardware_addr,
+                source_protocol_addr,
+                target_protocol_addr,
+                ..
+            } => (source_hardware_addr, source_protocol_addr, target_protocol_addr),
+            _ => return None,
+        };
+    let &mac = neighbors.get(&target_protocol_addr)?;
+
+    let eth_repr = EthernetRepr {
+        src_addr: mac,
+        dst_addr: source_hardware_addr,
+        ethertype: EthernetProtocol::Arp,
+    };
+    let arp_repr = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: mac,
+        source_protocol_addr: target_protocol_addr,
+        target_hardware_addr: source_hardware_addr,
+        target_protocol_addr: source_protocol_addr,
+    };
+    let mut reply = vec![0; eth_repr.buffer_len() + arp_repr.buffer_len()];
+    {
+        let mut frame = EthernetFrame::new_unchecked(&mut reply[..]);
+        eth_repr.emit(&mut frame);
+        arp_repr.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    }
+    Some(reply)
+}
+
+/// Whether an incoming frame carries an IPv4 packet with a wrong header, TCP
+/// or UDP checksum.
+fn bad_checksum(buffer: &[u8]) -> bool {
+    let frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return false;
+    }
+    let packet = match Ipv4Packet::new_checked(frame.payload()) {
+        Ok(packet) => packet,
+        Err(_) => return false,
+    };
+    if !packet.verify_checksum() {
+        return true;
+    }
+    let src_addr = IpAddress::Ipv4(packet.src_addr());
+    let dst_addr = IpAddress::Ipv4(packet.dst_addr());
+    match packet.protocol() {
+        IpProtocol::Tcp => TcpPacket::new_checked(packet.payload())
+            .map_or(false, |segment| !segment.verify_checksum(&src_addr, &dst_addr)),
+        IpProtocol::Udp => UdpPacket::new_checked(packet.payload())
+            .map_or(false, |datagram| !datagram.verify_checksum(&src_addr, &dst_addr)),
+        _ => false,
+    }
+}
+
+/// Whether an outgoing frame is an ICMP port unreachable message, which
+/// smoltcp sends for UDP datagrams no socket takes.
+fn is_port_unreachable(buffer: &[u8]) -> bool {
+    let frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return false;
+    }
+    let packet = match Ipv4Packet::new_checked(frame.payload()) {
+        Ok(packet) => packet,
+        Err(_) => return false,
+    };
+    if packet.protocol() != IpProtocol::Icmp {
+        return false;
+    }
+    match Icmpv4Packet::new_checked(packet.payload()) {
+        Ok(message) => {
+            message.msg_type() == Icmpv4Message::DstUnreachable
+                && Icmpv4DstUnreachable::from(message.msg_code())
+                    == Icmpv4DstUnreachable::PortUnreachable
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether an incoming frame is a TCP segment with the URG flag.
+fn is_urgent(buffer: &[u8]) -> bool {
+    let frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return false;
+    }
+    let packet = match Ipv4Packet::new_checked(frame.payload()) {
+        Ok(packet) => packet,
+        Err(_) => return false,
+    };
+    if packet.protocol() != IpProtocol::Tcp {
+        return false;
+    }
+    match TcpPacket::new_checked(packet.payload()) {
+        Ok(segment) => segment.urg(),
+        Err(_) => false,
+    }
+}
+
+/// Payload length of an incoming UDP datagram, empty ones included, or of a
+/// TCP segment carrying data, the packets reads get a receive time for.
+fn stamped_len(buffer: &[u8]) -> Option<usize> {
+    let frame = EthernetFrame::new_checked(buffer).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+    match packet.protocol() {
+        IpProtocol::Udp => {
+            let datagram = UdpPacket::new_checked(packet.payload()).ok()?;
+            Some(datagram.payload().len())
+        }
+        IpProtocol::Tcp => {
+            let segment = TcpPacket::new_checked(packet.payload()).ok()?;
+            match segment.payload().len() {
+                0 => None,
+                len => Some(len),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Notes the receive time of an incoming packet of `flow` with `len` bytes
+/// of payload.
+fn note_rx_stamp(hooks: &mut Hooks, flow: &Flow, len: usize) {
+    let mut time = TimeSpec::default();
+    if syscall::clock_gettime(syscall::CLOCK_REALTIME, &mut time).is_err() {
+        return;
+    }
+    if let Some(ref mut stamps) = hooks.rx_stamps {
+        if stamps.len() >= NetworkDevice::MAX_RX_STAMPS {
+            stamps.pop_front();
+        }
+        stamps.push_back(RxStamp {
+            time,
+            source: flow.remote,
+            len,
+        });
+    }
+}
+
+/// Zeroes the checksum of an IPv4 UDP datagram, which tells the receiver that
+/// none was computed. IPv6 doesn't allow that.
+fn clear_udp_checksum(buffer: &mut [u8]) {
+    let mut frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return;
+    }
+    let mut packet = match Ipv4Packet::new_checked(frame.payload_mut()) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    if packet.protocol() != IpProtocol::Udp {
+        return;
+    }
+    if let Ok(mut datagram) = UdpPacket::new_checked(packet.payload_mut()) {
+        datagram.set_checksum(0);
+    }
+}
+
+/// Counts an outgoing connect SYN against `limit`, false if the frame exceeds
+/// it and has to be dropped.
+fn count_syn(buffer: &[u8], limit: &mut (u32, u32)) -> bool {
+    let frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return true,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return true;
+    }
+    let packet = match Ipv4Packet::new_checked(frame.payload()) {
+        Ok(packet) => packet,
+        Err(_) => return true,
+    };
+    if packet.protocol() != IpProtocol::Tcp {
+        return true;
+    }
+    let segment = match TcpPacket::new_checked(packet.payload()) {
+        Ok(segment) => segment,
+        Err(_) => return true,
+    };
+    // A SYN-ACK answers a connect instead of starting one
+    if !segment.syn() || segment.ack() {
+        return true;
+    }
+    let (retries, ref mut sent) = *limit;
+    *sent = sent.saturating_add(1);
+    *sent - 1 <= retries
+}
+
+fn mark_tos(buffer: &mut [u8], tos: u8) {
+    let mut frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return;
+    }
+    if let Ok(mut packet) = Ipv4Packet::new_checked(frame.payload_mut()) {
+        packet.set_dscp(tos >> 2);
+        packet.set_ecn(tos & 0b11);
+        packet.fill_checksum();
+    }
+}
+
+/// Lets routers fragment a packet instead of dropping it.
+fn clear_dont_frag(buffer: &mut [u8]) {
+    let mut frame = match EthernetFrame::new_checked(buffer) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return;
+    }
+    if let Ok(mut packet) = Ipv4Packet::new_checked(frame.payload_mut()) {
+        if packet.dont_frag() {
+            packet.set_dont_frag(false);
+            packet.fill_checksum();
+        }
+    }
 }
 
 pub struct RxToken {
     buffer: Buffer,
+    data: Rc<RefCell<NetworkDeviceData>>,
 }
 
 impl smoltcp::phy::RxToken for RxToken {
@@ -49,7 +815,12 @@ impl smoltcp::phy::RxToken for RxToken {
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
-        f(&mut self.buffer)
+        let result = f(&mut self.buffer);
+        // Checksums are only checked again for the frames smoltcp turned down
+        if result.is_err() && bad_checksum(&self.buffer) {
+            self.data.borrow_mut().stats.checksum_errors += 1;
+        }
+        result
     }
 }
 
@@ -62,11 +833,60 @@ impl smoltcp::phy::TxToken for TxToken {
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
-        let data = self.data.borrow_mut();
+        let mut data = self.data.borrow_mut();
         let mut buffer = data.buffer_pool.borrow_mut().get_buffer();
         buffer.resize(len);
         let res = f(&mut buffer)?;
 
+        // Like an unplugged cable, whatever is sent while the link is down is lost
+        if !data.link_up {
+            data.stats.drops += 1;
+            return Ok(res);
+        }
+
+        if !data.static_neighbors.is_empty() {
+            if let Some(reply) = static_arp_reply(&buffer, &data.static_neighbors) {
+                let mut reply_buffer = data.buffer_pool.borrow_mut().get_buffer();
+                reply_buffer.resize(reply.len());
+                reply_buffer.copy_from_slice(&reply);
+                data.input_queue.borrow_mut().push_back(reply_buffer);
+                return Ok(res);
+            }
+        }
+
+        let flow = if data.hooks.is_empty() {
+            None
+        } else {
+            packet_flow(&buffer, true)
+        };
+        if let Some(ref flow) = flow {
+            // Handled like a lost SYN, the connect fails once the limit shows up exhausted
+            let allowed = match hook_owner(&data.hooks, flow, |hooks| hooks.syn_limit.is_some())
+                .and_then(|socket| data.hooks.get_mut(&socket))
+                .and_then(|hooks| hooks.syn_limit.as_mut())
+            {
+                Some(limit) => count_syn(&buffer, limit),
+                None => true,
+            };
+            if !allowed {
+                data.stats.drops += 1;
+                return Ok(res);
+            }
+
+            if let Some(tos) = find_hook(&data.hooks, flow, |hooks| hooks.tos) {
+                mark_tos(&mut buffer, tos);
+            }
+            if hook_owner(&data.hooks, flow, |hooks| hooks.no_df).is_some() {
+                clear_dont_frag(&mut buffer);
+            }
+            if let Some(clamp) = find_hook(&data.hooks, flow, |hooks| hooks.mss) {
+                clamp_mss(&mut buffer, clamp);
+            }
+            if hook_owner(&data.hooks, flow, |hooks| hooks.no_checksum).is_some() {
+                clear_udp_checksum(&mut buffer);
+            }
+        }
+
         let mut loopback = false;
         if let Ok(mut frame) = smoltcp::wire::EthernetFrame::new_checked(&mut buffer) {
             if frame.dst_addr() == EthernetAddress::default() {
@@ -75,14 +895,20 @@ impl smoltcp::phy::TxToken for TxToken {
             }
         }
 
+        data.capture(&buffer);
+
+        if is_port_unreachable(&buffer) {
+            data.stats.no_socket += 1;
+        }
+
         if loopback {
             data.input_queue.borrow_mut().push_back(buffer.move_out());
-        } else {
-            data.network_file
-                .borrow_mut()
-                .write(&buffer)
-                .map_err(|_| smoltcp::Error::Dropped)?;
+        } else if data.network_file.borrow_mut().write(&buffer).is_err() {
+            data.stats.drops += 1;
+            return Err(smoltcp::Error::Dropped);
         }
+        data.stats.tx_packets += 1;
+        data.stats.tx_bytes += len as u64;
 
         Ok(res)
     }
@@ -94,18 +920,60 @@ impl<'a> smoltcp::phy::Device<'a> for NetworkDevice {
 
     fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
         let mut limits = smoltcp::phy::DeviceCapabilities::default();
-        limits.max_transmission_unit = Self::MTU;
+        limits.max_transmission_unit = self.mtu();
         limits.max_burst_size = Some(20);
         limits
     }
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let data = self.data.borrow_mut();
+        let mut data = self.data.borrow_mut();
+        if !data.link_up {
+            let dropped = {
+                let mut input_queue = data.input_queue.borrow_mut();
+                let dropped = input_queue.len();
+                input_queue.clear();
+                dropped
+            };
+            data.stats.drops += dropped as u64;
+            return None;
+        }
         let buffer = data.input_queue.borrow_mut().pop_front();
 
-        if let Some(buffer) = buffer {
+        if let Some(mut buffer) = buffer {
+            data.capture(&buffer);
+            data.stats.rx_packets += 1;
+            data.stats.rx_bytes += buffer.len() as u64;
+            let flow = if data.hooks.is_empty() {
+                None
+            } else {
+                packet_flow(&buffer, false)
+            };
+            if let Some(ref flow) = flow {
+                if let Some(clamp) = find_hook(&data.hooks, flow, |hooks| hooks.mss) {
+                    clamp_mss(&mut buffer, clamp);
+                }
+                if is_urgent(&buffer) {
+                    let urgent = hook_owner(&data.hooks, flow, |hooks| hooks.urgent.is_some())
+                        .and_then(|socket| data.hooks.get_mut(&socket))
+                        .and_then(|hooks| hooks.urgent.as_mut());
+                    if let Some(seen) = urgent {
+                        *seen = true;
+                    }
+                }
+                let stamped = hook_owner(&data.hooks, flow, |hooks| hooks.rx_stamps.is_some())
+                    .and_then(|socket| data.hooks.get_mut(&socket));
+                if let (Some(hooks), Some(len)) = (stamped, stamped_len(&buffer)) {
+                    // smoltcp drops those, they'd never be read
+                    if !bad_checksum(&buffer) {
+                        note_rx_stamp(hooks, flow, len);
+                    }
+                }
+            }
             Some((
-                RxToken { buffer },
+                RxToken {
+                    buffer,
+                    data: Rc::clone(&self.data),
+                },
                 TxToken {
                     data: Rc::clone(&self.data),
                 },
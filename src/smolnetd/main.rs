@@ -59,12 +59,17 @@ fn run() -> Result<()> {
         .map_err(|e| Error::from_syscall_error(e, "failed to open :netcfg"))?
         as RawFd;
 
+    trace!("opening :pcap");
+    let pcap_fd = syscall::open(":pcap", O_RDWR | O_CREAT | O_NONBLOCK)
+        .map_err(|e| Error::from_syscall_error(e, "failed to open :pcap"))?
+        as RawFd;
+
     let time_path = format!("time:{}", syscall::CLOCK_MONOTONIC);
     let time_fd = syscall::open(&time_path, syscall::O_RDWR)
         .map_err(|e| Error::from_syscall_error(e, "failed to open time:"))?
         as RawFd;
 
-    let (network_file, ip_file, time_file, udp_file, tcp_file, icmp_file, netcfg_file) = unsafe {
+    let (network_file, ip_file, time_file, udp_file, tcp_file, icmp_file, netcfg_file, pcap_file) = unsafe {
         (
             File::from_raw_fd(network_fd),
             File::from_raw_fd(ip_fd),
@@ -73,6 +78,7 @@ fn run() -> Result<()> {
             File::from_raw_fd(tcp_fd),
             File::from_raw_fd(icmp_fd),
             File::from_raw_fd(netcfg_fd),
+            File::from_raw_fd(pcap_fd),
         )
     };
 
@@ -84,6 +90,7 @@ fn run() -> Result<()> {
         icmp_file,
         time_file,
         netcfg_file,
+        pcap_file,
     )));
 
     let mut event_queue = EventQueue::<(), Error>::new()
@@ -135,6 +142,14 @@ fn run() -> Result<()> {
         .add(time_fd, move |_| smolnetd_.borrow_mut().on_time_event())
         .map_err(|e| Error::from_io_error(e, "failed to listen to time events"))?;
 
+    let smolnetd_ = Rc::clone(&smolnetd);
+
+    event_queue
+        .add(pcap_fd, move |_| {
+            smolnetd_.borrow_mut().on_pcap_scheme_event()
+        })
+        .map_err(|e| Error::from_io_error(e, "failed to listen to pcap events"))?;
+
     event_queue
         .add(netcfg_fd, move |_| {
             smolnetd.borrow_mut().on_netcfg_scheme_event()
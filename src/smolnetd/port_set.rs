@@ -1,13 +1,26 @@
 use std::collections::btree_map::{BTreeMap, Entry};
 
+use smoltcp::wire::IpAddress;
+
+/// Ports in use, along with the local addresses bound on each of them.
+///
+/// A wildcard address conflicts with every other bind on the same port, while
+/// binds to distinct specific addresses can share a port.
 pub struct PortSet {
     from: u16,
     range: u16,
     next: u16,
-    ports: BTreeMap<u16, usize>,
+    ports: BTreeMap<u16, Vec<(IpAddress, usize)>>,
+}
+
+fn conflicts(a: &IpAddress, b: &IpAddress) -> bool {
+    !a.is_specified() || !b.is_specified() || a == b
 }
 
 impl PortSet {
+    /// Ephemeral ports tried for a single bind before giving up
+    const BIND_TRIES: usize = 8;
+
     pub fn new(from: u16, to: u16) -> Option<PortSet> {
         if from > to {
             return None;
@@ -20,43 +33,138 @@ impl PortSet {
         })
     }
 
-    pub fn get_port(&mut self) -> Option<u16> {
-        if self.ports.len() >= self.range as usize {
+    /// Picks a port nothing is bound to yet and binds `addr` to it.
+    pub fn get_port(&mut self, addr: IpAddress) -> Option<u16> {
+        let used = self
+            .ports
+            .keys()
+            .filter(|&&port| port >= self.from && port - self.from < self.range)
+            .count();
+        if used >= self.range as usize {
             return None;
         }
 
         let port = loop {
-            if let Entry::Vacant(entry) = self.ports.entry(self.next) {
-                entry.insert(1);
-                let port = self.from + self.next;
-                self.next = self.next.wrapping_add(1);
+            let port = self.from + self.next;
+            self.next = ((u32::from(self.next) + 1) % u32::from(self.range)) as u16;
+            if let Entry::Vacant(entry) = self.ports.entry(port) {
+                entry.insert(vec![(addr, 1)]);
                 break port;
             }
-            self.next = self.next.wrapping_add(1);
         };
 
         Some(port)
     }
 
-    pub fn claim_port(&mut self, port: u16) -> bool {
-        if let Entry::Vacant(entry) = self.ports.entry(port) {
-            entry.insert(1);
-            true
-        } else {
-            false
+    /// Binds `addr` to ephemeral ports until `bind` takes one, giving back each
+    /// port it turns down. Fails with the last error of `bind` once
+    /// `BIND_TRIES` ports were turned down, or with `None` if none was free.
+    pub fn bind_ephemeral<E, F>(&mut self, addr: IpAddress, mut bind: F) -> Result<u16, Option<E>>
+    where
+        F: FnMut(u16) -> Result<(), E>,
+    {
+        let mut last_err = None;
+        for _ in 0..Self::BIND_TRIES {
+            let port = match self.get_port(addr) {
+                Some(port) => port,
+                None => break,
+            };
+            match bind(port) {
+                Ok(()) => return Ok(port),
+                Err(err) => {
+                    self.release_port(addr, port);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Binds `addr` to `port` unless that conflicts with an existing bind.
+    pub fn claim_port(&mut self, addr: IpAddress, port: u16) -> bool {
+        if let Some(binds) = self.ports.get(&port) {
+            if binds.iter().any(|&(bound, _)| conflicts(&bound, &addr)) {
+                return false;
+            }
         }
+        self.ports.entry(port).or_insert_with(Vec::new).push((addr, 1));
+        true
+    }
+
+    /// Binds `addr` to the first port of `lo..=hi` that it doesn't conflict on.
+    pub fn get_port_in_range(&mut self, addr: IpAddress, lo: u16, hi: u16) -> Option<u16> {
+        (lo..=hi).find(|&port| self.claim_port(addr, port))
+    }
+
+    /// Whether anything is bound to `port`, without claiming it.
+    pub fn is_claimed(&self, port: u16) -> bool {
+        self.ports.contains_key(&port)
     }
 
-    pub fn acquire_port(&mut self, port: u16) {
-        *self.ports.entry(port).or_insert(0) += 1;
+    /// Takes one more reference on the bind of `addr` to `port`.
+    pub fn acquire_port(&mut self, addr: IpAddress, port: u16) {
+        let binds = self.ports.entry(port).or_insert_with(Vec::new);
+        match binds.iter().position(|&(bound, _)| bound == addr) {
+            Some(i) => binds[i].1 += 1,
+            None => binds.push((addr, 1)),
+        }
     }
 
-    pub fn release_port(&mut self, port: u16) {
+    pub fn release_port(&mut self, addr: IpAddress, port: u16) {
         if let Entry::Occupied(mut entry) = self.ports.entry(port) {
-            *entry.get_mut() -= 1;
-            if *entry.get() == 0 {
+            if let Some(i) = entry.get().iter().position(|&(bound, _)| bound == addr) {
+                entry.get_mut()[i].1 -= 1;
+                if entry.get()[i].1 == 0 {
+                    entry.get_mut().remove(i);
+                }
+            }
+            if entry.get().is_empty() {
                 entry.remove();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::wire::IpAddress;
+
+    use super::PortSet;
+
+    #[test]
+    fn bind_ephemeral_after_collision() {
+        let mut port_set = PortSet::new(49152, 49160).unwrap();
+        let addr = IpAddress::v4(127, 0, 0, 1);
+        let mut tried = Vec::new();
+        let result = port_set.bind_ephemeral(addr, |port| {
+            tried.push(port);
+            if tried.len() == 1 {
+                Err("collision")
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result, Ok(49153));
+        assert_eq!(tried, vec![49152, 49153]);
+        assert!(!port_set.is_claimed(49152));
+        assert!(port_set.is_claimed(49153));
+    }
+
+    #[test]
+    fn bind_ephemeral_gives_up() {
+        let mut port_set = PortSet::new(49152, 65535).unwrap();
+        let addr = IpAddress::v4(127, 0, 0, 1);
+        let mut tries = 0;
+        let result = port_set.bind_ephemeral(addr, |_| {
+            tries += 1;
+            Err("collision")
+        });
+        assert_eq!(result, Err(Some("collision")));
+        assert_eq!(tries, PortSet::BIND_TRIES);
+        assert!((49152..=65535).all(|port| !port_set.is_claimed(port)));
+
+        let mut port_set = PortSet::new(49152, 49152).unwrap();
+        assert!(port_set.claim_port(addr, 49152));
+        assert_eq!(port_set.bind_ephemeral::<(), _>(addr, |_| Ok(())), Err(None));
+    }
+}
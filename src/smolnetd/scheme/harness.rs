@@ -0,0 +1,119 @@
+//! In-process stand-ins for the files smolnetd runs on, so that tests can
+//! drive the socket schemes without a network card. The device writes the
+//! frames leaving the interface to a temporary file in place of `network:`,
+//! while the ones to its own addresses loop back to its input queue, and a
+//! scheme answers the operations it blocked on through a temporary file in
+//! place of its scheme file.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use smoltcp::socket::AnySocket;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, Ipv4Address};
+
+use super::socket::{SchemeSocket, SocketScheme};
+use super::{new_interface, Iface};
+use buffer_pool::{Buffer, BufferPool};
+use device::{Captures, NetworkDevice};
+
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// A file in the temporary directory, deleted once dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new() -> TempFile {
+        let name = format!(
+            "smolnetd-test-{}-{}",
+            process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = env::temp_dir().join(name);
+        File::create(&path).expect("Can't create a temporary file");
+        TempFile { path }
+    }
+
+    pub fn open(&self) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .expect("Can't open a temporary file")
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// An interface at 10.0.2.15 and on the loopback network, on a device
+/// without any network attached.
+pub struct Harness {
+    pub iface: Iface,
+    input_queue: Rc<RefCell<VecDeque<Buffer>>>,
+    timer: ::std::time::Instant,
+    _network_file: TempFile,
+}
+
+impl Harness {
+    /// Polls after which frames still looping back are left queued
+    const MAX_POLLS: usize = 16;
+
+    pub fn new() -> Harness {
+        let network_file = TempFile::new();
+        let hardware_addr = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+        let input_queue = Rc::new(RefCell::new(VecDeque::new()));
+        let network_device = NetworkDevice::new(
+            Rc::new(RefCell::new(network_file.open())),
+            Rc::clone(&input_queue),
+            hardware_addr,
+            Rc::new(RefCell::new(BufferPool::new(2048))),
+            Rc::new(RefCell::new(Captures::default())),
+        );
+        let iface = new_interface(
+            network_device,
+            hardware_addr,
+            IpAddress::v4(10, 0, 2, 15),
+            Ipv4Address::new(10, 0, 2, 2),
+        );
+        Harness {
+            iface: Rc::new(RefCell::new(iface)),
+            input_queue,
+            timer: ::std::time::Instant::now(),
+            _network_file: network_file,
+        }
+    }
+
+    /// A scheme on the interface, along with the file it answers the
+    /// operations it blocked on through.
+    pub fn scheme<SocketT>(&self) -> (SocketScheme<SocketT>, TempFile)
+    where
+        SocketT: SchemeSocket + AnySocket<'static>,
+    {
+        let scheme_file = TempFile::new();
+        let scheme = SocketScheme::new(Rc::clone(&self.iface), scheme_file.open());
+        (scheme, scheme_file)
+    }
+
+    /// Polls the interface until it received all the frames it sent itself.
+    pub fn poll(&self) {
+        let mut iface = self.iface.borrow_mut();
+        for _ in 0..Self::MAX_POLLS {
+            let _ = iface.poll(Instant::from(self.timer));
+            if self.input_queue.borrow().is_empty() {
+                break;
+            }
+        }
+    }
+}
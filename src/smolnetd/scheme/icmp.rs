@@ -4,14 +4,16 @@ use smoltcp::socket::{
 };
 use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpAddress, IpEndpoint};
 use smoltcp::iface::{SocketHandle};
+use std::collections::BTreeMap;
 use std::mem;
 use std::str;
 use syscall;
 use syscall::{Error as SyscallError, Result as SyscallResult};
 
-use super::socket::{DupResult, SchemeFile, SchemeSocket, SocketFile, SocketScheme};
-use super::{Smolnetd, SmolnetInterface};
-use device::NetworkDevice;
+use super::socket::{add_socket, smoltcp_err_to_syscall, socket_buffer_size, write_path,
+                    DupResult, SchemeFile, SchemeSocket, SocketFile, SocketScheme};
+use super::SmolnetInterface;
+use device::Flow;
 use port_set::PortSet;
 
 pub type IcmpScheme = SocketScheme<IcmpSocket<'static>>;
@@ -36,6 +38,11 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
         PortSet::new(1u16, 0xffffu16).expect("Wrong ICMP ident values")
     }
 
+    fn port_claimed(_ident_set: &Self::SchemeDataT, _port: u16) -> Option<bool> {
+        // Echo idents aren't ports
+        None
+    }
+
     fn can_send(&self) -> bool {
         self.can_send()
     }
@@ -44,11 +51,20 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
         self.can_recv()
     }
 
-    fn may_recv(&self) -> bool {
+    fn may_recv(&self, _data: &Self::DataT) -> bool {
         true
     }
 
+    fn parse_setting(_name: &str) -> Option<Self::SettingT> {
+        None
+    }
+
+    fn setting_names() -> &'static [&'static str] {
+        &[]
+    }
+
     fn get_setting(
+        &self,
         _file: &SocketFile<Self::DataT>,
         _setting: Self::SettingT,
         _buf: &mut [u8],
@@ -57,6 +73,7 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
     }
 
     fn set_setting(
+        &mut self,
         _file: &mut SocketFile<Self::DataT>,
         _setting: Self::SettingT,
         _buf: &[u8],
@@ -64,12 +81,70 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
         Ok(0)
     }
 
-    fn hop_limit(&self) -> u8 {
-        self.hop_limit().unwrap_or(64)
+    fn take_error(&mut self) -> Option<SyscallError> {
+        None
+    }
+
+    fn recv_available(&mut self) -> Option<usize> {
+        None
+    }
+
+    fn send_queued(&self) -> Option<usize> {
+        None
+    }
+
+    fn recv_lowat_met(&self, _recv_lowat: usize) -> bool {
+        true
+    }
+
+    fn send_lowat_met(&self, _send_lowat: usize) -> bool {
+        true
+    }
+
+    fn flush_recv(&mut self) -> usize {
+        let mut count = 0;
+        while let Ok((payload, _)) = self.recv() {
+            count += payload.len();
+        }
+        count
+    }
+
+    fn shutdown(&mut self, _abort: bool) {}
+
+    fn is_flushed(&self) -> bool {
+        true
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn is_connecting(&self) -> bool {
+        false
     }
 
-    fn set_hop_limit(&mut self, hop_limit: u8) {
-        self.set_hop_limit(Some(hop_limit));
+    fn take_datagram(_data: &mut Self::DataT) -> Option<(IpEndpoint, usize)> {
+        None
+    }
+
+    fn hop_limit(&self) -> Option<u8> {
+        self.hop_limit()
+    }
+
+    fn set_hop_limit(&mut self, hop_limit: Option<u8>) {
+        self.set_hop_limit(hop_limit);
+    }
+
+    fn ip_flow(&self, _data: &Self::DataT) -> Option<Flow> {
+        None
+    }
+
+    fn is_bound(&self) -> bool {
+        true
+    }
+
+    fn remote_addr(&self, data: &Self::DataT) -> Option<IpAddress> {
+        Some(data.ip)
     }
 
     fn new_socket(
@@ -80,6 +155,8 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
     ) -> SyscallResult<(SocketHandle, Self::DataT)> {
         use std::str::FromStr;
 
+        let mtu = iface.device().get_ref().mtu();
+        let buffer_size = socket_buffer_size();
         let mut parts = path.split('/');
         let method = parts
             .next()
@@ -95,22 +172,23 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
 
                 let socket = IcmpSocket::new(
                     IcmpSocketBuffer::new(
-                        vec![IcmpPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-                        vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+                        vec![IcmpPacketMetadata::EMPTY; buffer_size],
+                        vec![0; mtu * buffer_size],
                     ),
                     IcmpSocketBuffer::new(
-                        vec![IcmpPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-                        vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+                        vec![IcmpPacketMetadata::EMPTY; buffer_size],
+                        vec![0; mtu * buffer_size],
                     ),
                 );
-                let handle = iface.add_socket(socket);
+                let handle = add_socket(iface, socket)?;
                 let mut icmp_socket = iface.get_socket::<IcmpSocket>(handle);
+                // Idents aren't tied to a local address
                 let ident = ident_set
-                    .get_port()
+                    .get_port(IpAddress::Unspecified)
                     .ok_or_else(|| SyscallError::new(syscall::EINVAL))?;
                 icmp_socket
                     .bind(IcmpEndpoint::Ident(ident))
-                    .map_err(|_| syscall::Error::new(syscall::EINVAL))?;
+                    .map_err(smoltcp_err_to_syscall)?;
                 let socket_data = IcmpData {
                     socket_type: IcmpSocketType::Echo,
                     ident,
@@ -127,22 +205,22 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
 
                 let socket = IcmpSocket::new(
                     IcmpSocketBuffer::new(
-                        vec![IcmpPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-                        vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+                        vec![IcmpPacketMetadata::EMPTY; buffer_size],
+                        vec![0; mtu * buffer_size],
                     ),
                     IcmpSocketBuffer::new(
-                        vec![IcmpPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-                        vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+                        vec![IcmpPacketMetadata::EMPTY; buffer_size],
+                        vec![0; mtu * buffer_size],
                     ),
                 );
-                let handle = iface.add_socket(socket);
+                let handle = add_socket(iface, socket)?;
                 let mut icmp_socket = iface.get_socket::<IcmpSocket>(handle);
                 let ident = ident_set
-                    .get_port()
+                    .get_port(IpAddress::Unspecified)
                     .ok_or_else(|| SyscallError::new(syscall::EINVAL))?;
                 icmp_socket
                     .bind(IcmpEndpoint::Udp(IpEndpoint::from(ident)))
-                    .map_err(|_| syscall::Error::new(syscall::EINVAL))?;
+                    .map_err(smoltcp_err_to_syscall)?;
                 let socket_data = IcmpData {
                     socket_type: IcmpSocketType::Udp,
                     ident,
@@ -160,7 +238,7 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
         ident_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<()> {
         if let SchemeFile::Socket(ref file) = *file {
-            ident_set.release_port(file.data.ident);
+            ident_set.release_port(IpAddress::Unspecified, file.data.ident);
         }
         Ok(())
     }
@@ -168,6 +246,7 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
     fn write_buf(
         &mut self,
         file: &mut SocketFile<Self::DataT>,
+        _mtu: usize,
         buf: &[u8],
     ) -> SyscallResult<Option<usize>> {
         if self.can_send() {
@@ -186,7 +265,7 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
 
                     let icmp_payload = self
                         .send(icmp_repr.buffer_len(), file.data.ip)
-                        .map_err(|_| syscall::Error::new(syscall::EINVAL))?;
+                        .map_err(smoltcp_err_to_syscall)?;
                     let mut icmp_packet = Icmpv4Packet::new_unchecked(icmp_payload);
                     //TODO: replace Default with actual caps
                     icmp_repr.emit(&mut icmp_packet, &Default::default());
@@ -207,10 +286,11 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
         buf: &mut [u8],
     ) -> SyscallResult<Option<usize>> {
         while self.can_recv() {
-            let (payload, _) = self.recv().expect("Can't recv icmp packet");
+            let (payload, _) = self.recv().map_err(smoltcp_err_to_syscall)?;
             let icmp_packet = Icmpv4Packet::new_unchecked(&payload);
             //TODO: replace default with actual caps
-            let icmp_repr = Icmpv4Repr::parse(&icmp_packet, &Default::default()).unwrap();
+            let icmp_repr = Icmpv4Repr::parse(&icmp_packet, &Default::default())
+                .map_err(smoltcp_err_to_syscall)?;
 
             if let Icmpv4Repr::EchoReply { seq_no, data, .. } = icmp_repr {
                 if buf.len() < mem::size_of::<u16>() + data.len() {
@@ -233,41 +313,38 @@ impl<'a> SchemeSocket for IcmpSocket<'a> {
         }
     }
 
+    fn fsync(&mut self, _file: &mut SocketFile<Self::DataT>) -> SyscallResult<Option<usize>> {
+        Ok(Some(0))
+    }
+
     fn dup(
         _iface: &mut SmolnetInterface,
         _file: &mut SchemeFile<Self>,
         _path: &str,
+        _query: &BTreeMap<&str, &str>,
         _: &mut Self::SchemeDataT,
     ) -> SyscallResult<DupResult<Self>> {
         Err(SyscallError::new(syscall::EBADF))
     }
 
+    fn describe(&self, file: &SocketFile<Self::DataT>) -> String {
+        let kind = match file.data.socket_type {
+            IcmpSocketType::Echo => "ECHO",
+            IcmpSocketType::Udp => "UDP",
+        };
+        format!("icmp {} {} {}", file.data.ident, file.data.ip, kind)
+    }
+
     fn fpath(&self, file: &SchemeFile<Self>, buf: &mut [u8]) -> SyscallResult<usize> {
         if let SchemeFile::Socket(ref socket_file) = *file {
             match socket_file.data.socket_type {
                 IcmpSocketType::Echo => {
                     let path = format!("icmp:echo/{}", socket_file.data.ip);
-                    let path = path.as_bytes();
-
-                    let mut i = 0;
-                    while i < buf.len() && i < path.len() {
-                        buf[i] = path[i];
-                        i += 1;
-                    }
-
-                    Ok(i)
+                    write_path(&path, buf)
                 }
                 IcmpSocketType::Udp => {
                     let path = format!("icmp:udp/{}", socket_file.data.ip);
-                    let path = path.as_bytes();
-
-                    let mut i = 0;
-                    while i < buf.len() && i < path.len() {
-                        buf[i] = path[i];
-                        i += 1;
-                    }
-
-                    Ok(i)
+                    write_path(&path, buf)
                 }
             }
         } else {
@@ -1,25 +1,62 @@
 use smoltcp::socket::{RawPacketMetadata, RawSocket, RawSocketBuffer};
-use smoltcp::wire::{IpProtocol, IpVersion};
+use smoltcp::wire::{
+    IpAddress, IpEndpoint, IpProtocol, IpVersion, Ipv4Packet, ETHERNET_HEADER_LEN,
+};
 use smoltcp::iface::{SocketHandle};
+use std::collections::BTreeMap;
 use std::str;
 use syscall;
 use syscall::{Error as SyscallError, Result as SyscallResult};
 
-use super::socket::{DupResult, SchemeFile, SchemeSocket, SocketFile, SocketScheme};
-use super::{Smolnetd, SmolnetInterface};
-use device::NetworkDevice;
+use super::socket::{add_socket, smoltcp_err_to_syscall, socket_buffer_size, write_path,
+                    DupResult, SchemeFile, SchemeSocket, SocketFile, SocketScheme};
+use super::SmolnetInterface;
+use device::Flow;
 
 pub type IpScheme = SocketScheme<RawSocket<'static>>;
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum IpSetting {
+    /// Whether packets are written and read with their IPv4 header, as a
+    /// single byte. Raw sockets have no destination of their own, so the
+    /// header can't be left out and the setting can only be turned on.
+    HdrIncl,
+}
+
+/// Checks a packet written with its IPv4 header before it reaches smoltcp,
+/// which rebuilds the header from the parsed fields and so drops any options.
+fn check_header(socket: &RawSocket, mtu: usize, buf: &[u8]) -> SyscallResult<()> {
+    if buf.len() > mtu - ETHERNET_HEADER_LEN {
+        return Err(SyscallError::new(syscall::EMSGSIZE));
+    }
+    // Makes sure the header length fits in the total length and the buffer
+    let packet = Ipv4Packet::new_checked(buf).map_err(|_| SyscallError::new(syscall::EINVAL))?;
+    if packet.version() != 4 || usize::from(packet.total_len()) != buf.len() {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+    if packet.protocol() != socket.ip_protocol() {
+        return Err(SyscallError::new(syscall::EPROTOTYPE));
+    }
+    let src_addr = packet.src_addr();
+    if !src_addr.is_unspecified() && !src_addr.is_unicast() {
+        return Err(SyscallError::new(syscall::EADDRNOTAVAIL));
+    }
+    Ok(())
+}
+
 impl<'a> SchemeSocket for RawSocket<'a> {
     type SchemeDataT = ();
     type DataT = ();
-    type SettingT = ();
+    type SettingT = IpSetting;
 
     fn new_scheme_data() -> Self::SchemeDataT {
         ()
     }
 
+    fn port_claimed(_data: &Self::SchemeDataT, _port: u16) -> Option<bool> {
+        None
+    }
+
     fn can_send(&self) -> bool {
         self.can_send()
     }
@@ -28,31 +65,118 @@ impl<'a> SchemeSocket for RawSocket<'a> {
         self.can_recv()
     }
 
-    fn may_recv(&self) -> bool {
+    fn may_recv(&self, _data: &Self::DataT) -> bool {
         true
     }
 
+    fn parse_setting(name: &str) -> Option<Self::SettingT> {
+        match name {
+            "hdrincl" => Some(IpSetting::HdrIncl),
+            _ => None,
+        }
+    }
+
+    fn setting_names() -> &'static [&'static str] {
+        &["hdrincl"]
+    }
+
     fn get_setting(
+        &self,
         _file: &SocketFile<Self::DataT>,
-        _setting: Self::SettingT,
-        _buf: &mut [u8],
+        setting: Self::SettingT,
+        buf: &mut [u8],
     ) -> SyscallResult<usize> {
-        Ok(0)
+        match setting {
+            IpSetting::HdrIncl => {
+                if let Some(hdrincl) = buf.get_mut(0) {
+                    *hdrincl = 1;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+        }
     }
 
     fn set_setting(
+        &mut self,
         _file: &mut SocketFile<Self::DataT>,
-        _setting: Self::SettingT,
-        _buf: &[u8],
+        setting: Self::SettingT,
+        buf: &[u8],
     ) -> SyscallResult<usize> {
-        Ok(0)
+        match setting {
+            IpSetting::HdrIncl => match buf.get(0) {
+                Some(&0) => Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                Some(_) => Ok(1),
+                None => Err(SyscallError::new(syscall::EIO)),
+            },
+        }
+    }
+
+    fn take_error(&mut self) -> Option<SyscallError> {
+        None
     }
 
-    fn hop_limit(&self) -> u8 {
-        0
+    fn recv_available(&mut self) -> Option<usize> {
+        None
     }
 
-    fn set_hop_limit(&mut self, _hop_limit: u8) {}
+    fn send_queued(&self) -> Option<usize> {
+        None
+    }
+
+    fn recv_lowat_met(&self, _recv_lowat: usize) -> bool {
+        true
+    }
+
+    fn send_lowat_met(&self, _send_lowat: usize) -> bool {
+        true
+    }
+
+    fn flush_recv(&mut self) -> usize {
+        let mut count = 0;
+        while let Ok(packet) = self.recv() {
+            count += packet.len();
+        }
+        count
+    }
+
+    fn shutdown(&mut self, _abort: bool) {}
+
+    fn is_flushed(&self) -> bool {
+        true
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn is_connecting(&self) -> bool {
+        false
+    }
+
+    fn take_datagram(_data: &mut Self::DataT) -> Option<(IpEndpoint, usize)> {
+        None
+    }
+
+    fn hop_limit(&self) -> Option<u8> {
+        None
+    }
+
+    fn set_hop_limit(&mut self, _hop_limit: Option<u8>) {}
+
+    fn ip_flow(&self, _data: &Self::DataT) -> Option<Flow> {
+        None
+    }
+
+    fn is_bound(&self) -> bool {
+        true
+    }
+
+    fn remote_addr(&self, _data: &Self::DataT) -> Option<IpAddress> {
+        // The destination comes with the header of every packet
+        None
+    }
 
     fn new_socket(
         iface: &mut SmolnetInterface,
@@ -65,14 +189,16 @@ impl<'a> SchemeSocket for RawSocket<'a> {
         }
         let proto =
             u8::from_str_radix(path, 16).or_else(|_| Err(SyscallError::new(syscall::ENOENT)))?;
+        let mtu = iface.device().get_ref().mtu();
+        let buffer_size = socket_buffer_size();
 
         let rx_buffer = RawSocketBuffer::new(
-            vec![RawPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-            vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+            vec![RawPacketMetadata::EMPTY; buffer_size],
+            vec![0; mtu * buffer_size],
         );
         let tx_buffer = RawSocketBuffer::new(
-            vec![RawPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-            vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+            vec![RawPacketMetadata::EMPTY; buffer_size],
+            vec![0; mtu * buffer_size],
         );
         let ip_socket = RawSocket::new(
             IpVersion::Ipv4,
@@ -81,7 +207,7 @@ impl<'a> SchemeSocket for RawSocket<'a> {
             tx_buffer,
         );
 
-        let socket_handle = iface.add_socket(ip_socket);
+        let socket_handle = add_socket(iface, ip_socket)?;
         Ok((socket_handle, ()))
     }
 
@@ -92,10 +218,12 @@ impl<'a> SchemeSocket for RawSocket<'a> {
     fn write_buf(
         &mut self,
         file: &mut SocketFile<Self::DataT>,
+        mtu: usize,
         buf: &[u8],
     ) -> SyscallResult<Option<usize>> {
+        check_header(self, mtu, buf)?;
         if self.can_send() {
-            self.send_slice(buf).expect("Can't send slice");
+            self.send_slice(buf).map_err(smoltcp_err_to_syscall)?;
             Ok(Some(buf.len()))
         } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
             Err(SyscallError::new(syscall::EAGAIN))
@@ -110,7 +238,7 @@ impl<'a> SchemeSocket for RawSocket<'a> {
         buf: &mut [u8],
     ) -> SyscallResult<Option<usize>> {
         if self.can_recv() {
-            let length = self.recv_slice(buf).expect("Can't receive slice");
+            let length = self.recv_slice(buf).map_err(smoltcp_err_to_syscall)?;
             Ok(Some(length))
         } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
             Err(SyscallError::new(syscall::EAGAIN))
@@ -119,25 +247,26 @@ impl<'a> SchemeSocket for RawSocket<'a> {
         }
     }
 
+    fn fsync(&mut self, _file: &mut SocketFile<Self::DataT>) -> SyscallResult<Option<usize>> {
+        Ok(Some(0))
+    }
+
     fn dup(
         _iface: &mut SmolnetInterface,
         _file: &mut SchemeFile<Self>,
         _path: &str,
+        _query: &BTreeMap<&str, &str>,
         _: &mut Self::SchemeDataT,
     ) -> SyscallResult<DupResult<Self>> {
         Err(SyscallError::new(syscall::EBADF))
     }
 
+    fn describe(&self, _file: &SocketFile<Self::DataT>) -> String {
+        format!("ip {} * RAW", self.ip_protocol())
+    }
+
     fn fpath(&self, _file: &SchemeFile<Self>, buf: &mut [u8]) -> SyscallResult<usize> {
         let path = format!("ip:{}", self.ip_protocol());
-        let path = path.as_bytes();
-
-        let mut i = 0;
-        while i < buf.len() && i < path.len() {
-            buf[i] = path[i];
-            i += 1;
-        }
-
-        Ok(i)
+        write_path(&path, buf)
     }
 }
@@ -17,15 +17,20 @@ use syscall::data::TimeSpec;
 use self::icmp::IcmpScheme;
 use self::ip::IpScheme;
 use self::netcfg::NetCfgScheme;
+use self::pcap::PcapScheme;
+use self::socket::{set_max_files, set_socket_buffer_size, time_before};
 use self::tcp::TcpScheme;
 use self::udp::UdpScheme;
 use buffer_pool::{Buffer, BufferPool};
-use device::NetworkDevice;
+use device::{Captures, NetworkDevice};
 use redox_netstack::error::{Error, Result};
 
+#[cfg(test)]
+mod harness;
 mod icmp;
 mod ip;
 mod netcfg;
+mod pcap;
 mod socket;
 mod tcp;
 mod udp;
@@ -33,32 +38,43 @@ mod udp;
 type SmolnetInterface = Interface<'static, Tracer<NetworkDevice>>;
 type Iface = Rc<RefCell<SmolnetInterface>>;
 
-const MAX_DURATION: Duration = Duration::from_micros(::std::u64::MAX);
 const MIN_DURATION: Duration = Duration::from_micros(0);
 
+/// Daemon state the `health` netcfg node reports, refreshed after every poll.
+#[derive(Default)]
+pub struct Health {
+    /// Monotonic time the interface was last polled at
+    last_poll: TimeSpec,
+    /// Operations blocked across the socket schemes
+    waiting: usize,
+}
+
+pub type HealthRef = Rc<RefCell<Health>>;
+
 pub struct Smolnetd {
     network_file: Rc<RefCell<File>>,
     time_file: File,
 
     iface: Iface,
     timer: ::std::time::Instant,
+    next_time_event: Option<TimeSpec>,
 
     ip_scheme: IpScheme,
     udp_scheme: UdpScheme,
     tcp_scheme: TcpScheme,
     icmp_scheme: IcmpScheme,
     netcfg_scheme: NetCfgScheme,
+    pcap_scheme: PcapScheme,
     input_queue: Rc<RefCell<VecDeque<Buffer>>>,
     buffer_pool: Rc<RefCell<BufferPool>>,
+    health: HealthRef,
 }
 
 impl Smolnetd {
     const MAX_PACKET_SIZE: usize = 2048;
-    const SOCKET_BUFFER_SIZE: usize = 128; //packets
 
-    // multiplied by 1000 due to previously being in milliseconds (millis)
-    const MIN_CHECK_TIMEOUT: Duration = Duration::from_millis(10); 
-    const MAX_CHECK_TIMEOUT: Duration = Duration::from_millis(500);
+    /// Retry delay when polling keeps failing or never settles
+    const MIN_CHECK_TIMEOUT: Duration = Duration::from_millis(10);
 
     pub fn new(
         network_file: File,
@@ -68,89 +84,99 @@ impl Smolnetd {
         icmp_file: File,
         time_file: File,
         netcfg_file: File,
+        pcap_file: File,
     ) -> Smolnetd {
         let hardware_addr = EthernetAddress::from_str(getcfg("mac").unwrap().trim())
             .expect("Can't parse the 'mac' cfg");
         let local_ip =
             IpAddress::from_str(getcfg("ip").unwrap().trim()).expect("Can't parse the 'ip' cfg.");
-        let protocol_addrs = vec![
-            IpCidr::new(local_ip, 24),
-            IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
-        ];
         let default_gw = Ipv4Address::from_str(getcfg("ip_router").unwrap().trim())
             .expect("Can't parse the 'ip_router' cfg.");
+        // Optional, the default stays in place when it's missing or invalid
+        if let Ok(size) = getcfg("socket_buffers") {
+            match usize::from_str(size.trim()) {
+                Ok(size) if set_socket_buffer_size(size).is_ok() => (),
+                _ => warn!("Ignoring invalid 'socket_buffers' cfg: {}", size.trim()),
+            }
+        }
+        if let Ok(max) = getcfg("max_fds") {
+            match usize::from_str(max.trim()) {
+                Ok(max) if max > 0 => set_max_files(max),
+                _ => warn!("Ignoring invalid 'max_fds' cfg: {}", max.trim()),
+            }
+        }
 
         let buffer_pool = Rc::new(RefCell::new(BufferPool::new(Self::MAX_PACKET_SIZE)));
         let input_queue = Rc::new(RefCell::new(VecDeque::new()));
         let network_file = Rc::new(RefCell::new(network_file));
-        let network_device = Tracer::new(
-            NetworkDevice::new(
-                Rc::clone(&network_file),
-                Rc::clone(&input_queue),
-                hardware_addr,
-                Rc::clone(&buffer_pool),
-            ),
-            |_timestamp, printer| trace!("{}", printer),
+        let captures = Rc::new(RefCell::new(Captures::default()));
+        let network_device = NetworkDevice::new(
+            Rc::clone(&network_file),
+            Rc::clone(&input_queue),
+            hardware_addr,
+            Rc::clone(&buffer_pool),
+            Rc::clone(&captures),
         );
-        let mut routes = Routes::new(BTreeMap::new());
-        routes
-            .add_default_ipv4_route(default_gw)
-            .expect("Failed to add default gateway");
-        let iface = InterfaceBuilder::new(network_device, vec![])
-            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
-            .hardware_addr(HardwareAddress::Ethernet(hardware_addr))
-            .ip_addrs(protocol_addrs)
-            .routes(routes)
-            .finalize();
+        let iface = new_interface(network_device, hardware_addr, local_ip, default_gw);
         let iface = Rc::new(RefCell::new(iface));
+        let health = Rc::new(RefCell::new(Health::default()));
         Smolnetd {
             iface: Rc::clone(&iface),
             timer: ::std::time::Instant::now(),
+            next_time_event: None,
             time_file,
             ip_scheme: IpScheme::new(Rc::clone(&iface), ip_file),
             udp_scheme: UdpScheme::new(Rc::clone(&iface), udp_file),
             tcp_scheme: TcpScheme::new(Rc::clone(&iface), tcp_file),
             icmp_scheme: IcmpScheme::new(Rc::clone(&iface), icmp_file),
-            netcfg_scheme: NetCfgScheme::new(Rc::clone(&iface), netcfg_file),
+            netcfg_scheme: NetCfgScheme::new(Rc::clone(&iface), netcfg_file, Rc::clone(&health)),
+            pcap_scheme: PcapScheme::new(captures, pcap_file),
             input_queue,
             network_file,
             buffer_pool,
+            health,
         }
     }
 
     pub fn on_network_scheme_event(&mut self) -> Result<Option<()>> {
         if self.read_frames()? > 0 {
-            self.poll()?;
+            let timeout = self.poll()?;
+            self.schedule_time_event(timeout)?;
         }
         Ok(None)
     }
 
     pub fn on_ip_scheme_event(&mut self) -> Result<Option<()>> {
         self.ip_scheme.on_scheme_event()?;
-        let _ = self.poll()?;
+        let timeout = self.poll()?;
+        self.schedule_time_event(timeout)?;
         Ok(None)
     }
 
     pub fn on_udp_scheme_event(&mut self) -> Result<Option<()>> {
         trace!("received event");
         self.udp_scheme.on_scheme_event()?;
-        let _ = self.poll()?;
+        let timeout = self.poll()?;
+        self.schedule_time_event(timeout)?;
         Ok(None)
     }
 
     pub fn on_tcp_scheme_event(&mut self) -> Result<Option<()>> {
         self.tcp_scheme.on_scheme_event()?;
-        let _ = self.poll()?;
+        let timeout = self.poll()?;
+        self.schedule_time_event(timeout)?;
         Ok(None)
     }
 
     pub fn on_icmp_scheme_event(&mut self) -> Result<Option<()>> {
         self.icmp_scheme.on_scheme_event()?;
-        let _ = self.poll()?;
+        let timeout = self.poll()?;
+        self.schedule_time_event(timeout)?;
         Ok(None)
     }
 
     pub fn on_time_event(&mut self) -> Result<Option<()>> {
+        self.next_time_event = None;
         let timeout = self.poll()?;
         self.schedule_time_event(timeout)?;
         //TODO: Fix network scheme to ensure events are not missed
@@ -159,10 +185,26 @@ impl Smolnetd {
 
     pub fn on_netcfg_scheme_event(&mut self) -> Result<Option<()>> {
         self.netcfg_scheme.on_scheme_event()?;
+        // A link going down fails the operations blocked on sockets right away
+        let timeout = self.poll()?;
+        self.schedule_time_event(timeout)?;
+        if self.netcfg_scheme.poll_requested() {
+            self.netcfg_scheme.complete_poll()?;
+        }
         Ok(None)
     }
 
-    fn schedule_time_event(&mut self, timeout: Duration) -> Result<()> {
+    pub fn on_pcap_scheme_event(&mut self) -> Result<Option<()>> {
+        self.pcap_scheme.on_scheme_event()?;
+        Ok(None)
+    }
+
+    /// Arms the time file, unless a wakeup at or before the new deadline is already pending.
+    fn schedule_time_event(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
         let mut time = TimeSpec::default();
         if self.time_file.read(&mut time)? < size_of::<TimeSpec>() {
             return Err(Error::from_syscall_error(
@@ -170,49 +212,72 @@ impl Smolnetd {
                 "Can't read current time",
             ));
         }
-        let mut time_ms = time.tv_sec * 1000i64 + i64::from(time.tv_nsec) / 1_000_000i64;
-        time_ms += timeout.total_millis() as i64;
-        time.tv_sec = time_ms / 1000;
-        time.tv_nsec = ((time_ms % 1000) * 1_000_000) as i32;
+        let timeout_us = timeout.total_micros();
+        let nsecs = i64::from(time.tv_nsec) + (timeout_us % 1_000_000) as i64 * 1_000;
+        time.tv_sec += (timeout_us / 1_000_000) as i64 + nsecs / 1_000_000_000;
+        time.tv_nsec = (nsecs % 1_000_000_000) as i32;
+
+        if let Some(ref scheduled) = self.next_time_event {
+            if !time_before(&time, scheduled) {
+                return Ok(());
+            }
+        }
+
         self.time_file
             .write_all(&time)
             .map_err(|e| Error::from_io_error(e, "Failed to write to time file"))?;
+        self.next_time_event = Some(time);
         Ok(())
     }
 
-    fn poll(&mut self) -> Result<Duration> {
-        let timeout = {
+    /// Current time on the clock the interface runs on.
+    fn timestamp(&self) -> Instant {
+        Instant::from(self.timer)
+    }
+
+    /// Polls the interface and returns how long until either the interface or
+    /// a blocked operation next needs servicing, `None` if nothing is pending.
+    fn poll(&mut self) -> Result<Option<Duration>> {
+        let poll_delay = {
             let mut iter_limit = 10usize;
             let mut iface = self.iface.borrow_mut();
-            let timestamp = Instant::from(self.timer);
             loop {
                 if iter_limit == 0 {
-                    break MIN_DURATION;
+                    break Some(Smolnetd::MIN_CHECK_TIMEOUT);
                 }
                 iter_limit -= 1;
+                let timestamp = self.timestamp();
                 match iface.poll(timestamp) {
                     Ok(_) | Err(smoltcp::Error::Unrecognized) => (),
                     Err(e) => {
                         error!("poll error: {}", e);
-                        break MIN_DURATION;
+                        break Some(Smolnetd::MIN_CHECK_TIMEOUT);
                     }
                 }
                 match iface.poll_delay(timestamp) {
-                    Some(delay) => {
-                        match delay.millis() {
-                            0 => {},
-                            _ => break ::std::cmp::min(MAX_DURATION, delay),
-                        }
-                    },
-                    None => break MAX_DURATION,
+                    // More work is already due, poll again right away
+                    Some(delay) if delay == MIN_DURATION => (),
+                    delay => break delay,
                 }
             }
         };
-        self.notify_sockets()?;
-        Ok(::std::cmp::min(
-            ::std::cmp::max(Smolnetd::MIN_CHECK_TIMEOUT, timeout),
-            Smolnetd::MAX_CHECK_TIMEOUT,
-        ))
+        let deadline_delay = match self.notify_sockets()? {
+            Some(deadline) => Some(duration_until(&deadline)?),
+            None => None,
+        };
+        {
+            let mut health = self.health.borrow_mut();
+            syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut health.last_poll)
+                .map_err(|e| Error::from_syscall_error(e, "Can't get time"))?;
+            health.waiting = self.ip_scheme.waiting() + self.udp_scheme.waiting()
+                + self.tcp_scheme.waiting() + self.icmp_scheme.waiting();
+        }
+        Ok(match (poll_delay, deadline_delay) {
+            (Some(poll_delay), Some(deadline_delay)) => {
+                Some(::std::cmp::min(poll_delay, deadline_delay))
+            }
+            (poll_delay, deadline_delay) => poll_delay.or(deadline_delay),
+        })
     }
 
     fn read_frames(&mut self) -> Result<usize> {
@@ -239,11 +304,63 @@ impl Smolnetd {
         Ok(total_frames)
     }
 
-    fn notify_sockets(&mut self) -> Result<()> {
-        self.ip_scheme.notify_sockets()?;
-        self.udp_scheme.notify_sockets()?;
-        self.tcp_scheme.notify_sockets()?;
-        self.icmp_scheme.notify_sockets()
+    /// Returns the earliest deadline among the blocked operations of all schemes.
+    fn notify_sockets(&mut self) -> Result<Option<TimeSpec>> {
+        let deadlines = [
+            self.ip_scheme.notify_sockets()?,
+            self.udp_scheme.notify_sockets()?,
+            self.tcp_scheme.notify_sockets()?,
+            self.icmp_scheme.notify_sockets()?,
+        ];
+        self.pcap_scheme.notify_captures()?;
+        Ok(deadlines
+            .iter()
+            .filter_map(|deadline| *deadline)
+            .fold(None, |earliest: Option<TimeSpec>, deadline| match earliest {
+                Some(earliest) if !time_before(&deadline, &earliest) => Some(earliest),
+                _ => Some(deadline),
+            }))
+    }
+}
+
+/// Builds the interface on top of `network_device`, owning `local_ip` on a
+/// /24 along with the loopback network and routing the rest to `default_gw`.
+fn new_interface(
+    network_device: NetworkDevice,
+    hardware_addr: EthernetAddress,
+    local_ip: IpAddress,
+    default_gw: Ipv4Address,
+) -> SmolnetInterface {
+    let network_device = Tracer::new(network_device, |_timestamp, printer| {
+        trace!("{}", printer)
+    });
+    let protocol_addrs = vec![
+        IpCidr::new(local_ip, 24),
+        IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
+    ];
+    let mut routes = Routes::new(BTreeMap::new());
+    routes
+        .add_default_ipv4_route(default_gw)
+        .expect("Failed to add default gateway");
+    InterfaceBuilder::new(network_device, vec![])
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .hardware_addr(HardwareAddress::Ethernet(hardware_addr))
+        .ip_addrs(protocol_addrs)
+        .routes(routes)
+        .finalize()
+}
+
+/// Time left until `deadline`, rounded up so that a wakeup never comes early.
+fn duration_until(deadline: &TimeSpec) -> Result<Duration> {
+    let mut cur_time = TimeSpec::default();
+    syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut cur_time)
+        .map_err(|e| Error::from_syscall_error(e, "Can't get time"))?;
+    let nsecs = (deadline.tv_sec - cur_time.tv_sec) * 1_000_000_000
+        + i64::from(deadline.tv_nsec - cur_time.tv_nsec);
+    if nsecs <= 0 {
+        Ok(MIN_DURATION)
+    } else {
+        Ok(Duration::from_micros((nsecs as u64 + 999) / 1_000))
     }
 }
 
@@ -263,17 +380,174 @@ fn post_fevent(scheme_file: &mut File, fd: usize, event: usize, data_len: usize)
         .map_err(|e| Error::from_io_error(e, "failed to post fevent"))
 }
 
-fn parse_endpoint(socket: &str) -> IpEndpoint {
+/// Splits `path?key=value&key=value` into the bare path and its query parameters.
+fn split_query(path: &str) -> (&str, BTreeMap<&str, &str>) {
+    let mut parts = path.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let mut query = BTreeMap::new();
+    if let Some(params) = parts.next() {
+        for param in params.split('&').filter(|param| !param.is_empty()) {
+            let mut pair = param.splitn(2, '=');
+            let key = pair.next().unwrap_or("");
+            query.insert(key, pair.next().unwrap_or(""));
+        }
+    }
+    (path, query)
+}
+
+/// Parses a local `host:port` that may give a `lo-hi` port range instead of
+/// a port, returning the endpoint with a wildcard port along with the range.
+fn parse_local_endpoint(socket: &str) -> syscall::Result<(IpEndpoint, Option<(u16, u16)>)> {
+    let (host, ports) = match socket.rfind(':') {
+        Some(i) => (&socket[..i], &socket[i + 1..]),
+        None => return Ok((parse_endpoint(socket)?, None)),
+    };
+    let mut bounds = ports.splitn(2, '-');
+    let lo = bounds.next().unwrap_or("");
+    let hi = match bounds.next() {
+        Some(hi) => hi,
+        None => return Ok((parse_endpoint(socket)?, None)),
+    };
+    let lo = lo.parse::<u16>()
+        .map_err(|_| syscall::Error::new(syscall::EINVAL))?;
+    let hi = hi.parse::<u16>()
+        .map_err(|_| syscall::Error::new(syscall::EINVAL))?;
+    if lo == 0 || lo > hi {
+        return Err(syscall::Error::new(syscall::EINVAL));
+    }
+    Ok((parse_endpoint(host)?, Some((lo, hi))))
+}
+
+/// Role of a socket stated by a `listen:` or `connect:` prefix of its open path.
+#[derive(Clone, Copy, PartialEq)]
+enum OpenMode {
+    /// No prefix, the socket connects if a remote endpoint is given
+    Infer,
+    Listen,
+    Connect,
+}
+
+impl OpenMode {
+    /// Fails with `EINVAL` when the remote endpoint contradicts the mode.
+    fn check(self, remote_endpoint: &IpEndpoint) -> syscall::Result<()> {
+        match self {
+            OpenMode::Listen if remote_endpoint.is_specified() => {
+                Err(syscall::Error::new(syscall::EINVAL))
+            }
+            OpenMode::Connect if !remote_endpoint.is_specified() => {
+                Err(syscall::Error::new(syscall::EINVAL))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Splits an open path into its mode and its remote and local endpoints.
+/// Paths take `remote/local`, except that `listen:local` only names the
+/// local endpoint, as `/local` does.
+fn split_open_path(path: &str) -> (OpenMode, &str, &str) {
+    let (mode, endpoints) = if path.starts_with("listen:") {
+        (OpenMode::Listen, &path["listen:".len()..])
+    } else if path.starts_with("connect:") {
+        (OpenMode::Connect, &path["connect:".len()..])
+    } else {
+        (OpenMode::Infer, path)
+    };
+    if mode == OpenMode::Listen && !endpoints.contains('/') {
+        return (mode, "", endpoints);
+    }
+    let mut parts = endpoints.split('/');
+    let remote = parts.next().unwrap_or("");
+    let local = parts.next().unwrap_or("");
+    (mode, remote, local)
+}
+
+/// Parses `host:port`, where an empty host or port stands for the wildcard,
+/// as does a `0` host.
+fn parse_endpoint(socket: &str) -> syscall::Result<IpEndpoint> {
     let mut socket_parts = socket.split(':');
-    let host = IpAddress::Ipv4(
-        Ipv4Address::from_str(socket_parts.next().unwrap_or(""))
-            .unwrap_or_else(|_| Ipv4Address::new(0, 0, 0, 0)),
-    );
-
-    let port = socket_parts
-        .next()
-        .unwrap_or("")
-        .parse::<u16>()
-        .unwrap_or(0);
-    IpEndpoint::new(host, port)
+    let host = match socket_parts.next().unwrap_or("") {
+        "" | "0" => Ipv4Address::new(0, 0, 0, 0),
+        host => Ipv4Address::from_str(host)
+            .map_err(|_| syscall::Error::new(syscall::EINVAL))?,
+    };
+    let port = match socket_parts.next().unwrap_or("") {
+        "" => 0,
+        port => port
+            .parse::<u16>()
+            .map_err(|_| syscall::Error::new(syscall::EINVAL))?,
+    };
+    if socket_parts.next().is_some() {
+        return Err(syscall::Error::new(syscall::EINVAL));
+    }
+    Ok(IpEndpoint::new(IpAddress::Ipv4(host), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_query_params() {
+        let (path, query) = split_query("listen");
+        assert_eq!(path, "listen");
+        assert!(query.is_empty());
+
+        let (path, query) = split_query("listen?");
+        assert_eq!(path, "listen");
+        assert!(query.is_empty());
+
+        let (path, query) = split_query("connect?timeout=5&&nodelay");
+        assert_eq!(path, "connect");
+        assert_eq!(query.len(), 2);
+        assert_eq!(query.get("timeout"), Some(&"5"));
+        assert_eq!(query.get("nodelay"), Some(&""));
+
+        // Only the first `?` and `=` split, later ones belong to the value
+        let (path, query) = split_query("1.2.3.4:80?key=a=b?c");
+        assert_eq!(path, "1.2.3.4:80");
+        assert_eq!(query.get("key"), Some(&"a=b?c"));
+    }
+
+    #[test]
+    fn parse_endpoint_wildcards() {
+        let any = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(0, 0, 0, 0)), 0);
+        assert_eq!(parse_endpoint("").unwrap(), any);
+        assert_eq!(parse_endpoint("0").unwrap(), any);
+        assert_eq!(parse_endpoint(":0").unwrap(), any);
+        assert_eq!(
+            parse_endpoint("0:80").unwrap(),
+            IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(0, 0, 0, 0)), 80)
+        );
+        assert!(parse_endpoint("0.0.0:80").is_err());
+        assert!(parse_endpoint("1.2.3.4:80:1").is_err());
+    }
+
+    #[test]
+    fn parse_local_endpoint_port_range() {
+        let (endpoint, range) = parse_local_endpoint("0:40000-40100").unwrap();
+        assert_eq!(
+            endpoint,
+            IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(0, 0, 0, 0)), 0)
+        );
+        assert_eq!(range, Some((40000, 40100)));
+
+        let (endpoint, range) = parse_local_endpoint("10.0.0.1:5000-5000").unwrap();
+        assert_eq!(
+            endpoint,
+            IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(10, 0, 0, 1)), 0)
+        );
+        assert_eq!(range, Some((5000, 5000)));
+
+        let (endpoint, range) = parse_local_endpoint("10.0.0.1:5000").unwrap();
+        assert_eq!(
+            endpoint,
+            IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(10, 0, 0, 1)), 5000)
+        );
+        assert_eq!(range, None);
+
+        assert!(parse_local_endpoint("0:0-100").is_err());
+        assert!(parse_local_endpoint("0:200-100").is_err());
+        assert!(parse_local_endpoint("0:a-100").is_err());
+    }
 }
@@ -3,7 +3,7 @@ mod nodes;
 mod notifier;
 
 use smoltcp::wire::{IpAddress, EthernetAddress, IpCidr, Ipv4Address, HardwareAddress};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
@@ -11,7 +11,7 @@ use std::rc::Rc;
 use std::mem;
 use std::str::FromStr;
 use std::str;
-use syscall::data::Stat;
+use syscall::data::{Stat, TimeSpec};
 use syscall::flag::{MODE_DIR, MODE_FILE};
 use syscall::{Error as SyscallError, EventFlags as SyscallEventFlags, Packet as SyscallPacket, Result as SyscallResult, SchemeMut};
 use syscall;
@@ -19,7 +19,10 @@ use syscall;
 use self::nodes::*;
 use self::notifier::*;
 use redox_netstack::error::{Error, Result};
-use super::{post_fevent, Iface};
+use redox_netstack::logger::recent_records;
+use super::socket::{max_sockets, privileged_port_max, set_max_sockets, set_privileged_port_max,
+                    set_socket_buffer_size, socket_buffer_size};
+use super::{post_fevent, HealthRef, Iface};
 
 const WRITE_BUFFER_MAX_SIZE: usize = 0xffff;
 
@@ -47,8 +50,66 @@ fn parse_default_gw(value: &str) -> SyscallResult<Ipv4Address> {
     Err(SyscallError::new(syscall::EINVAL))
 }
 
-fn mk_root_node(iface: Iface, notifier: NotifierRef, dns_config: DNSConfigRef) -> CfgNodeRef {
+/// `aa:bb:cc:dd:ee:ff` form of a MAC address, smoltcp printing it with dashes.
+fn format_mac(mac: &EthernetAddress) -> String {
+    let bytes = mac.as_bytes();
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]
+    )
+}
+
+fn mk_root_node(
+    iface: Iface,
+    notifier: NotifierRef,
+    dns_config: DNSConfigRef,
+    health: HealthRef,
+    poll_requested: Rc<Cell<bool>>,
+) -> CfgNodeRef {
     cfg_node!{
+        "poll" => {
+            wo [poll_requested] (bool, false)
+            |written, _line| {
+                *written = true;
+                Ok(())
+            }
+            |written| {
+                if *written {
+                    poll_requested.set(true);
+                }
+                Ok(())
+            }
+        },
+        "health" => {
+            ro [iface, health] || {
+                let iface = iface.borrow();
+                let ifaces_up = iface.device().get_ref().link_up() as usize;
+                let health = health.borrow();
+                let mut now = TimeSpec::default();
+                // Reads can't fail, a supervisor sees a zero time instead
+                let _ = syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut now);
+                format!(
+                    "ifaces_up={}\nsockets={}\nwaiting={}\nlast_poll={}.{:09}\nnow={}.{:09}\n",
+                    ifaces_up,
+                    iface.sockets().count(),
+                    health.waiting,
+                    health.last_poll.tv_sec,
+                    health.last_poll.tv_nsec,
+                    now.tv_sec,
+                    now.tv_nsec
+                )
+            }
+        },
+        "log" => {
+            ro [] || {
+                let mut log = String::new();
+                for record in recent_records() {
+                    log += &record;
+                    log.push('\n');
+                }
+                log
+            }
+        },
         "resolv" => {
             "nameserver" => {
                 rw [dns_config, notifier] (Option<Ipv4Address>, None)
@@ -77,6 +138,87 @@ fn mk_root_node(iface: Iface, notifier: NotifierRef, dns_config: DNSConfigRef) -
                 }
             }
         },
+        "ports" => {
+            "privileged" => {
+                rw [notifier] (Option<u16>, None)
+                || {
+                    format!("{}\n", privileged_port_max())
+                }
+                |cur_value, line| {
+                    if cur_value.is_none() {
+                        let port = u16::from_str(line.trim())
+                            .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                        *cur_value = Some(port);
+                        Ok(())
+                    } else {
+                        Err(SyscallError::new(syscall::EINVAL))
+                    }
+                }
+                |cur_value| {
+                    if let Some(port) = *cur_value {
+                        set_privileged_port_max(port);
+                        notifier.borrow_mut().schedule_notify("ports/privileged");
+                    }
+                    Ok(())
+                }
+            }
+        },
+        "sockets" => {
+            "buffers" => {
+                rw [notifier] (Option<usize>, None)
+                || {
+                    format!("{}\n", socket_buffer_size())
+                }
+                |cur_value, line| {
+                    if cur_value.is_none() {
+                        let size = usize::from_str(line.trim())
+                            .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                        if size == 0 {
+                            return Err(SyscallError::new(syscall::EINVAL));
+                        }
+                        *cur_value = Some(size);
+                        Ok(())
+                    } else {
+                        Err(SyscallError::new(syscall::EINVAL))
+                    }
+                }
+                |cur_value| {
+                    if let Some(size) = *cur_value {
+                        set_socket_buffer_size(size)?;
+                        notifier.borrow_mut().schedule_notify("sockets/buffers");
+                    }
+                    Ok(())
+                }
+            },
+            "count" => {
+                ro [iface] || {
+                    format!("{}\n", iface.borrow().sockets().count())
+                }
+            },
+            "max" => {
+                rw [notifier] (Option<usize>, None)
+                || {
+                    format!("{}\n", max_sockets())
+                }
+                |cur_value, line| {
+                    if cur_value.is_none() {
+                        let max = usize::from_str(line.trim())
+                            .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                        *cur_value = Some(max);
+                        Ok(())
+                    } else {
+                        Err(SyscallError::new(syscall::EINVAL))
+                    }
+                }
+                |cur_value| {
+                    if let Some(max) = *cur_value {
+                        set_max_sockets(max);
+                        notifier.borrow_mut().schedule_notify("sockets/max");
+                    }
+                    Ok(())
+                }
+            }
+        },
         "route" => {
             "list" => {
                 ro [iface] || {
@@ -180,6 +322,155 @@ fn mk_root_node(iface: Iface, notifier: NotifierRef, dns_config: DNSConfigRef) -
                         Ok(())
                     }
                 },
+                "stats" => {
+                    rw [iface] (bool, false)
+                    || {
+                        let stats = iface.borrow().device().get_ref().stats();
+                        format!(
+                            "rx_packets {}\nrx_bytes {}\ntx_packets {}\ntx_bytes {}\n\
+                             drops {}\nchecksum_errors {}\nno_socket {}\n",
+                            stats.rx_packets,
+                            stats.rx_bytes,
+                            stats.tx_packets,
+                            stats.tx_bytes,
+                            stats.drops,
+                            stats.checksum_errors,
+                            stats.no_socket
+                        )
+                    }
+                    |reset, line| {
+                        if line.trim() == "reset" {
+                            *reset = true;
+                            Ok(())
+                        } else {
+                            Err(SyscallError::new(syscall::EINVAL))
+                        }
+                    }
+                    |reset| {
+                        if *reset {
+                            iface.borrow().device().get_ref().reset_stats();
+                        }
+                        Ok(())
+                    }
+                },
+                "hwaddr" => {
+                    try_ro [iface] || {
+                        #[allow(unreachable_patterns)]
+                        match iface.borrow().hardware_addr() {
+                            HardwareAddress::Ethernet(mac) => Ok(format!("{}\n", format_mac(&mac))),
+                            _ => Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                        }
+                    }
+                },
+                "mtu" => {
+                    rw [iface, notifier] (Option<usize>, None)
+                    || {
+                        format!("{}\n", iface.borrow().device().get_ref().mtu())
+                    }
+                    |cur_value, line| {
+                        if cur_value.is_none() {
+                            let mtu = usize::from_str(line.trim())
+                                .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                            *cur_value = Some(mtu);
+                            Ok(())
+                        } else {
+                            Err(SyscallError::new(syscall::EINVAL))
+                        }
+                    }
+                    |cur_value| {
+                        if let Some(mtu) = *cur_value {
+                            if !iface.borrow().device().get_ref().set_mtu(mtu) {
+                                return Err(SyscallError::new(syscall::EINVAL));
+                            }
+                            notifier.borrow_mut().schedule_notify("ifaces/eth0/mtu");
+                        }
+                        Ok(())
+                    }
+                },
+                "link" => {
+                    rw [iface, notifier] (Option<bool>, None)
+                    || {
+                        if iface.borrow().device().get_ref().link_up() {
+                            "up\n".to_string()
+                        } else {
+                            "down\n".to_string()
+                        }
+                    }
+                    |cur_value, line| {
+                        if cur_value.is_none() {
+                            let up = match line.trim() {
+                                "up" => true,
+                                "down" => false,
+                                _ => return Err(SyscallError::new(syscall::EINVAL)),
+                            };
+                            *cur_value = Some(up);
+                            Ok(())
+                        } else {
+                            Err(SyscallError::new(syscall::EINVAL))
+                        }
+                    }
+                    |cur_value| {
+                        if let Some(up) = *cur_value {
+                            iface.borrow().device().get_ref().set_link_up(up);
+                            notifier.borrow_mut().schedule_notify("ifaces/eth0/link");
+                        }
+                        Ok(())
+                    }
+                },
+                "neigh" => {
+                    "list" => {
+                        ro [iface]
+                        || {
+                            let mut neighbors = String::new();
+                            for (ip, mac) in iface.borrow().device().get_ref().static_neighbors() {
+                                neighbors += &format!("{} {}\n", ip, mac);
+                            }
+                            neighbors
+                        }
+                    },
+                    "add" => {
+                        wo [iface, notifier] (Vec<(Ipv4Address, EthernetAddress)>, Vec::new())
+                        |cur_value, line| {
+                            let mut words = line.split_whitespace();
+                            let ip = Ipv4Address::from_str(words.next().unwrap_or(""))
+                                .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                            let mac = EthernetAddress::from_str(words.next().unwrap_or(""))
+                                .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                            if words.next().is_some() || !ip.is_unicast() || !mac.is_unicast() {
+                                return Err(SyscallError::new(syscall::EINVAL));
+                            }
+                            cur_value.push((ip, mac));
+                            Ok(())
+                        }
+                        |cur_value| {
+                            let iface = iface.borrow();
+                            for &(ip, mac) in cur_value.iter() {
+                                iface.device().get_ref().set_static_neighbor(ip, Some(mac));
+                            }
+                            notifier.borrow_mut().schedule_notify("ifaces/eth0/neigh/list");
+                            Ok(())
+                        }
+                    },
+                    "rm" => {
+                        wo [iface, notifier] (Vec<Ipv4Address>, Vec::new())
+                        |cur_value, line| {
+                            let ip = Ipv4Address::from_str(line.trim())
+                                .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                            cur_value.push(ip);
+                            Ok(())
+                        }
+                        |cur_value| {
+                            let iface = iface.borrow();
+                            for &ip in cur_value.iter() {
+                                if !iface.device().get_ref().set_static_neighbor(ip, None) {
+                                    return Err(SyscallError::new(syscall::EINVAL));
+                                }
+                            }
+                            notifier.borrow_mut().schedule_notify("ifaces/eth0/neigh/list");
+                            Ok(())
+                        }
+                    },
+                },
                 "addr" => {
                     "list" => {
                         ro [iface]
@@ -342,20 +633,33 @@ pub struct NetCfgScheme {
     files: BTreeMap<usize, NetCfgFile>,
     root_node: CfgNodeRef,
     notifier: NotifierRef,
+    /// Set by a write to `poll`, whose reply waits for the next interface poll
+    poll_requested: Rc<Cell<bool>>,
+    /// Replies held back until that poll is done
+    deferred: Vec<SyscallPacket>,
 }
 
 impl NetCfgScheme {
-    pub fn new(iface: Iface, scheme_file: File) -> NetCfgScheme {
+    pub fn new(iface: Iface, scheme_file: File, health: HealthRef) -> NetCfgScheme {
         let notifier = Notifier::new_ref();
         let dns_config = Rc::new(RefCell::new(DNSConfig {
             name_server: Ipv4Address::new(8, 8, 8, 8),
         }));
+        let poll_requested = Rc::new(Cell::new(false));
         NetCfgScheme {
             scheme_file,
             next_fd: 1,
             files: BTreeMap::new(),
-            root_node: mk_root_node(iface, Rc::clone(&notifier), dns_config),
+            root_node: mk_root_node(
+                iface,
+                Rc::clone(&notifier),
+                dns_config,
+                health,
+                Rc::clone(&poll_requested),
+            ),
             notifier,
+            poll_requested,
+            deferred: Vec::new(),
         }
     }
 
@@ -375,12 +679,31 @@ impl NetCfgScheme {
                 }
             }
             self.handle(&mut packet);
-            self.scheme_file.write_all(&packet)?;
+            // Requests coming along with a poll request wait for it as well
+            if self.poll_requested.get() {
+                self.deferred.push(packet);
+            } else {
+                self.scheme_file.write_all(&packet)?;
+            }
         };
         self.notify_scheduled_fds();
         Ok(result)
     }
 
+    /// Whether a write to `poll` is waiting for the interface to be polled.
+    pub fn poll_requested(&self) -> bool {
+        self.poll_requested.get()
+    }
+
+    /// Replies to the requests held back for a poll, once it has run.
+    pub fn complete_poll(&mut self) -> Result<()> {
+        self.poll_requested.set(false);
+        for packet in self.deferred.drain(..) {
+            self.scheme_file.write_all(&packet)?;
+        }
+        Ok(())
+    }
+
     fn notify_scheduled_fds(&mut self) {
         let fds_to_notify = self.notifier.borrow_mut().get_notified_fds();
         for fd in fds_to_notify {
@@ -404,7 +727,7 @@ impl SchemeMut for NetCfgScheme {
             current_node = next_node;
         }
         let current_node = current_node.borrow();
-        let read_buf = Vec::from(current_node.read());
+        let read_buf = Vec::from(current_node.read()?);
         let fd = self.next_fd;
         trace!("open {} {}", fd, path);
         self.next_fd += 1;
@@ -66,8 +66,8 @@ pub trait CfgNode {
         true
     }
 
-    fn read(&self) -> String {
-        String::new()
+    fn read(&self) -> SyscallResult<String> {
+        Ok(String::new())
     }
 
     fn open(&self, _file: &str) -> Option<CfgNodeRef> {
@@ -90,8 +90,8 @@ impl<F> CfgNode for RONode<F>
 where
     F: Fn() -> String,
 {
-    fn read(&self) -> String {
-        (self.read_fun)()
+    fn read(&self) -> SyscallResult<String> {
+        Ok((self.read_fun)())
     }
 }
 
@@ -104,6 +104,32 @@ where
     }
 }
 
+/// Read only node whose content may not exist, failing the open instead.
+pub struct TryRONode<F>
+where
+    F: Fn() -> SyscallResult<String>,
+{
+    read_fun: F,
+}
+
+impl<F> CfgNode for TryRONode<F>
+where
+    F: Fn() -> SyscallResult<String>,
+{
+    fn read(&self) -> SyscallResult<String> {
+        (self.read_fun)()
+    }
+}
+
+impl<F> TryRONode<F>
+where
+    F: 'static + Fn() -> SyscallResult<String>,
+{
+    pub fn new_ref(read_fun: F) -> CfgNodeRef {
+        Rc::new(RefCell::new(TryRONode { read_fun }))
+    }
+}
+
 pub struct WONode<W>
 where
     W: 'static + Fn() -> Box<NodeWriter>,
@@ -151,8 +177,8 @@ where
     F: Fn() -> String,
     W: 'static + Fn() -> Box<NodeWriter>,
 {
-    fn read(&self) -> String {
-        (self.read_fun)()
+    fn read(&self) -> SyscallResult<String> {
+        Ok((self.read_fun)())
     }
 
     fn is_writable(&self) -> bool {
@@ -186,7 +212,7 @@ impl CfgNode for StaticDirNode {
         true
     }
 
-    fn read(&self) -> String {
+    fn read(&self) -> SyscallResult<String> {
         let mut files = String::new();
         for child in self.child_nodes.keys() {
             if !files.is_empty() {
@@ -194,7 +220,7 @@ impl CfgNode for StaticDirNode {
             }
             files += child;
         }
-        files
+        Ok(files)
     }
 
     fn open(&self, file: &str) -> Option<CfgNodeRef> {
@@ -218,6 +244,12 @@ macro_rules! cfg_node {
             RONode::new_ref(move|| $b)
         }
     };
+    (try_ro [ $($c:ident),* ] || $b:block ) => {
+        {
+            $(let $c = $c.clone();)*
+            TryRONode::new_ref(move|| $b)
+        }
+    };
     (wo [ $($c:ident),* ] ( $et:ty , $e:expr ) |$data_i:ident, $line_i:ident|
      $write_line:block |$data_i2:ident| $commit:block) => {
         {
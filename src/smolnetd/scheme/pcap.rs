@@ -0,0 +1,252 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use syscall;
+use syscall::flag::EVENT_READ;
+use syscall::{
+    Error as SyscallError, EventFlags as SyscallEventFlags, Packet as SyscallPacket,
+    Result as SyscallResult, SchemeBlockMut,
+};
+
+use device::Captures;
+use redox_netstack::error::{Error, Result};
+use super::post_fevent;
+use super::socket::write_path;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65_535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+struct CaptureFile {
+    flags: usize,
+    events: usize,
+    read_notified: bool,
+    header_sent: bool,
+}
+
+/// Read-only scheme handing out the frames seen by the device in pcap format.
+///
+/// The first read on a capture fd returns the pcap global header, every
+/// following read returns a single record.
+pub struct PcapScheme {
+    next_fd: usize,
+    files: BTreeMap<usize, CaptureFile>,
+    captures: Rc<RefCell<Captures>>,
+    scheme_file: File,
+    wait_queue: Vec<SyscallPacket>,
+}
+
+impl PcapScheme {
+    pub fn new(captures: Rc<RefCell<Captures>>, scheme_file: File) -> PcapScheme {
+        PcapScheme {
+            next_fd: 1,
+            files: BTreeMap::new(),
+            captures,
+            scheme_file,
+            wait_queue: Vec::new(),
+        }
+    }
+
+    pub fn on_scheme_event(&mut self) -> Result<Option<()>> {
+        let result = loop {
+            let mut packet = SyscallPacket::default();
+            match self.scheme_file.read(&mut packet) {
+                Ok(0) => {
+                    let fds: Vec<usize> = self.files.keys().cloned().collect();
+                    for fd in fds {
+                        let _ = self.close(fd);
+                    }
+                    self.wait_queue.clear();
+                    break Some(());
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock {
+                        break None;
+                    } else {
+                        return Err(Error::from(err));
+                    }
+                }
+            }
+            if let Some(a) = self.handle(&mut packet) {
+                packet.a = a;
+                self.scheme_file.write_all(&packet)?;
+            } else {
+                self.wait_queue.push(packet);
+            }
+        };
+        Ok(result)
+    }
+
+    /// Completes blocked reads and signals the capture fds that got new frames.
+    pub fn notify_captures(&mut self) -> Result<()> {
+        for (&fd, file) in &mut self.files {
+            if file.events & EVENT_READ.bits() == EVENT_READ.bits()
+                && self.captures.borrow().has_frames(fd)
+            {
+                if !file.read_notified {
+                    file.read_notified = true;
+                    post_fevent(&mut self.scheme_file, fd, EVENT_READ.bits(), 1)?;
+                }
+            } else {
+                file.read_notified = false;
+            }
+        }
+
+        let mut i = 0;
+        while i < self.wait_queue.len() {
+            let mut packet = self.wait_queue[i];
+            if let Some(a) = self.handle(&packet) {
+                self.wait_queue.remove(i);
+                packet.a = a;
+                self.scheme_file.write_all(&packet)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SchemeBlockMut for PcapScheme {
+    fn open(
+        &mut self,
+        path: &str,
+        flags: usize,
+        uid: u32,
+        _gid: u32,
+    ) -> SyscallResult<Option<usize>> {
+        if !path.is_empty() {
+            return Err(SyscallError::new(syscall::ENOENT));
+        }
+        if uid != 0 {
+            return Err(SyscallError::new(syscall::EACCES));
+        }
+        if flags & syscall::O_ACCMODE != syscall::O_RDONLY {
+            return Err(SyscallError::new(syscall::EACCES));
+        }
+
+        let id = self.next_fd;
+        self.next_fd += 1;
+
+        self.files.insert(
+            id,
+            CaptureFile {
+                flags,
+                events: 0,
+                read_notified: false,
+                header_sent: false,
+            },
+        );
+        self.captures.borrow_mut().add(id);
+
+        Ok(Some(id))
+    }
+
+    fn close(&mut self, fd: usize) -> SyscallResult<Option<usize>> {
+        self.files
+            .remove(&fd)
+            .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+        self.captures.borrow_mut().remove(fd);
+        self.wait_queue.retain(|packet| packet.b != fd);
+        Ok(Some(0))
+    }
+
+    fn read(&mut self, fd: usize, buf: &mut [u8]) -> SyscallResult<Option<usize>> {
+        let file = self
+            .files
+            .get_mut(&fd)
+            .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+
+        if !file.header_sent {
+            if buf.len() < GLOBAL_HEADER_LEN {
+                return Err(SyscallError::new(syscall::EINVAL));
+            }
+            LittleEndian::write_u32(&mut buf[0..4], PCAP_MAGIC);
+            LittleEndian::write_u16(&mut buf[4..6], PCAP_VERSION_MAJOR);
+            LittleEndian::write_u16(&mut buf[6..8], PCAP_VERSION_MINOR);
+            // UTC timestamps without accuracy information
+            LittleEndian::write_i32(&mut buf[8..12], 0);
+            LittleEndian::write_u32(&mut buf[12..16], 0);
+            LittleEndian::write_u32(&mut buf[16..20], PCAP_SNAPLEN);
+            LittleEndian::write_u32(&mut buf[20..24], LINKTYPE_ETHERNET);
+            file.header_sent = true;
+            return Ok(Some(GLOBAL_HEADER_LEN));
+        }
+
+        if buf.len() < RECORD_HEADER_LEN {
+            return Err(SyscallError::new(syscall::EINVAL));
+        }
+
+        match self.captures.borrow_mut().pop(fd) {
+            Some((time, frame)) => {
+                // Frames that don't fit are truncated, the record keeps their original length
+                let len = cmp::min(frame.len(), buf.len() - RECORD_HEADER_LEN);
+                LittleEndian::write_u32(&mut buf[0..4], time.tv_sec as u32);
+                LittleEndian::write_u32(&mut buf[4..8], (time.tv_nsec / 1_000) as u32);
+                LittleEndian::write_u32(&mut buf[8..12], len as u32);
+                LittleEndian::write_u32(&mut buf[12..16], frame.len() as u32);
+                buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len].copy_from_slice(&frame[..len]);
+                Ok(Some(RECORD_HEADER_LEN + len))
+            }
+            None => {
+                if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+                    Err(SyscallError::new(syscall::EAGAIN))
+                } else {
+                    Ok(None) // internally scheduled to re-read
+                }
+            }
+        }
+    }
+
+    fn fevent(
+        &mut self,
+        fd: usize,
+        events: SyscallEventFlags,
+    ) -> SyscallResult<Option<SyscallEventFlags>> {
+        let file = self
+            .files
+            .get_mut(&fd)
+            .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+        file.events = events.bits();
+        file.read_notified = false; // resend missed events
+        if events.contains(EVENT_READ) && self.captures.borrow().has_frames(fd) {
+            file.read_notified = true;
+            Ok(Some(EVENT_READ))
+        } else {
+            Ok(Some(SyscallEventFlags::empty()))
+        }
+    }
+
+    fn fpath(&mut self, fd: usize, buf: &mut [u8]) -> SyscallResult<Option<usize>> {
+        if !self.files.contains_key(&fd) {
+            return Err(SyscallError::new(syscall::EBADF));
+        }
+        write_path("pcap:", buf).map(Some)
+    }
+
+    fn fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SyscallResult<Option<usize>> {
+        let file = self
+            .files
+            .get_mut(&fd)
+            .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+        match cmd {
+            syscall::F_GETFL => Ok(Some(file.flags)),
+            syscall::F_SETFL => {
+                file.flags = (file.flags & syscall::O_ACCMODE) | (arg & !syscall::O_ACCMODE);
+                Ok(Some(0))
+            }
+            _ => Err(SyscallError::new(syscall::EINVAL)),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cmp::{self, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
 use std::fs::File;
 use std::io::{ErrorKind, Read, Write};
 use std::marker::PhantomData;
@@ -8,8 +9,11 @@ use std::mem;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::rc::Rc;
+use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use byteorder::{ByteOrder, LittleEndian};
 use syscall;
 use syscall::data::TimeSpec;
 use syscall::flag::{EVENT_READ, EVENT_WRITE};
@@ -19,19 +23,34 @@ use syscall::{
 };
 
 use redox_netstack::error::{Error, Result};
+use smoltcp;
 use smoltcp::socket::{AnySocket};
 use smoltcp::iface::{SocketHandle};
+use smoltcp::wire::{
+    IpAddress, IpEndpoint, IpProtocol, ETHERNET_HEADER_LEN, IPV4_HEADER_LEN, TCP_HEADER_LEN,
+};
 
-use super::{post_fevent, Iface, SmolnetInterface};
+use super::{post_fevent, split_query, Iface, SmolnetInterface};
+use device::{Flow, NetworkDevice};
 
 pub struct NullFile {
     pub flags: usize,
+    /// Descriptor flags such as `FD_CLOEXEC`, only stored for the kernel to query
+    pub fd_flags: usize,
     pub uid: u32,
     pub gid: u32,
 }
 
 pub struct SocketFile<DataT> {
     pub flags: usize,
+    /// Descriptor flags such as `FD_CLOEXEC`, only stored for the kernel to query
+    pub fd_flags: usize,
+    /// Bytes a stream socket waits for before a blocking read returns, zero
+    /// or one reading whatever is there
+    pub recv_lowat: usize,
+    /// Free send buffer bytes a stream socket waits for before reporting
+    /// `EVENT_WRITE`, zero reporting it whenever anything fits
+    pub send_lowat: usize,
     pub data: DataT,
 
     events: usize,
@@ -40,17 +59,25 @@ pub struct SocketFile<DataT> {
     write_notified: bool,
     read_timeout: Option<TimeSpec>,
     write_timeout: Option<TimeSpec>,
+    pending_error: Option<SyscallError>,
 }
 
 impl<DataT> SocketFile<DataT> {
     pub fn clone_with_data(&self, data: DataT) -> SocketFile<DataT> {
         SocketFile {
             flags: self.flags,
-            events: self.events,
-            read_notified: false, // we still want to notify about this new socket
+            // Like dup(2), the new descriptor doesn't inherit close-on-exec
+            fd_flags: 0,
+            recv_lowat: self.recv_lowat,
+            send_lowat: self.send_lowat,
+            // Event subscriptions belong to the fd, the new one starts without
+            // any and both edges fire again once it subscribes via fevent
+            events: 0,
+            read_notified: false,
             write_notified: false,
             read_timeout: self.read_timeout,
             write_timeout: self.write_timeout,
+            pending_error: None,
             socket_handle: self.socket_handle,
             data,
         }
@@ -59,23 +86,102 @@ impl<DataT> SocketFile<DataT> {
     pub fn new_with_data(socket_handle: SocketHandle, data: DataT) -> SocketFile<DataT> {
         SocketFile {
             flags: 0,
+            fd_flags: 0,
+            recv_lowat: 0,
+            send_lowat: 0,
             events: 0,
             read_notified: false,
             write_notified: false,
             read_timeout: None,
             write_timeout: None,
+            pending_error: None,
             socket_handle,
             data,
         }
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 enum Setting<SettingT: Copy> {
     Ttl,
     ReadTimeout,
     WriteTimeout,
-    #[allow(dead_code)]
+    Stats,
+    /// Type-of-Service byte (DSCP and ECN) of outgoing IPv4 packets. For TCP a
+    /// change only applies to segments sent afterwards.
+    Tos,
+    /// How long closing the last fd waits for queued data to be delivered,
+    /// zero discarding it instead.
+    Linger,
+    /// Time without any data going in or out after which the socket is
+    /// aborted and its fds fail with `ETIMEDOUT`.
+    IdleTimeout,
+    /// Bytes that a read would return right now, for datagram sockets that's
+    /// only the next datagram since a read never spans two of them.
+    InQ,
+    /// Bytes queued for sending that haven't been sent or acknowledged yet.
+    OutQ,
+    /// uid and gid that opened the socket, as two little-endian u32s
+    Owner,
+    /// Clamp on the TCP maximum segment size advertised and accepted in SYN
+    /// segments. Established connections keep the size they negotiated.
+    MaxSegmentSize,
+    /// Minimum bytes a blocking stream read waits for, as a little-endian u32.
+    /// `EVENT_READ` waits for as much, unless the connection was closed.
+    RecvLowat,
+    /// Minimum free send buffer bytes before a stream socket reports
+    /// `EVENT_WRITE`, as a little-endian u32. Writes themselves ignore it.
+    SendLowat,
+    /// Reading it discards everything in the receive buffer and returns the
+    /// number of bytes dropped as a little-endian u64.
+    FlushRecv,
+    /// Whether UDP datagrams go out with a zero checksum, as a single byte.
+    NoChecksum,
+    /// Whether path MTU discovery is off, as a single byte. Packets then go out
+    /// without the don't-fragment bit so routers fragment them instead of
+    /// dropping them. The stack itself never fragments, so writes stay bounded
+    /// by the interface MTU, and a TCP MSS clamp still applies on top of it.
+    NoPmtud,
+    /// Whether outgoing IPv4 packets carry the don't-fragment bit, as a single
+    /// byte. Until written it goes by `NoPmtud`, afterwards it overrides it.
+    /// Either way a datagram larger than the interface MTU fails with
+    /// `EMSGSIZE`, since the stack never fragments.
+    DontFragment,
+    /// Whether writes only reach hosts on a directly attached subnet, failing
+    /// with `ENETUNREACH` instead of going through the gateway, as a single byte.
+    DontRoute,
+    /// Name of the interface the socket is pinned to, empty if none. Writes
+    /// to hosts that interface can't reach fail with `ENETUNREACH`.
+    BindToDevice,
+    /// Reading it returns how many reads, writes and other operations are
+    /// blocked on the socket fd as a little-endian u64, writing anything
+    /// fails all of them with `EINTR`.
+    Cancel,
+    /// Whether TCP urgent data arrived since the last read, as a single byte.
+    /// smoltcp ignores the urgent pointer, so urgent bytes are only ever
+    /// delivered in line with the rest of the stream, and sending them fails
+    /// with `EOPNOTSUPP`.
+    Oob,
+    /// Whether reads of the socket fd start with the `TimeSpec` the data was
+    /// received at, as a single byte. For datagrams that's when each one
+    /// arrived, for streams when the newest data read did. Only UDP and TCP
+    /// sockets can turn it on.
+    Timestamping,
+    /// SYN retransmissions of a TCP connect before it fails with `ETIMEDOUT`,
+    /// as a little-endian u32. Only SYNs sent after setting it count, and
+    /// the count starts over once the connect is answered or gives up.
+    SynRetries,
+    /// Bytes per second the socket may send, as a little-endian u32. Zero
+    /// lifts the limit.
+    SendRate,
+    /// Transmit queue the socket's packets go out through, as a little-endian
+    /// u32 below `TX_QUEUES`.
+    TxQueue,
+    /// Handed out by the `abort` dup once the TCP connection was reset, it
+    /// reads nothing and only holds a reference to the socket.
+    Abort,
+    /// Snapshot of every setting that can be read, see `read_options`.
+    Options,
     Other(SettingT),
 }
 
@@ -111,15 +217,19 @@ where
         if let &mut SchemeFile::Socket(SocketFile {
             socket_handle,
             events,
+            recv_lowat,
+            send_lowat,
+            ref data,
             ref mut read_notified,
             ref mut write_notified,
             ..
         }) = self
         {
             let socket = iface.get_socket::<SocketT>(socket_handle);
+            let ready = readiness(socket, data, recv_lowat, send_lowat);
 
             if events & syscall::EVENT_READ.bits() == syscall::EVENT_READ.bits()
-                && (socket.can_recv() || !socket.may_recv())
+                && ready & EVENT_READ.bits() == EVENT_READ.bits()
             {
                 if !*read_notified {
                     *read_notified = true;
@@ -130,7 +240,7 @@ where
             }
 
             if events & syscall::EVENT_WRITE.bits() == syscall::EVENT_WRITE.bits()
-                && socket.can_send()
+                && ready & EVENT_WRITE.bits() == EVENT_WRITE.bits()
             {
                 if !*write_notified {
                     *write_notified = true;
@@ -144,13 +254,334 @@ where
     }
 }
 
+/// Event flags the socket currently satisfies.
+fn readiness<SocketT: SchemeSocket>(
+    socket: &SocketT,
+    data: &SocketT::DataT,
+    recv_lowat: usize,
+    send_lowat: usize,
+) -> usize {
+    let mut ready = 0;
+    // A closed connection is readable whatever the watermark
+    if (socket.can_recv() && socket.recv_lowat_met(recv_lowat)) || !socket.may_recv(data) {
+        ready |= EVENT_READ.bits();
+    }
+    if socket.can_send() && socket.send_lowat_met(send_lowat) {
+        ready |= EVENT_WRITE.bits();
+    }
+    ready
+}
+
+/// Control file reporting the readiness of many sockets at once.
+///
+/// Writing `(fd, events)` pairs of little-endian u64 subscribes to the socket
+/// fds of the same scheme, zero events unsubscribing. Reads return the
+/// `(fd, revents)` pairs of the sockets that became ready since the last read.
+struct EventFile {
+    flags: usize,
+    events: usize,
+    read_notified: bool,
+    interest: BTreeMap<usize, usize>,
+    /// Events already reported per socket fd, until they stop holding
+    reported: BTreeMap<usize, usize>,
+    ready: BTreeMap<usize, usize>,
+}
+
+impl EventFile {
+    const PAIR_SIZE: usize = 2 * 8;
+
+    fn new(flags: usize) -> EventFile {
+        EventFile {
+            flags,
+            events: 0,
+            read_notified: false,
+            interest: BTreeMap::new(),
+            reported: BTreeMap::new(),
+            ready: BTreeMap::new(),
+        }
+    }
+
+    fn forget(&mut self, fd: usize) {
+        self.interest.remove(&fd);
+        self.reported.remove(&fd);
+        self.ready.remove(&fd);
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> SyscallResult<Option<usize>> {
+        if buf.len() < Self::PAIR_SIZE {
+            return Err(SyscallError::new(syscall::EINVAL));
+        }
+        if self.ready.is_empty() {
+            return if self.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+                Err(SyscallError::new(syscall::EAGAIN))
+            } else {
+                Ok(None) // internally scheduled to re-read
+            };
+        }
+        let fds: Vec<usize> = self
+            .ready
+            .keys()
+            .cloned()
+            .take(buf.len() / Self::PAIR_SIZE)
+            .collect();
+        let mut count = 0;
+        for fd in fds {
+            let revents = self.ready.remove(&fd).unwrap_or(0);
+            LittleEndian::write_u64(&mut buf[count..count + 8], fd as u64);
+            LittleEndian::write_u64(&mut buf[count + 8..count + 16], revents as u64);
+            count += Self::PAIR_SIZE;
+        }
+        Ok(Some(count))
+    }
+}
+
+/// Snapshot of the open sockets taken when the listing was opened, one
+/// `fd type local remote state` line per socket fd.
+struct SocketList {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl SocketList {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let remaining = &self.data[self.offset..];
+        let mut count = ::std::cmp::min(buf.len(), remaining.len());
+        // Only hand out whole lines, unless a single one doesn't fit
+        if count < remaining.len() {
+            if let Some(end) = remaining[..count].iter().rposition(|&b| b == b'\n') {
+                count = end + 1;
+            }
+        }
+        buf[..count].copy_from_slice(&remaining[..count]);
+        self.offset += count;
+        count
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct SocketStats {
+    bytes_sent: u64,
+    bytes_recv: u64,
+    packets_sent: u64,
+    packets_recv: u64,
+}
+
+impl SocketStats {
+    const SIZE: usize = 4 * 8;
+
+    fn on_send(&mut self, count: usize) {
+        self.bytes_sent += count as u64;
+        self.packets_sent += 1;
+    }
+
+    fn on_recv(&mut self, count: usize) {
+        self.bytes_recv += count as u64;
+        self.packets_recv += 1;
+    }
+}
+
+struct IdleTimer {
+    timeout: TimeSpec,
+    last_activity: TimeSpec,
+}
+
+impl IdleTimer {
+    fn touch(&mut self) {
+        let _ = syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut self.last_activity);
+    }
+
+    fn deadline(&self) -> TimeSpec {
+        add_time(&self.timeout, &self.last_activity)
+    }
+}
+
+/// Token bucket limiting the bytes a socket sends per second, which holds
+/// up to a second worth of them. A write goes through as long as a token is
+/// left and may overdraw the bucket, so datagrams are never cut.
+struct SendRate {
+    rate: u32,
+    tokens: i64,
+    last_refill: TimeSpec,
+}
+
+impl SendRate {
+    fn new(rate: u32, cur_time: TimeSpec) -> SendRate {
+        SendRate {
+            rate,
+            tokens: i64::from(rate),
+            last_refill: cur_time,
+        }
+    }
+
+    fn refill(&mut self, cur_time: &TimeSpec) {
+        let elapsed_ns = (cur_time.tv_sec - self.last_refill.tv_sec)
+            .saturating_mul(1_000_000_000)
+            .saturating_add(i64::from(cur_time.tv_nsec - self.last_refill.tv_nsec));
+        let elapsed_ns = cmp::min(cmp::max(elapsed_ns, 0), 1_000_000_000);
+        let refilled = i64::from(self.rate) * elapsed_ns / 1_000_000_000;
+        // The time that didn't add up to a whole token yet carries over
+        if refilled > 0 {
+            self.tokens = cmp::min(self.tokens + refilled, i64::from(self.rate));
+            self.last_refill = *cur_time;
+        }
+    }
+
+    fn can_send(&self) -> bool {
+        self.tokens > 0
+    }
+
+    fn on_send(&mut self, count: usize) {
+        self.tokens -= count as i64;
+    }
+
+    /// When the bucket holds a token again.
+    fn next_refill(&self) -> TimeSpec {
+        let missing = (1 - self.tokens) as u64;
+        let ns = (missing * 1_000_000_000 + u64::from(self.rate) - 1) / u64::from(self.rate);
+        let delay = TimeSpec {
+            tv_sec: (ns / 1_000_000_000) as i64,
+            tv_nsec: (ns % 1_000_000_000) as i32,
+        };
+        add_time(&self.last_refill, &delay)
+    }
+}
+
+/// Socket whose last fd is being closed while its queued data drains.
+struct Lingering<SocketT>
+where
+    SocketT: SchemeSocket,
+{
+    file: SchemeFile<SocketT>,
+    until: TimeSpec,
+}
+
 #[derive(Default, Clone)]
 struct WaitHandle {
     until: Option<TimeSpec>,
     packet: SyscallPacket,
 }
 
-type WaitQueue = Vec<WaitHandle>;
+/// Blocked packets in the order they arrived, along with a min-heap of their
+/// timeouts so expiring them doesn't take a scan of every waiter.
+///
+/// Heap entries of packets that completed otherwise stay behind until they
+/// reach the top or outnumber the waiters.
+#[derive(Default)]
+struct WaitQueue {
+    next_id: u64,
+    handles: BTreeMap<u64, WaitHandle>,
+    deadlines: BinaryHeap<Reverse<(i64, i32, u64)>>,
+}
+
+impl WaitQueue {
+    fn push(&mut self, handle: WaitHandle) {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(until) = handle.until {
+            self.deadlines.push(Reverse((until.tv_sec, until.tv_nsec, id)));
+        }
+        self.handles.insert(id, handle);
+    }
+
+    /// Ids of the waiting packets, oldest first.
+    fn ids(&self) -> Vec<u64> {
+        self.handles.keys().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    fn get(&self, id: u64) -> Option<&WaitHandle> {
+        self.handles.get(&id)
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.handles.remove(&id);
+        self.compact();
+    }
+
+    fn retain<F: FnMut(&WaitHandle) -> bool>(&mut self, mut f: F) {
+        let ids: Vec<u64> = self
+            .handles
+            .iter()
+            .filter(|&(_, handle)| !f(handle))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            self.handles.remove(&id);
+        }
+        self.compact();
+    }
+
+    /// Removes and returns the packets blocked on `fd`, oldest first.
+    fn take_fd(&mut self, fd: usize) -> Vec<WaitHandle> {
+        let ids: Vec<u64> = self
+            .handles
+            .iter()
+            .filter(|&(_, handle)| handle.packet.b == fd)
+            .map(|(&id, _)| id)
+            .collect();
+        let taken = ids
+            .into_iter()
+            .filter_map(|id| self.handles.remove(&id))
+            .collect();
+        self.compact();
+        taken
+    }
+
+    fn clear(&mut self) {
+        self.handles.clear();
+        self.deadlines.clear();
+    }
+
+    /// Removes and returns the packets whose timeout is before `cur_time`.
+    fn expire(&mut self, cur_time: &TimeSpec) -> Vec<WaitHandle> {
+        let mut expired = Vec::new();
+        loop {
+            let id = match self.deadlines.peek() {
+                Some(&Reverse((tv_sec, tv_nsec, id)))
+                    if time_before(&TimeSpec { tv_sec, tv_nsec }, cur_time) =>
+                {
+                    id
+                }
+                _ => break,
+            };
+            self.deadlines.pop();
+            if let Some(handle) = self.handles.remove(&id) {
+                expired.push(handle);
+            }
+        }
+        expired
+    }
+
+    /// Earliest timeout of the packets still waiting.
+    fn next_deadline(&mut self) -> Option<TimeSpec> {
+        loop {
+            match self.deadlines.peek() {
+                Some(&Reverse((tv_sec, tv_nsec, id))) => {
+                    if self.handles.contains_key(&id) {
+                        return Some(TimeSpec { tv_sec, tv_nsec });
+                    }
+                }
+                None => return None,
+            }
+            self.deadlines.pop();
+        }
+    }
+
+    /// Rebuilds the heap once stale entries make up most of it.
+    fn compact(&mut self) {
+        if self.deadlines.len() > 2 * self.handles.len() + 16 {
+            let handles = &self.handles;
+            self.deadlines = self
+                .deadlines
+                .drain()
+                .filter(|&Reverse((_, _, id))| handles.contains_key(&id))
+                .collect();
+        }
+    }
+}
 
 pub type DupResult<T> = Option<(
     SchemeFile<T>,
@@ -163,19 +594,64 @@ where
 {
     type SchemeDataT;
     type DataT;
-    type SettingT: Copy;
+    type SettingT: Copy + PartialEq;
 
     fn new_scheme_data() -> Self::SchemeDataT;
+    /// Whether a local port is bound by some socket, `None` for schemes
+    /// without ports.
+    fn port_claimed(data: &Self::SchemeDataT, port: u16) -> Option<bool>;
 
     fn can_send(&self) -> bool;
     fn can_recv(&self) -> bool;
-    fn may_recv(&self) -> bool;
-
-    fn hop_limit(&self) -> u8;
-    fn set_hop_limit(&mut self, limit: u8);
-
-    fn get_setting(socket_file: &SocketFile<Self::DataT>, setting: Self::SettingT, data: &mut [u8]) -> SyscallResult<usize>;
-    fn set_setting(socket_file: &mut SocketFile<Self::DataT>, setting: Self::SettingT, data: &[u8]) -> SyscallResult<usize>;
+    fn may_recv(&self, data: &Self::DataT) -> bool;
+
+    /// Hop limit (IPv4 TTL) set on the socket, `None` using the interface default.
+    fn hop_limit(&self) -> Option<u8>;
+    fn set_hop_limit(&mut self, limit: Option<u8>);
+
+    /// Flow of the packets of this socket as seen through the fd owning
+    /// `data`, which its packet hooks apply to.
+    fn ip_flow(&self, data: &Self::DataT) -> Option<Flow>;
+    /// Whether the local endpoint is bound, false while the bind is deferred.
+    fn is_bound(&self) -> bool;
+    /// Address every write goes to, if the socket has a fixed destination.
+    fn remote_addr(&self, data: &Self::DataT) -> Option<IpAddress>;
+
+    fn parse_setting(name: &str) -> Option<Self::SettingT>;
+    /// Settings that reading has no side effect on, snapshotted by `options`.
+    fn setting_names() -> &'static [&'static str];
+    fn get_setting(&self, socket_file: &SocketFile<Self::DataT>, setting: Self::SettingT, data: &mut [u8]) -> SyscallResult<usize>;
+    fn set_setting(&mut self, socket_file: &mut SocketFile<Self::DataT>, setting: Self::SettingT, data: &[u8]) -> SyscallResult<usize>;
+
+    /// Reports an asynchronous error (such as a retransmission timeout) exactly once.
+    fn take_error(&mut self) -> Option<SyscallError>;
+
+    /// Bytes that the next read could return, if the socket can tell.
+    fn recv_available(&mut self) -> Option<usize>;
+    /// Bytes still waiting in the send buffer, if the socket can tell.
+    fn send_queued(&self) -> Option<usize>;
+    /// Whether enough was received for a read to honor the `recv_lowat`
+    /// watermark. Datagram sockets ignore it as a datagram is read whole.
+    fn recv_lowat_met(&self, recv_lowat: usize) -> bool;
+    /// Whether enough of the send buffer is free for `EVENT_WRITE` to honor
+    /// the `send_lowat` watermark. Datagram sockets ignore it.
+    fn send_lowat_met(&self, send_lowat: usize) -> bool;
+    /// Discards everything queued for reading, returning the bytes dropped.
+    /// The connection itself is left as it is.
+    fn flush_recv(&mut self) -> usize;
+
+    /// Starts closing a lingering socket, `abort` discarding its queued data.
+    fn shutdown(&mut self, abort: bool);
+    /// Whether a lingering socket has delivered everything it had queued.
+    fn is_flushed(&self) -> bool;
+    /// Whether the socket reached a state it never leaves again, such as a
+    /// stream the peer reset, so blocked reads and writes can't complete.
+    fn is_terminated(&self) -> bool;
+    /// Whether a connect is still waiting for the peer to answer its SYN.
+    fn is_connecting(&self) -> bool;
+    /// Source and full length of the datagram the last read of the fd owning
+    /// `data` returned, if any, which only the first call reports.
+    fn take_datagram(data: &mut Self::DataT) -> Option<(IpEndpoint, usize)>;
 
     fn new_socket(
         iface: &mut SmolnetInterface,
@@ -189,6 +665,7 @@ where
     fn write_buf(
         &mut self,
         file: &mut SocketFile<Self::DataT>,
+        mtu: usize,
         buf: &[u8],
     ) -> SyscallResult<Option<usize>>;
 
@@ -198,12 +675,20 @@ where
         buf: &mut [u8],
     ) -> SyscallResult<Option<usize>>;
 
+    fn fsync(&mut self, file: &mut SocketFile<Self::DataT>) -> SyscallResult<Option<usize>>;
+
     fn fpath(&self, file: &SchemeFile<Self>, data: &mut [u8]) -> SyscallResult<usize>;
 
+    /// `type local remote state` of the socket, for the socket listing.
+    fn describe(&self, file: &SocketFile<Self::DataT>) -> String;
+
+    /// `name` comes without the `?key=value` query of the dup path, which is
+    /// passed as `query`.
     fn dup(
         iface: &mut SmolnetInterface,
         file: &mut SchemeFile<Self>,
         name: &str,
+        query: &BTreeMap<&str, &str>,
         data: &mut Self::SchemeDataT,
     ) -> SyscallResult<DupResult<Self>>;
 }
@@ -215,9 +700,34 @@ where
     next_fd: usize,
     nulls: BTreeMap<usize, NullFile>,
     files: BTreeMap<usize, SchemeFile<SocketT>>,
+    /// Number of fds, socket and setting ones alike, referring to each socket
+    refs: BTreeMap<SocketHandle, usize>,
+    event_files: BTreeMap<usize, EventFile>,
+    lists: BTreeMap<usize, SocketList>,
     iface: Iface,
     scheme_file: File,
     wait_queue: WaitQueue,
+    stats: BTreeMap<SocketHandle, SocketStats>,
+    tos: BTreeMap<SocketHandle, u8>,
+    mss: BTreeMap<SocketHandle, u16>,
+    syn_retries: BTreeMap<SocketHandle, u32>,
+    no_checksum: BTreeSet<SocketHandle>,
+    no_pmtud: BTreeSet<SocketHandle>,
+    /// Don't-fragment bit as written through `df`, over what `no_pmtud` implies
+    dont_frag: BTreeMap<SocketHandle, bool>,
+    dont_route: BTreeSet<SocketHandle>,
+    bound_device: BTreeMap<SocketHandle, String>,
+    oob: BTreeSet<SocketHandle>,
+    timestamping: BTreeSet<SocketHandle>,
+    /// Setting fds subscribed to `EVENT_READ`, see `notify_setting_change`
+    setting_watchers: BTreeSet<usize>,
+    linger: BTreeMap<SocketHandle, TimeSpec>,
+    idle: BTreeMap<SocketHandle, IdleTimer>,
+    send_rate: BTreeMap<SocketHandle, SendRate>,
+    /// uid and gid of whoever opened each socket
+    owners: BTreeMap<SocketHandle, (u32, u32)>,
+    tx_queue: BTreeMap<SocketHandle, u32>,
+    lingering: BTreeMap<usize, Lingering<SocketT>>,
     scheme_data: SocketT::SchemeDataT,
     _phantom_socket: PhantomData<SocketT>,
 }
@@ -231,10 +741,31 @@ where
             next_fd: 1,
             nulls: BTreeMap::new(),
             files: BTreeMap::new(),
+            refs: BTreeMap::new(),
+            event_files: BTreeMap::new(),
+            lists: BTreeMap::new(),
             iface,
             scheme_data: SocketT::new_scheme_data(),
             scheme_file,
-            wait_queue: Vec::new(),
+            wait_queue: WaitQueue::default(),
+            stats: BTreeMap::new(),
+            tos: BTreeMap::new(),
+            mss: BTreeMap::new(),
+            syn_retries: BTreeMap::new(),
+            no_checksum: BTreeSet::new(),
+            no_pmtud: BTreeSet::new(),
+            dont_frag: BTreeMap::new(),
+            dont_route: BTreeSet::new(),
+            bound_device: BTreeMap::new(),
+            oob: BTreeSet::new(),
+            timestamping: BTreeSet::new(),
+            setting_watchers: BTreeSet::new(),
+            linger: BTreeMap::new(),
+            idle: BTreeMap::new(),
+            send_rate: BTreeMap::new(),
+            owners: BTreeMap::new(),
+            tx_queue: BTreeMap::new(),
+            lingering: BTreeMap::new(),
             _phantom_socket: PhantomData,
         }
     }
@@ -244,7 +775,7 @@ where
             let mut packet = SyscallPacket::default();
             match self.scheme_file.read(&mut packet) {
                 Ok(0) => {
-                    //TODO: Cleanup must occur
+                    self.close_all();
                     break Some(());
                 }
                 Ok(_) => (),
@@ -281,11 +812,112 @@ where
         Ok(result)
     }
 
-    pub fn notify_sockets(&mut self) -> Result<()> {
+    /// Number of operations blocked until their socket is ready or times out.
+    pub fn waiting(&self) -> usize {
+        self.wait_queue.len()
+    }
+
+    /// Returns the earliest deadline of the blocked operations still waiting.
+    pub fn notify_sockets(&mut self) -> Result<Option<TimeSpec>> {
         let mut cur_time = TimeSpec::default();
         syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut cur_time)
             .map_err(|e| Error::from_syscall_error(e, "Can't get time"))?;
 
+        // Latch asynchronous socket errors so the next read or write reports them
+        {
+            let mut iface = self.iface.borrow_mut();
+            let mut errors = BTreeMap::new();
+
+            let mut expired = Vec::new();
+            for (&socket_handle, timer) in &mut self.idle {
+                // Data waiting to be read counts as activity
+                if iface.get_socket::<SocketT>(socket_handle).can_recv() {
+                    timer.last_activity = cur_time;
+                } else if !time_before(&cur_time, &timer.deadline()) {
+                    expired.push(socket_handle);
+                }
+            }
+            for socket_handle in expired {
+                trace!("socket idle timeout: {}", socket_handle);
+                self.idle.remove(&socket_handle);
+                iface.get_socket::<SocketT>(socket_handle).shutdown(true);
+                errors.insert(socket_handle, SyscallError::new(syscall::ETIMEDOUT));
+            }
+
+            let unanswered: Vec<SocketHandle> = self
+                .syn_retries
+                .keys()
+                .cloned()
+                .filter(|&socket_handle| iface.device().get_ref().syns_exhausted(socket_handle))
+                .collect();
+            for socket_handle in unanswered {
+                trace!("socket connect out of SYN retries: {}", socket_handle);
+                self.syn_retries.remove(&socket_handle);
+                if let Some(flow) = self.socket_flow(&mut iface, socket_handle) {
+                    iface.device().get_ref().set_syn_retries(socket_handle, flow, None);
+                }
+                iface.get_socket::<SocketT>(socket_handle).shutdown(true);
+                errors.insert(socket_handle, SyscallError::new(syscall::ETIMEDOUT));
+            }
+            // Established or closed, the next connect gets all its SYNs again
+            for &socket_handle in self.syn_retries.keys() {
+                if !iface.get_socket::<SocketT>(socket_handle).is_connecting() {
+                    iface.device().get_ref().reset_syns(socket_handle);
+                }
+            }
+
+            for file in self.files.values() {
+                let socket_handle = file.socket_handle();
+                let socket = iface.get_socket::<SocketT>(socket_handle);
+                if let Some(err) = socket.take_error() {
+                    errors.insert(socket_handle, err);
+                }
+            }
+            if !errors.is_empty() {
+                for file in self.files.values_mut() {
+                    if let SchemeFile::Socket(ref mut file) = *file {
+                        if let Some(err) = errors.get(&file.socket_handle) {
+                            file.pending_error = Some(*err);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Collect readiness for the event files
+        {
+            let mut iface = self.iface.borrow_mut();
+            for (&event_fd, event_file) in &mut self.event_files {
+                for (&fd, &interest) in &event_file.interest {
+                    let ready = match self.files.get(&fd) {
+                        Some(&SchemeFile::Socket(ref file)) => {
+                            let socket = iface.get_socket::<SocketT>(file.socket_handle);
+                            readiness(socket, &file.data, file.recv_lowat, file.send_lowat)
+                                & interest
+                        }
+                        _ => 0,
+                    };
+                    let reported = event_file.reported.entry(fd).or_insert(0);
+                    let revents = ready & !*reported;
+                    *reported = ready;
+                    if revents != 0 {
+                        *event_file.ready.entry(fd).or_insert(0) |= revents;
+                    }
+                }
+
+                if event_file.events & EVENT_READ.bits() == EVENT_READ.bits()
+                    && !event_file.ready.is_empty()
+                {
+                    if !event_file.read_notified {
+                        event_file.read_notified = true;
+                        post_fevent(&mut self.scheme_file, event_fd, EVENT_READ.bits(), 1)?;
+                    }
+                } else {
+                    event_file.read_notified = false;
+                }
+            }
+        }
+
         // Notify non-blocking sockets
         for (&fd, ref mut file) in &mut self.files {
             let events = {
@@ -296,37 +928,369 @@ where
             }
         }
 
-        // Wake up blocking queue
-        let mut i = 0;
-        while i < self.wait_queue.len() {
-            let mut packet = self.wait_queue[i].packet;
-            if let Some(a) = self.handle(&packet) {
-                self.wait_queue.remove(i);
+        // Wake up blocking queue, failing the reads and writes of sockets
+        // while the link is down or once their socket is gone for good
+        let link_up = self.iface.borrow().device().get_ref().link_up();
+        for id in self.wait_queue.ids() {
+            // Handling an earlier packet may have dropped this one
+            let mut packet = match self.wait_queue.get(id) {
+                Some(handle) => handle.packet,
+                None => continue,
+            };
+            let result = if !link_up
+                && (packet.a == syscall::SYS_READ || packet.a == syscall::SYS_WRITE)
+                && self.files.contains_key(&packet.b)
+            {
+                Some((-syscall::ENETDOWN) as usize)
+            } else if let Some(a) = self.terminated(&packet) {
+                Some(a)
+            } else {
+                self.retry(&packet)
+            };
+            if let Some(a) = result {
+                self.wait_queue.remove(id);
                 packet.a = a;
                 self.scheme_file.write_all(&packet)?;
-            } else {
-                match self.wait_queue[i].until {
-                    Some(until)
-                        if (until.tv_sec < cur_time.tv_sec
-                            || (until.tv_sec == cur_time.tv_sec
-                                && until.tv_nsec < cur_time.tv_nsec)) =>
-                    {
-                        self.wait_queue.remove(i);
-                        packet.a = (-syscall::ETIMEDOUT) as usize;
-                        self.scheme_file.write_all(&packet)?;
+            }
+        }
+
+        // Whatever is still blocked past its timeout fails
+        for handle in self.wait_queue.expire(&cur_time) {
+            let mut packet = handle.packet;
+            packet.a = (-syscall::ETIMEDOUT) as usize;
+            self.scheme_file.write_all(&packet)?;
+        }
+
+        let next_deadline = self
+            .wait_queue
+            .next_deadline()
+            .into_iter()
+            .chain(self.idle.values().map(IdleTimer::deadline))
+            // Writes held back by an empty bucket get retried once it refills
+            .chain(
+                self.send_rate
+                    .values()
+                    .filter(|send_rate| !send_rate.can_send())
+                    .map(SendRate::next_refill),
+            )
+            .fold(None, |earliest: Option<TimeSpec>, until| match earliest {
+                Some(earliest) if !time_before(&until, &earliest) => Some(earliest),
+                _ => Some(until),
+            });
+
+        Ok(next_deadline)
+    }
+
+    /// Releases every socket and port once the scheme has gone away.
+    fn close_all(&mut self) {
+        let fds: Vec<usize> = self.files.keys().cloned().collect();
+        for fd in fds {
+            if let Err(err) = self.close(fd) {
+                error!("failed to close fd {} on shutdown: {}", fd, err);
+            }
+        }
+        let fds: Vec<usize> = self.lingering.keys().cloned().collect();
+        for fd in fds {
+            if let Some(lingering) = self.lingering.remove(&fd) {
+                if let Err(err) = self.release_file(lingering.file, true) {
+                    error!("failed to close fd {} on shutdown: {}", fd, err);
+                }
+            }
+        }
+        self.nulls.clear();
+        self.event_files.clear();
+        self.lists.clear();
+        self.wait_queue.clear();
+    }
+
+    fn list_sockets(&self) -> SocketList {
+        let mut iface = self.iface.borrow_mut();
+        let mut data = String::new();
+        for (fd, file) in &self.files {
+            if let SchemeFile::Socket(ref file) = *file {
+                let socket = iface.get_socket::<SocketT>(file.socket_handle);
+                data += &format!("{} {}\n", fd, socket.describe(file));
+            }
+        }
+        SocketList {
+            data: data.into_bytes(),
+            offset: 0,
+        }
+    }
+
+    fn update_interest(&mut self, fd: usize, buf: &[u8]) -> SyscallResult<usize> {
+        let mut count = 0;
+        for pair in buf.chunks(EventFile::PAIR_SIZE) {
+            if pair.len() < EventFile::PAIR_SIZE {
+                break;
+            }
+            let target = LittleEndian::read_u64(&pair[0..8]) as usize;
+            let events = LittleEndian::read_u64(&pair[8..16]) as usize;
+            match self.files.get(&target) {
+                Some(&SchemeFile::Socket(_)) => (),
+                _ => return Err(SyscallError::new(syscall::EBADF)),
+            }
+            let event_file = self
+                .event_files
+                .get_mut(&fd)
+                .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+            // A new subscription reports whatever already holds
+            event_file.forget(target);
+            let events = events & (EVENT_READ.bits() | EVENT_WRITE.bits());
+            if events != 0 {
+                event_file.interest.insert(target, events);
+            }
+            count += EventFile::PAIR_SIZE;
+        }
+        Ok(count)
+    }
+
+    /// Posts `EVENT_READ` to the subscribed setting fds of the same setting
+    /// and socket as `setting_fd`, which was just written successfully. Any
+    /// such write counts as a change, even one storing the value already
+    /// there, and the writer itself isn't notified. Settings kept per socket
+    /// fd, like the timeouts, are reported for every fd of the socket.
+    fn notify_setting_change(&mut self, setting_fd: usize) -> SyscallResult<()> {
+        let (socket_handle, setting) = match self.files.get(&setting_fd) {
+            Some(&SchemeFile::Setting(ref file)) => (file.socket_handle, file.setting),
+            _ => return Ok(()),
+        };
+        for &fd in &self.setting_watchers {
+            if fd == setting_fd {
+                continue;
+            }
+            if let Some(&SchemeFile::Setting(ref file)) = self.files.get(&fd) {
+                if file.socket_handle == socket_handle && file.setting == setting {
+                    post_fevent(&mut self.scheme_file, fd, EVENT_READ.bits(), 1)
+                        .map_err(|_| SyscallError::new(syscall::EIO))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes every fd of a socket see the given `events` again on the next
+    /// notification if they still hold. A socket that just got bound rearms
+    /// `EVENT_WRITE` so fevent-driven clients can pick up the local endpoint
+    /// with fpath, the event doesn't mean more buffer space then. A flushed
+    /// receive buffer rearms `EVENT_READ` for the data arriving afterwards.
+    fn rearm(&mut self, socket_handle: SocketHandle, events: usize) {
+        let mut fds = Vec::new();
+        for (&fd, file) in &mut self.files {
+            if let SchemeFile::Socket(ref mut file) = *file {
+                if file.socket_handle == socket_handle {
+                    if events & EVENT_READ.bits() == EVENT_READ.bits() {
+                        file.read_notified = false;
                     }
-                    _ => {
-                        i += 1;
+                    if events & EVENT_WRITE.bits() == EVENT_WRITE.bits() {
+                        file.write_notified = false;
                     }
+                    fds.push(fd);
+                }
+            }
+        }
+        for event_file in self.event_files.values_mut() {
+            for fd in &fds {
+                if let Some(reported) = event_file.reported.get_mut(fd) {
+                    *reported &= !events;
+                }
+            }
+        }
+    }
+
+    /// Handles a blocked packet again, unless it's a write on a socket that has
+    /// no room for it yet and no error to report either.
+    fn retry(&mut self, packet: &SyscallPacket) -> Option<usize> {
+        if packet.a == syscall::SYS_WRITE {
+            if let Some(&SchemeFile::Socket(ref file)) = self.files.get(&packet.b) {
+                let mut iface = self.iface.borrow_mut();
+                if file.pending_error.is_none()
+                    && !iface.get_socket::<SocketT>(file.socket_handle).can_send()
+                {
+                    return None;
                 }
             }
         }
+        self.handle(packet)
+    }
+
+    /// Result forced on a blocked read or write whose socket terminated:
+    /// `ECONNRESET` for reads and `EPIPE` for writes. Sockets with a latched
+    /// error are left to the retry, which reports that error instead.
+    fn terminated(&mut self, packet: &SyscallPacket) -> Option<usize> {
+        if packet.a != syscall::SYS_READ && packet.a != syscall::SYS_WRITE {
+            return None;
+        }
+        if let Some(&SchemeFile::Socket(ref file)) = self.files.get(&packet.b) {
+            let mut iface = self.iface.borrow_mut();
+            if file.pending_error.is_none()
+                && iface.get_socket::<SocketT>(file.socket_handle).is_terminated()
+            {
+                return Some(if packet.a == syscall::SYS_READ {
+                    (-syscall::ECONNRESET) as usize
+                } else {
+                    (-syscall::EPIPE) as usize
+                });
+            }
+        }
+        None
+    }
+
+    fn acquire_ref(&mut self, socket_handle: SocketHandle) {
+        *self.refs.entry(socket_handle).or_insert(0) += 1;
+    }
+
+    /// Drops one reference to the socket, true if it was the last one.
+    fn release_ref(&mut self, socket_handle: SocketHandle) -> bool {
+        let count = match self.refs.get_mut(&socket_handle) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => 0,
+        };
+        if count == 0 {
+            self.refs.remove(&socket_handle);
+        }
+        count == 0
+    }
+
+    /// Flow of a socket as seen through the first of its fds still open.
+    fn socket_flow(
+        &self,
+        iface: &mut SmolnetInterface,
+        socket_handle: SocketHandle,
+    ) -> Option<Flow> {
+        let data = self
+            .files
+            .values()
+            .filter_map(|file| match *file {
+                SchemeFile::Socket(ref file) if file.socket_handle == socket_handle => {
+                    Some(&file.data)
+                }
+                _ => None,
+            })
+            .next()?;
+        iface.get_socket::<SocketT>(socket_handle).ip_flow(data)
+    }
+
+    /// Sets up the device hooks of the settings of `socket_handle` for `flow`.
+    fn apply_hooks(&self, device: &NetworkDevice, socket_handle: SocketHandle, flow: Flow) {
+        if let Some(&tos) = self.tos.get(&socket_handle) {
+            device.set_tos(socket_handle, flow, Some(tos));
+        }
+        if let Some(&mss) = self.mss.get(&socket_handle) {
+            device.set_mss(socket_handle, flow, Some(mss));
+        }
+        if let Some(&retries) = self.syn_retries.get(&socket_handle) {
+            device.set_syn_retries(socket_handle, flow, Some(retries));
+        }
+        if !dont_frag(&self.dont_frag, &self.no_pmtud, socket_handle) {
+            device.set_dont_frag(socket_handle, flow, false);
+        }
+        if self.no_checksum.contains(&socket_handle) {
+            device.set_udp_checksum(socket_handle, flow, false);
+        }
+        if self.oob.contains(&socket_handle) {
+            device.watch_urgent(socket_handle, flow, true);
+        }
+        if self.timestamping.contains(&socket_handle) {
+            device.watch_rx_stamps(socket_handle, flow, true);
+        }
+    }
+
+    /// Carries the settings hooking into the device over to the socket that
+    /// replaces another one on its fd, such as the next listener after an
+    /// accept, so that they keep applying to the connections to come. An
+    /// accepted connection keeps its own hooks, narrowed down to its peer,
+    /// while a connection replaced by a reconnect loses them along with its
+    /// port, spent SYN limit included.
+    fn hand_over_hooks(&mut self, old_handle: SocketHandle, socket_handle: SocketHandle) {
+        if let Some(&tos) = self.tos.get(&old_handle) {
+            self.tos.insert(socket_handle, tos);
+        }
+        if let Some(&mss) = self.mss.get(&old_handle) {
+            self.mss.insert(socket_handle, mss);
+        }
+        if let Some(&retries) = self.syn_retries.get(&old_handle) {
+            self.syn_retries.insert(socket_handle, retries);
+        }
+        if self.no_pmtud.contains(&old_handle) {
+            self.no_pmtud.insert(socket_handle);
+        }
+        if let Some(&dont_frag) = self.dont_frag.get(&old_handle) {
+            self.dont_frag.insert(socket_handle, dont_frag);
+        }
+        if self.no_checksum.contains(&old_handle) {
+            self.no_checksum.insert(socket_handle);
+        }
+        if self.oob.contains(&old_handle) {
+            self.oob.insert(socket_handle);
+        }
+        if self.timestamping.contains(&old_handle) {
+            self.timestamping.insert(socket_handle);
+        }
+
+        let mut iface = self.iface.borrow_mut();
+        match self.socket_flow(&mut iface, old_handle) {
+            Some(flow) if flow.local.port != 0 => {
+                iface.device().get_ref().move_hooks(old_handle, flow);
+            }
+            _ => {
+                self.syn_retries.remove(&old_handle);
+                iface.device().get_ref().remove_hooks(old_handle);
+            }
+        }
+        if let Some(flow) = self.socket_flow(&mut iface, socket_handle) {
+            self.apply_hooks(iface.device().get_ref(), socket_handle, flow);
+        }
+    }
+
+    /// Drops the settings of a removed socket that hook into the device,
+    /// along with the hooks themselves.
+    fn forget_hooks(&mut self, socket_handle: SocketHandle) {
+        let mut iface = self.iface.borrow_mut();
+        self.tos.remove(&socket_handle);
+        self.mss.remove(&socket_handle);
+        self.no_pmtud.remove(&socket_handle);
+        self.dont_frag.remove(&socket_handle);
+        self.no_checksum.remove(&socket_handle);
+        self.syn_retries.remove(&socket_handle);
+        iface.device().get_ref().remove_hooks(socket_handle);
+        self.oob.remove(&socket_handle);
+        self.timestamping.remove(&socket_handle);
+    }
 
+    /// Drops a closed fd, removing its socket along with it when `last_ref` is set.
+    fn release_file(&mut self, file: SchemeFile<SocketT>, last_ref: bool) -> SyscallResult<()> {
+        let socket_handle = file.socket_handle();
+        self.iface
+            .borrow_mut()
+            .get_socket::<SocketT>(socket_handle)
+            .close_file(&file, &mut self.scheme_data)?;
+
+        if last_ref {
+            self.forget_hooks(socket_handle);
+            self.iface.borrow_mut().remove_socket(socket_handle);
+            self.stats.remove(&socket_handle);
+            self.idle.remove(&socket_handle);
+            self.send_rate.remove(&socket_handle);
+            self.tx_queue.remove(&socket_handle);
+            self.owners.remove(&socket_handle);
+            self.dont_route.remove(&socket_handle);
+            self.bound_device.remove(&socket_handle);
+        }
         Ok(())
     }
 
     fn handle_block(&mut self, packet: &mut SyscallPacket) -> SyscallResult<Option<TimeSpec>> {
         let fd = packet.b;
+        if let Some(lingering) = self.lingering.get(&fd) {
+            return Ok(Some(lingering.until));
+        }
+        if self.event_files.contains_key(&fd) {
+            return Ok(None);
+        }
         let (read_timeout, write_timeout) = {
             let file = self
                 .files
@@ -341,8 +1305,13 @@ where
         }?;
 
         let mut timeout = match packet.a {
-            syscall::SYS_WRITE => Ok(write_timeout),
-            syscall::SYS_READ => Ok(read_timeout),
+            syscall::SYS_WRITE | syscall::SYS_FSYNC => Ok(write_timeout),
+            // Like SO_SNDTIMEO, the write timeout bounds a blocking connect
+            syscall::SYS_DUP if dup_path(packet).split(|&b| b == b'?').next() == Some(&b"connect"[..]) => {
+                Ok(write_timeout)
+            }
+            // Like SO_RCVTIMEO, the read timeout also bounds a blocking accept
+            syscall::SYS_READ | syscall::SYS_DUP => Ok(read_timeout),
             _ => Ok(None),
         }?;
 
@@ -373,38 +1342,299 @@ where
         };
 
         match setting {
-            Setting::Other(setting) => SocketT::get_setting(file, setting, buf),
+            Setting::Other(setting) => {
+                let mut iface = self.iface.borrow_mut();
+                let socket = iface.get_socket::<SocketT>(file.socket_handle);
+                SocketT::get_setting(socket, file, setting, buf)
+            }
             Setting::Ttl => {
-                if let Some(hop_limit) = buf.get_mut(0) {
-                    let mut iface = self.iface.borrow_mut();
-                    let socket = iface.get_socket::<SocketT>(file.socket_handle);
-                    *hop_limit = socket.hop_limit();
+                if buf.is_empty() {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                let mut iface = self.iface.borrow_mut();
+                let socket = iface.get_socket::<SocketT>(file.socket_handle);
+                // Nothing to read while the interface default applies
+                match socket.hop_limit() {
+                    Some(hop_limit) => {
+                        buf[0] = hop_limit;
+                        Ok(1)
+                    }
+                    None => Ok(0),
+                }
+            }
+            Setting::Tos => {
+                if let Some(tos) = buf.get_mut(0) {
+                    *tos = self.tos.get(&file.socket_handle).cloned().unwrap_or(0);
                     Ok(1)
                 } else {
                     Err(SyscallError::new(syscall::EIO))
                 }
             }
-            Setting::ReadTimeout | Setting::WriteTimeout => {
-                let timespec = match (setting, file.read_timeout, file.write_timeout) {
-                    (Setting::ReadTimeout, Some(read_timeout), _) => read_timeout,
-                    (Setting::WriteTimeout, _, Some(write_timeout)) => write_timeout,
-                    _ => {
+            Setting::Owner => {
+                if buf.len() < 8 {
+                    return Ok(0);
+                }
+                let (uid, gid) = self.owners.get(&file.socket_handle).cloned().unwrap_or((0, 0));
+                LittleEndian::write_u32(&mut buf[0..4], uid);
+                LittleEndian::write_u32(&mut buf[4..8], gid);
+                Ok(8)
+            }
+            Setting::Stats => {
+                if buf.len() < SocketStats::SIZE {
+                    return Ok(0);
+                }
+                let stats = self
+                    .stats
+                    .get(&file.socket_handle)
+                    .cloned()
+                    .unwrap_or_default();
+                LittleEndian::write_u64(&mut buf[0..8], stats.bytes_sent);
+                LittleEndian::write_u64(&mut buf[8..16], stats.bytes_recv);
+                LittleEndian::write_u64(&mut buf[16..24], stats.packets_sent);
+                LittleEndian::write_u64(&mut buf[24..32], stats.packets_recv);
+                Ok(SocketStats::SIZE)
+            }
+            Setting::MaxSegmentSize => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                let mut iface = self.iface.borrow_mut();
+                match iface.get_socket::<SocketT>(file.socket_handle).ip_flow(&file.data) {
+                    Some(ref flow) if flow.protocol == IpProtocol::Tcp => (),
+                    _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                }
+                let mut mss = iface.device().get_ref().mtu() - ETHERNET_HEADER_LEN
+                    - IPV4_HEADER_LEN - TCP_HEADER_LEN;
+                if let Some(&clamp) = self.mss.get(&file.socket_handle) {
+                    mss = cmp::min(mss, usize::from(clamp));
+                }
+                LittleEndian::write_u32(&mut buf[0..4], mss as u32);
+                Ok(4)
+            }
+            Setting::InQ | Setting::OutQ => {
+                if buf.len() < 8 {
+                    return Ok(0);
+                }
+                let mut iface = self.iface.borrow_mut();
+                let socket = iface.get_socket::<SocketT>(file.socket_handle);
+                let count = match setting {
+                    Setting::InQ => socket.recv_available(),
+                    _ => socket.send_queued(),
+                }
+                .ok_or_else(|| SyscallError::new(syscall::EOPNOTSUPP))?;
+                LittleEndian::write_u64(&mut buf[0..8], count as u64);
+                Ok(8)
+            }
+            Setting::IdleTimeout => match self.idle.get(&file.socket_handle) {
+                Some(timer) => read_timespec(&timer.timeout, buf),
+                None => Ok(0),
+            },
+            Setting::Linger => match self.linger.get(&file.socket_handle) {
+                Some(linger) => read_timespec(linger, buf),
+                None => Ok(0),
+            },
+            Setting::FlushRecv => {
+                if buf.len() < 8 {
+                    return Ok(0);
+                }
+                let socket_handle = file.socket_handle;
+                let count = {
+                    let mut iface = self.iface.borrow_mut();
+                    iface.get_socket::<SocketT>(socket_handle).flush_recv()
+                };
+                LittleEndian::write_u64(&mut buf[0..8], count as u64);
+                self.rearm(socket_handle, EVENT_READ.bits());
+                Ok(8)
+            }
+            Setting::RecvLowat => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                LittleEndian::write_u32(&mut buf[0..4], file.recv_lowat as u32);
+                Ok(4)
+            }
+            Setting::SendLowat => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                LittleEndian::write_u32(&mut buf[0..4], file.send_lowat as u32);
+                Ok(4)
+            }
+            Setting::NoChecksum => {
+                if let Some(flag) = buf.get_mut(0) {
+                    *flag = self.no_checksum.contains(&file.socket_handle) as u8;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::NoPmtud | Setting::DontFragment => {
+                if let Some(flag) = buf.get_mut(0) {
+                    *flag = match setting {
+                        Setting::DontFragment => {
+                            dont_frag(&self.dont_frag, &self.no_pmtud, file.socket_handle)
+                        }
+                        _ => self.no_pmtud.contains(&file.socket_handle),
+                    } as u8;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::DontRoute => {
+                if let Some(flag) = buf.get_mut(0) {
+                    *flag = self.dont_route.contains(&file.socket_handle) as u8;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::BindToDevice => match self.bound_device.get(&file.socket_handle) {
+                Some(name) => write_path(name, buf),
+                None => Ok(0),
+            },
+            Setting::Cancel => {
+                if buf.len() < 8 {
+                    return Ok(0);
+                }
+                let waiting = self
+                    .wait_queue
+                    .ids()
+                    .into_iter()
+                    .filter(|&id| {
+                        self.wait_queue
+                            .get(id)
+                            .map_or(false, |handle| handle.packet.b == fd)
+                    })
+                    .count();
+                LittleEndian::write_u64(&mut buf[0..8], waiting as u64);
+                Ok(8)
+            }
+            Setting::Oob => {
+                if buf.is_empty() {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                let mut iface = self.iface.borrow_mut();
+                match iface.get_socket::<SocketT>(file.socket_handle).ip_flow(&file.data) {
+                    Some(ref flow) if flow.protocol == IpProtocol::Tcp => (),
+                    _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                }
+                buf[0] = iface.device().get_ref().take_urgent(file.socket_handle) as u8;
+                Ok(1)
+            }
+            Setting::Timestamping => {
+                if let Some(flag) = buf.get_mut(0) {
+                    *flag = self.timestamping.contains(&file.socket_handle) as u8;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::SynRetries => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                // Nothing to read while smoltcp retransmits without a limit
+                match self.syn_retries.get(&file.socket_handle) {
+                    Some(&retries) => {
+                        LittleEndian::write_u32(&mut buf[0..4], retries);
+                        Ok(4)
+                    }
+                    None => Ok(0),
+                }
+            }
+            Setting::SendRate => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                let rate = self
+                    .send_rate
+                    .get(&file.socket_handle)
+                    .map_or(0, |send_rate| send_rate.rate);
+                LittleEndian::write_u32(&mut buf[0..4], rate);
+                Ok(4)
+            }
+            Setting::TxQueue => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                let queue = self.tx_queue.get(&file.socket_handle).cloned().unwrap_or(0);
+                LittleEndian::write_u32(&mut buf[0..4], queue);
+                Ok(4)
+            }
+            Setting::Abort => Ok(0),
+            Setting::Options => self.read_options(fd, buf),
+            Setting::ReadTimeout | Setting::WriteTimeout => {
+                let timespec = match (setting, file.read_timeout, file.write_timeout) {
+                    (Setting::ReadTimeout, Some(read_timeout), _) => read_timeout,
+                    (Setting::WriteTimeout, _, Some(write_timeout)) => write_timeout,
+                    _ => {
                         return Ok(0);
                     }
                 };
 
-                if buf.len() < mem::size_of::<TimeSpec>() {
-                    Ok(0)
-                } else {
-                    let count = timespec.deref().read(buf).map_err(|err| {
-                        SyscallError::new(err.raw_os_error().unwrap_or(syscall::EIO))
-                    })?;
-                    Ok(count)
-                }
+                read_timespec(&timespec, buf)
             }
         }
     }
 
+    /// Serializes the settings of the socket behind `fd` as a version byte,
+    /// currently 1, followed by one record per setting: the length of its
+    /// name as a u8, the name, the length of its value as a little-endian
+    /// u16 and the value as the setting's own file would read it. Settings
+    /// that don't apply to the socket are left out.
+    fn read_options(&mut self, fd: usize, buf: &mut [u8]) -> SyscallResult<usize> {
+        const VERSION: u8 = 1;
+
+        let generic = [
+            ("hop_limit", Setting::Ttl),
+            ("read_timeout", Setting::ReadTimeout),
+            ("write_timeout", Setting::WriteTimeout),
+            ("tos", Setting::Tos),
+            ("linger", Setting::Linger),
+            ("idle_timeout", Setting::IdleTimeout),
+            ("mss", Setting::MaxSegmentSize),
+            ("rcvlowat", Setting::RecvLowat),
+            ("sndlowat", Setting::SendLowat),
+            ("nochecksum", Setting::NoChecksum),
+            ("no_pmtud", Setting::NoPmtud),
+            ("df", Setting::DontFragment),
+            ("dontroute", Setting::DontRoute),
+            ("bindtodevice", Setting::BindToDevice),
+            ("syn_retries", Setting::SynRetries),
+            ("sndrate", Setting::SendRate),
+            ("timestamping", Setting::Timestamping),
+            ("txqueue", Setting::TxQueue),
+        ];
+        let mut settings = generic.to_vec();
+        for &name in SocketT::setting_names() {
+            if let Some(setting) = SocketT::parse_setting(name) {
+                settings.push((name, Setting::Other(setting)));
+            }
+        }
+
+        let mut options = vec![VERSION];
+        let mut value = [0; 256];
+        for (name, setting) in settings {
+            let len = match self.get_setting(fd, setting, &mut value) {
+                Ok(len) => len,
+                Err(_) => continue,
+            };
+            options.push(name.len() as u8);
+            options.extend_from_slice(name.as_bytes());
+            let mut value_len = [0; 2];
+            LittleEndian::write_u16(&mut value_len, len as u16);
+            options.extend_from_slice(&value_len);
+            options.extend_from_slice(&value[..len]);
+        }
+
+        if buf.len() < options.len() {
+            return Err(SyscallError::new(syscall::ERANGE));
+        }
+        buf[..options.len()].copy_from_slice(&options);
+        Ok(options.len())
+    }
+
     fn update_setting(
         &mut self,
         fd: usize,
@@ -423,17 +1653,7 @@ where
         };
         match setting {
             Setting::ReadTimeout | Setting::WriteTimeout => {
-                let (timeout, count) = {
-                    if buf.len() < mem::size_of::<TimeSpec>() {
-                        (None, 0)
-                    } else {
-                        let mut timespec = TimeSpec::default();
-                        let count = timespec.deref_mut().write(buf).map_err(|err| {
-                            SyscallError::new(err.raw_os_error().unwrap_or(syscall::EIO))
-                        })?;
-                        (Some(timespec), count)
-                    }
-                };
+                let (timeout, count) = parse_timespec(buf)?;
                 match setting {
                     Setting::ReadTimeout => {
                         file.read_timeout = timeout;
@@ -449,13 +1669,282 @@ where
                 if let Some(hop_limit) = buf.get(0) {
                     let mut iface = self.iface.borrow_mut();
                     let mut socket = iface.get_socket::<SocketT>(file.socket_handle);
-                    socket.set_hop_limit(*hop_limit);
+                    // Zero goes back to the interface default
+                    socket.set_hop_limit(if *hop_limit == 0 {
+                        None
+                    } else {
+                        Some(*hop_limit)
+                    });
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::Tos => {
+                if let Some(&tos) = buf.get(0) {
+                    let mut iface = self.iface.borrow_mut();
+                    let flow = iface
+                        .get_socket::<SocketT>(file.socket_handle)
+                        .ip_flow(&file.data)
+                        .ok_or_else(|| SyscallError::new(syscall::EOPNOTSUPP))?;
+                    let device = iface.device().get_ref();
+                    if tos == 0 {
+                        device.set_tos(file.socket_handle, flow, None);
+                        self.tos.remove(&file.socket_handle);
+                    } else {
+                        device.set_tos(file.socket_handle, flow, Some(tos));
+                        self.tos.insert(file.socket_handle, tos);
+                    }
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::IdleTimeout => {
+                let (timeout, count) = parse_timespec(buf)?;
+                match timeout {
+                    Some(timeout) if timeout.tv_sec < 0 || timeout.tv_nsec < 0 => {
+                        return Err(SyscallError::new(syscall::EINVAL));
+                    }
+                    Some(timeout) if timeout.tv_sec > 0 || timeout.tv_nsec > 0 => {
+                        let mut timer = IdleTimer {
+                            timeout,
+                            last_activity: TimeSpec::default(),
+                        };
+                        timer.touch();
+                        self.idle.insert(file.socket_handle, timer);
+                    }
+                    _ => {
+                        self.idle.remove(&file.socket_handle);
+                    }
+                }
+                Ok(count)
+            }
+            Setting::Linger => {
+                let (linger, count) = parse_timespec(buf)?;
+                match linger {
+                    Some(linger) if linger.tv_sec < 0 || linger.tv_nsec < 0 => {
+                        return Err(SyscallError::new(syscall::EINVAL));
+                    }
+                    Some(linger) => {
+                        self.linger.insert(file.socket_handle, linger);
+                    }
+                    None => {
+                        self.linger.remove(&file.socket_handle);
+                    }
+                }
+                Ok(count)
+            }
+            Setting::MaxSegmentSize => {
+                if buf.len() < 4 {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                let mss = LittleEndian::read_u32(&buf[0..4]);
+                if mss > u32::from(::std::u16::MAX) {
+                    return Err(SyscallError::new(syscall::EINVAL));
+                }
+                let mut iface = self.iface.borrow_mut();
+                let flow = match iface
+                    .get_socket::<SocketT>(file.socket_handle)
+                    .ip_flow(&file.data)
+                {
+                    Some(flow) if flow.protocol == IpProtocol::Tcp => flow,
+                    _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                };
+                let device = iface.device().get_ref();
+                if mss == 0 {
+                    device.set_mss(file.socket_handle, flow, None);
+                    self.mss.remove(&file.socket_handle);
+                } else {
+                    device.set_mss(file.socket_handle, flow, Some(mss as u16));
+                    self.mss.insert(file.socket_handle, mss as u16);
+                }
+                Ok(4)
+            }
+            Setting::RecvLowat => {
+                if buf.len() < 4 {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                file.recv_lowat = LittleEndian::read_u32(&buf[0..4]) as usize;
+                Ok(4)
+            }
+            Setting::SendLowat => {
+                if buf.len() < 4 {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                file.send_lowat = LittleEndian::read_u32(&buf[0..4]) as usize;
+                Ok(4)
+            }
+            Setting::NoChecksum => {
+                if let Some(&flag) = buf.get(0) {
+                    let mut iface = self.iface.borrow_mut();
+                    // Endpoints are always IPv4, which is the only version
+                    // allowing datagrams without a checksum
+                    let flow = match iface
+                        .get_socket::<SocketT>(file.socket_handle)
+                        .ip_flow(&file.data)
+                    {
+                        Some(flow) if flow.protocol == IpProtocol::Udp => flow,
+                        _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                    };
+                    let device = iface.device().get_ref();
+                    if flag == 0 {
+                        device.set_udp_checksum(file.socket_handle, flow, true);
+                        self.no_checksum.remove(&file.socket_handle);
+                    } else {
+                        device.set_udp_checksum(file.socket_handle, flow, false);
+                        self.no_checksum.insert(file.socket_handle);
+                    }
                     Ok(1)
                 } else {
                     Err(SyscallError::new(syscall::EIO))
                 }
             }
-            Setting::Other(setting) => SocketT::set_setting(file, setting, buf),
+            Setting::Timestamping => {
+                if let Some(&flag) = buf.get(0) {
+                    let mut iface = self.iface.borrow_mut();
+                    let flow = iface
+                        .get_socket::<SocketT>(file.socket_handle)
+                        .ip_flow(&file.data)
+                        .ok_or_else(|| SyscallError::new(syscall::EOPNOTSUPP))?;
+                    let device = iface.device().get_ref();
+                    if flag == 0 {
+                        if self.timestamping.remove(&file.socket_handle) {
+                            device.watch_rx_stamps(file.socket_handle, flow, false);
+                        }
+                    } else if self.timestamping.insert(file.socket_handle) {
+                        device.watch_rx_stamps(file.socket_handle, flow, true);
+                    }
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::NoPmtud | Setting::DontFragment => {
+                if let Some(&flag) = buf.get(0) {
+                    let mut iface = self.iface.borrow_mut();
+                    let flow = iface
+                        .get_socket::<SocketT>(file.socket_handle)
+                        .ip_flow(&file.data)
+                        .ok_or_else(|| SyscallError::new(syscall::EOPNOTSUPP))?;
+                    match setting {
+                        Setting::DontFragment => {
+                            self.dont_frag.insert(file.socket_handle, flag != 0);
+                        }
+                        _ if flag == 0 => {
+                            self.no_pmtud.remove(&file.socket_handle);
+                        }
+                        _ => {
+                            self.no_pmtud.insert(file.socket_handle);
+                        }
+                    }
+                    let dont_frag = dont_frag(&self.dont_frag, &self.no_pmtud, file.socket_handle);
+                    iface
+                        .device()
+                        .get_ref()
+                        .set_dont_frag(file.socket_handle, flow, dont_frag);
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::BindToDevice => {
+                let name = str::from_utf8(buf).map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                bind_to_device(&mut self.bound_device, file.socket_handle, name.trim())?;
+                Ok(buf.len())
+            }
+            Setting::DontRoute => {
+                if let Some(&flag) = buf.get(0) {
+                    if flag == 0 {
+                        self.dont_route.remove(&file.socket_handle);
+                    } else {
+                        self.dont_route.insert(file.socket_handle);
+                    }
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            Setting::SynRetries => {
+                let mut iface = self.iface.borrow_mut();
+                let flow = match iface
+                    .get_socket::<SocketT>(file.socket_handle)
+                    .ip_flow(&file.data)
+                {
+                    Some(flow) if flow.protocol == IpProtocol::Tcp => flow,
+                    _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                };
+                let device = iface.device().get_ref();
+                // A short write goes back to retransmitting without a limit
+                if buf.len() < 4 {
+                    device.set_syn_retries(file.socket_handle, flow, None);
+                    self.syn_retries.remove(&file.socket_handle);
+                    return Ok(0);
+                }
+                let retries = LittleEndian::read_u32(&buf[0..4]);
+                device.set_syn_retries(file.socket_handle, flow, Some(retries));
+                self.syn_retries.insert(file.socket_handle, retries);
+                Ok(4)
+            }
+            Setting::SendRate => {
+                if buf.len() < 4 {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                match LittleEndian::read_u32(&buf[0..4]) {
+                    0 => {
+                        self.send_rate.remove(&file.socket_handle);
+                    }
+                    rate => {
+                        let mut cur_time = TimeSpec::default();
+                        syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut cur_time)?;
+                        self.send_rate
+                            .insert(file.socket_handle, SendRate::new(rate, cur_time));
+                    }
+                }
+                Ok(4)
+            }
+            Setting::TxQueue => {
+                if buf.len() < 4 {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                match LittleEndian::read_u32(&buf[0..4]) {
+                    0 => {
+                        self.tx_queue.remove(&file.socket_handle);
+                    }
+                    queue if queue < TX_QUEUES => {
+                        self.tx_queue.insert(file.socket_handle, queue);
+                    }
+                    _ => return Err(SyscallError::new(syscall::EINVAL)),
+                }
+                Ok(4)
+            }
+            Setting::Stats
+            | Setting::Owner
+            | Setting::InQ
+            | Setting::OutQ
+            | Setting::Abort
+            | Setting::Options
+            | Setting::FlushRecv => {
+                Err(SyscallError::new(syscall::EINVAL))
+            }
+            // There's no way to have smoltcp set the urgent pointer
+            Setting::Oob => Err(SyscallError::new(syscall::EOPNOTSUPP)),
+            Setting::Cancel => {
+                for handle in self.wait_queue.take_fd(fd) {
+                    trace!("socket cancel blocked op: {}", fd);
+                    let mut packet = handle.packet;
+                    packet.a = (-syscall::EINTR) as usize;
+                    self.scheme_file
+                        .write_all(&packet)
+                        .map_err(|_| SyscallError::new(syscall::EIO))?;
+                }
+                Ok(buf.len())
+            }
+            Setting::Other(setting) => {
+                let mut iface = self.iface.borrow_mut();
+                let socket = iface.get_socket::<SocketT>(file.socket_handle);
+                SocketT::set_setting(socket, file, setting, buf)
+            }
         }
     }
 }
@@ -469,13 +1958,14 @@ where
         path: &str,
         flags: usize,
         uid: u32,
-        _gid: u32,
+        gid: u32,
     ) -> SyscallResult<Option<usize>> {
         if path.is_empty() {
             let null = NullFile {
                 flags: flags,
+                fd_flags: 0,
                 uid: uid,
-                gid: _gid,
+                gid: gid,
             };
 
             let id = self.next_fd;
@@ -485,6 +1975,7 @@ where
 
             Ok(Some(id))
         } else {
+            check_files_limit(self.files.len())?;
             let (socket_handle, data) = SocketT::new_socket(
                 &mut self.iface.borrow_mut(),
                 path,
@@ -494,12 +1985,16 @@ where
 
             let file = SchemeFile::Socket(SocketFile {
                 flags,
+                fd_flags: 0,
+                recv_lowat: 0,
+                send_lowat: 0,
                 events: 0,
                 socket_handle,
                 read_notified: false,
                 write_notified: false,
                 write_timeout: None,
                 read_timeout: None,
+                pending_error: None,
                 data,
             });
 
@@ -507,6 +2002,8 @@ where
             self.next_fd += 1;
 
             self.files.insert(id, file);
+            self.acquire_ref(socket_handle);
+            self.owners.insert(socket_handle, (uid, gid));
 
             Ok(Some(id))
         }
@@ -517,21 +2014,45 @@ where
             return Ok(Some(0));
         }
 
-        let socket_handle = {
-            let file = self
-                .files
-                .get(&fd)
-                .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
-            file.socket_handle()
-        };
+        if let Some(_list) = self.lists.remove(&fd) {
+            return Ok(Some(0));
+        }
+
+        if let Some(_event_file) = self.event_files.remove(&fd) {
+            self.wait_queue.retain(|handle| handle.packet.b != fd);
+            return Ok(Some(0));
+        }
+
+        if let Some(until) = self.lingering.get(&fd).map(|lingering| lingering.until) {
+            let mut cur_time = TimeSpec::default();
+            syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut cur_time)?;
+            let flushed = {
+                let mut iface = self.iface.borrow_mut();
+                let socket_handle = self.lingering[&fd].file.socket_handle();
+                let socket = iface.get_socket::<SocketT>(socket_handle);
+                socket.is_flushed()
+            };
+            if !flushed && time_before(&cur_time, &until) {
+                return Ok(None);
+            }
+            trace!("socket linger done: {}", fd);
+            if let Some(lingering) = self.lingering.remove(&fd) {
+                self.release_file(lingering.file, true)?;
+            }
+            return Ok(Some(0));
+        }
+
+        let scheme_file = self
+            .files
+            .remove(&fd)
+            .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+        let socket_handle = scheme_file.socket_handle();
         trace!("socket close: {}", socket_handle);
 
-        let scheme_file = self.files.remove(&fd);
-        let mut iface = self.iface.borrow_mut();
-        if let Some(scheme_file) = scheme_file {
-            let socket = iface.get_socket::<SocketT>(socket_handle);
-            socket.close_file(&scheme_file, &mut self.scheme_data)?;
+        for event_file in self.event_files.values_mut() {
+            event_file.forget(fd);
         }
+        self.setting_watchers.remove(&fd);
 
         self.wait_queue.retain(
             |&WaitHandle {
@@ -541,20 +2062,45 @@ where
         );
 
         trace!("removing...");
-        let mut no_refs_for_socket = true;
-        for (searched_fd, file) in &self.files {
-            if *searched_fd != fd && file.socket_handle() == socket_handle {
-                no_refs_for_socket = false;
-            }
-        }
+        let no_refs_for_socket = self.release_ref(socket_handle);
+
         if no_refs_for_socket {
-            iface.remove_socket(socket_handle);
+            if let Some(linger) = self.linger.remove(&socket_handle) {
+                let abort = linger.tv_sec == 0 && linger.tv_nsec == 0;
+                let flushed = {
+                    let mut iface = self.iface.borrow_mut();
+                    let socket = iface.get_socket::<SocketT>(socket_handle);
+                    socket.shutdown(abort);
+                    socket.is_flushed()
+                };
+                // An abort still needs one more poll to put the reset on the wire
+                if abort || !flushed {
+                    let mut cur_time = TimeSpec::default();
+                    syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut cur_time)?;
+                    trace!("socket linger: {}", socket_handle);
+                    self.lingering.insert(
+                        fd,
+                        Lingering {
+                            file: scheme_file,
+                            until: add_time(&linger, &cur_time),
+                        },
+                    );
+                    return Ok(None);
+                }
+            }
         }
+
+        self.release_file(scheme_file, no_refs_for_socket)?;
         //TODO: removing sockets in release should make prune unnecessary
         Ok(Some(0))
     }
 
     fn write(&mut self, fd: usize, buf: &[u8]) -> SyscallResult<Option<usize>> {
+        if self.event_files.contains_key(&fd) {
+            return self.update_interest(fd, buf).map(Some);
+        }
+
+        let setting_fd = fd;
         let (fd, setting) = {
             let file = self
                 .files
@@ -566,16 +2112,79 @@ where
                     (setting_handle.fd, setting_handle.setting)
                 }
                 SchemeFile::Socket(ref mut file) => {
-                    let mut iface = self.iface.borrow_mut();
-                    let mut socket = iface.get_socket::<SocketT>(file.socket_handle);
-                    return SocketT::write_buf(&mut socket, file, buf);
+                    if let Some(err) = file.pending_error.take() {
+                        return Err(err);
+                    }
+                    let socket_handle = file.socket_handle;
+                    if let Some(send_rate) = self.send_rate.get_mut(&socket_handle) {
+                        let mut cur_time = TimeSpec::default();
+                        syscall::clock_gettime(syscall::CLOCK_MONOTONIC, &mut cur_time)?;
+                        send_rate.refill(&cur_time);
+                        if !send_rate.can_send() {
+                            return if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+                                Err(SyscallError::new(syscall::EAGAIN))
+                            } else {
+                                Ok(None) // internally scheduled to re-write
+                            };
+                        }
+                    }
+                    let (result, bound) = {
+                        let mut iface = self.iface.borrow_mut();
+                        if !iface.device().get_ref().link_up() {
+                            return Err(SyscallError::new(syscall::ENETDOWN));
+                        }
+                        // A dontroute socket only reaches its link. There's a
+                        // single interface, so a pinned socket already goes
+                        // out through it, only a destination it has neither
+                        // an address nor a route for is refused.
+                        let dont_route = self.dont_route.contains(&socket_handle);
+                        if dont_route || self.bound_device.contains_key(&socket_handle) {
+                            let remote_addr =
+                                iface.get_socket::<SocketT>(socket_handle).remote_addr(&file.data);
+                            if let Some(addr) = remote_addr {
+                                check_egress(&mut iface, addr, !dont_route)?;
+                            }
+                        }
+                        let mtu = iface.device().get_ref().mtu();
+                        let mut socket = iface.get_socket::<SocketT>(socket_handle);
+                        let was_bound = socket.is_bound();
+                        let result = SocketT::write_buf(&mut socket, file, mtu, buf);
+                        (result, !was_bound && socket.is_bound())
+                    };
+                    if let Ok(Some(count)) = result {
+                        self.stats
+                            .entry(socket_handle)
+                            .or_insert_with(SocketStats::default)
+                            .on_send(count);
+                        if let Some(timer) = self.idle.get_mut(&socket_handle) {
+                            timer.touch();
+                        }
+                        if let Some(send_rate) = self.send_rate.get_mut(&socket_handle) {
+                            send_rate.on_send(count);
+                        }
+                    }
+                    if bound {
+                        self.rearm(socket_handle, EVENT_WRITE.bits());
+                    }
+                    return result;
                 }
             }
         };
-        self.update_setting(fd, setting, buf).map(Some)
+        let count = self.update_setting(fd, setting, buf)?;
+        if !self.setting_watchers.is_empty() {
+            self.notify_setting_change(setting_fd)?;
+        }
+        Ok(Some(count))
     }
 
     fn read(&mut self, fd: usize, buf: &mut [u8]) -> SyscallResult<Option<usize>> {
+        if let Some(event_file) = self.event_files.get_mut(&fd) {
+            return event_file.read(buf);
+        }
+        if let Some(list) = self.lists.get_mut(&fd) {
+            return Ok(Some(list.read(buf)));
+        }
+
         let (fd, setting) = {
             let file = self
                 .files
@@ -586,9 +2195,42 @@ where
                     (setting_handle.fd, setting_handle.setting)
                 }
                 SchemeFile::Socket(ref mut file) => {
+                    if let Some(err) = file.pending_error.take() {
+                        return Err(err);
+                    }
                     let mut iface = self.iface.borrow_mut();
-                    let mut socket = iface.get_socket::<SocketT>(file.socket_handle);
-                    return SocketT::read_buf(&mut socket, file, buf);
+                    // Timestamped reads put the receive time ahead of the data
+                    let stamped = self.timestamping.contains(&file.socket_handle);
+                    let header = if stamped { mem::size_of::<TimeSpec>() } else { 0 };
+                    if stamped && buf.len() <= header {
+                        return Err(SyscallError::new(syscall::EINVAL));
+                    }
+                    let result = {
+                        let mut socket = iface.get_socket::<SocketT>(file.socket_handle);
+                        SocketT::read_buf(&mut socket, file, &mut buf[header..])
+                    };
+                    if let Ok(Some(count)) = result {
+                        self.stats
+                            .entry(file.socket_handle)
+                            .or_insert_with(SocketStats::default)
+                            .on_recv(count);
+                        if let Some(timer) = self.idle.get_mut(&file.socket_handle) {
+                            timer.touch();
+                        }
+                        // End of file stays a read of nothing, unlike an
+                        // empty datagram
+                        let datagram = SocketT::take_datagram(&mut file.data);
+                        if stamped && (count > 0 || datagram.is_some()) {
+                            let stamp = iface
+                                .device()
+                                .get_ref()
+                                .take_rx_stamp(file.socket_handle, datagram)
+                                .unwrap_or_default();
+                            read_timespec(&stamp, &mut buf[..header])?;
+                            return Ok(Some(header + count));
+                        }
+                    }
+                    return result;
                 }
             }
         };
@@ -603,10 +2245,49 @@ where
             .get(&fd)
             .map(|null| (null.flags, null.uid, null.gid))
         {
+            if path == "events" {
+                let id = self.next_fd;
+                self.next_fd += 1;
+                self.event_files.insert(id, EventFile::new(flags));
+                return Ok(Some(id));
+            }
+            if path == "sockets" {
+                if uid != 0 {
+                    return Err(SyscallError::new(syscall::EACCES));
+                }
+                let list = self.list_sockets();
+                let id = self.next_fd;
+                self.next_fd += 1;
+                self.lists.insert(id, list);
+                return Ok(Some(id));
+            }
+            if path.starts_with("portcheck:") {
+                let port = path["portcheck:".len()..]
+                    .parse::<u16>()
+                    .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                let claimed = SocketT::port_claimed(&self.scheme_data, port)
+                    .ok_or_else(|| SyscallError::new(syscall::EOPNOTSUPP))?;
+                let id = self.next_fd;
+                self.next_fd += 1;
+                self.lists.insert(
+                    id,
+                    SocketList {
+                        data: vec![claimed as u8],
+                        offset: 0,
+                    },
+                );
+                return Ok(Some(id));
+            }
             return self.open(path, flags, uid, gid);
         }
 
-        let new_file = {
+        // Settings match on the bare name and ignore any parameters
+        let (path, query) = split_query(path);
+
+        // Before dups that accept a connection or abort the socket
+        check_files_limit(self.files.len())?;
+
+        let (new_file, moved) = {
             let file = self
                 .files
                 .get_mut(&fd)
@@ -614,6 +2295,15 @@ where
 
             let socket_handle = file.socket_handle();
 
+            // A connect that gave up in the background reports why
+            if path == "connect" && SocketT::parse_setting(path).is_none() {
+                if let SchemeFile::Socket(ref mut file) = *file {
+                    if let Some(err) = file.pending_error.take() {
+                        return Err(err);
+                    }
+                }
+            }
+
             let (new_handle, update_with) = match path {
                 "hop_limit" => (
                     SchemeFile::Setting(SettingFile {
@@ -639,30 +2329,299 @@ where
                     }),
                     None,
                 ),
-                _ => match SocketT::dup(
-                    &mut self.iface.borrow_mut(),
-                    file,
-                    path,
-                    &mut self.scheme_data,
-                )? {
-                    Some(some) => some,
-                    None => return Ok(None),
+                "stats" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Stats,
+                    }),
+                    None,
+                ),
+                "owner" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Owner,
+                    }),
+                    None,
+                ),
+                "inq" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::InQ,
+                    }),
+                    None,
+                ),
+                "outq" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::OutQ,
+                    }),
+                    None,
+                ),
+                "mss" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::MaxSegmentSize,
+                    }),
+                    None,
+                ),
+                "idle_timeout" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::IdleTimeout,
+                    }),
+                    None,
+                ),
+                "linger" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Linger,
+                    }),
+                    None,
+                ),
+                "tos" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Tos,
+                    }),
+                    None,
+                ),
+                "flush_recv" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::FlushRecv,
+                    }),
+                    None,
+                ),
+                "rcvlowat" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::RecvLowat,
+                    }),
+                    None,
+                ),
+                "sndlowat" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::SendLowat,
+                    }),
+                    None,
+                ),
+                "dontroute" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::DontRoute,
+                    }),
+                    None,
+                ),
+                "nochecksum" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::NoChecksum,
+                    }),
+                    None,
+                ),
+                "no_pmtud" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::NoPmtud,
+                    }),
+                    None,
+                ),
+                "bindtodevice" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::BindToDevice,
+                    }),
+                    None,
+                ),
+                // Pins the socket right away, the fd reads the name back
+                _ if path.starts_with("bindtodevice/") => {
+                    let name = &path["bindtodevice/".len()..];
+                    bind_to_device(&mut self.bound_device, socket_handle, name)?;
+                    (
+                        SchemeFile::Setting(SettingFile {
+                            socket_handle,
+                            fd,
+                            setting: Setting::BindToDevice,
+                        }),
+                        None,
+                    )
+                }
+                "cancel" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Cancel,
+                    }),
+                    None,
+                ),
+                // Urgent data is only noted from the first dup on
+                "oob" => {
+                    let mut iface = self.iface.borrow_mut();
+                    let flow = match *file {
+                        SchemeFile::Socket(ref file) => {
+                            iface.get_socket::<SocketT>(socket_handle).ip_flow(&file.data)
+                        }
+                        SchemeFile::Setting(_) => None,
+                    };
+                    let flow = match flow {
+                        Some(flow) if flow.protocol == IpProtocol::Tcp => flow,
+                        _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                    };
+                    iface.device().get_ref().watch_urgent(socket_handle, flow, true);
+                    self.oob.insert(socket_handle);
+                    (
+                        SchemeFile::Setting(SettingFile {
+                            socket_handle,
+                            fd,
+                            setting: Setting::Oob,
+                        }),
+                        None,
+                    )
+                }
+                "timestamping" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Timestamping,
+                    }),
+                    None,
+                ),
+                "df" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::DontFragment,
+                    }),
+                    None,
+                ),
+                "syn_retries" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::SynRetries,
+                    }),
+                    None,
+                ),
+                "options" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::Options,
+                    }),
+                    None,
+                ),
+                "sndrate" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::SendRate,
+                    }),
+                    None,
+                ),
+                "txqueue" => (
+                    SchemeFile::Setting(SettingFile {
+                        socket_handle,
+                        fd,
+                        setting: Setting::TxQueue,
+                    }),
+                    None,
+                ),
+                "abort" => {
+                    {
+                        let mut iface = self.iface.borrow_mut();
+                        let socket = iface.get_socket::<SocketT>(socket_handle);
+                        let flow = match *file {
+                            SchemeFile::Socket(ref file) => socket.ip_flow(&file.data),
+                            SchemeFile::Setting(_) => None,
+                        };
+                        match flow {
+                            Some(ref flow) if flow.protocol == IpProtocol::Tcp => (),
+                            _ => return Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                        }
+                        socket.shutdown(true);
+                    }
+                    // Like a zero linger, the last close only waits for the reset to go out
+                    self.linger.insert(socket_handle, TimeSpec::default());
+                    (
+                        SchemeFile::Setting(SettingFile {
+                            socket_handle,
+                            fd,
+                            setting: Setting::Abort,
+                        }),
+                        None,
+                    )
+                }
+                _ => match SocketT::parse_setting(path) {
+                    Some(setting) => (
+                        SchemeFile::Setting(SettingFile {
+                            socket_handle,
+                            fd,
+                            setting: Setting::Other(setting),
+                        }),
+                        None,
+                    ),
+                    None => match SocketT::dup(
+                        &mut self.iface.borrow_mut(),
+                        file,
+                        path,
+                        &query,
+                        &mut self.scheme_data,
+                    )? {
+                        Some(some) => some,
+                        None => {
+                            if let SchemeFile::Socket(ref file) = *file {
+                                if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+                                    return Err(SyscallError::new(syscall::EAGAIN));
+                                }
+                            }
+                            return Ok(None);
+                        }
+                    },
                 },
             };
 
+            let mut moved = None;
             if let Some((socket_handle, data)) = update_with {
                 if let SchemeFile::Socket(ref mut file) = *file {
+                    moved = Some((file.socket_handle, socket_handle));
                     file.socket_handle = socket_handle;
                     file.data = data;
                 }
             }
-            new_handle
+            (new_handle, moved)
         };
 
         let id = self.next_fd;
+        self.acquire_ref(new_file.socket_handle());
         self.files.insert(id, new_file);
         self.next_fd += 1;
 
+        // The original fd now refers to another socket, like a listener after accept
+        if let Some((old_handle, socket_handle)) = moved {
+            self.acquire_ref(socket_handle);
+            self.release_ref(old_handle);
+            if let Some(&owner) = self.owners.get(&old_handle) {
+                self.owners.insert(socket_handle, owner);
+            }
+            self.hand_over_hooks(old_handle, socket_handle);
+        }
+
         Ok(Some(id))
     }
 
@@ -671,12 +2630,33 @@ where
         fd: usize,
         events: SyscallEventFlags,
     ) -> SyscallResult<Option<SyscallEventFlags>> {
+        if let Some(event_file) = self.event_files.get_mut(&fd) {
+            event_file.events = events.bits();
+            event_file.read_notified = !event_file.ready.is_empty();
+            return if events.contains(EVENT_READ) && event_file.read_notified {
+                Ok(Some(EVENT_READ))
+            } else {
+                Ok(Some(SyscallEventFlags::empty()))
+            };
+        }
+
         let file = self
             .files
             .get_mut(&fd)
             .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
         match *file {
-            SchemeFile::Setting(_) => return Err(SyscallError::new(syscall::EBADF)),
+            // Setting reads and writes complete right away like those of a
+            // regular file, so poll based clients see them as always ready.
+            // Asking for `EVENT_READ` also subscribes to changes made through
+            // other fds, see `notify_setting_change`.
+            SchemeFile::Setting(_) => {
+                if events.contains(EVENT_READ) {
+                    self.setting_watchers.insert(fd);
+                } else {
+                    self.setting_watchers.remove(&fd);
+                }
+                return Ok(Some(events & (EVENT_READ | EVENT_WRITE)));
+            }
             SchemeFile::Socket(ref mut file) => {
                 file.events = events.bits();
                 file.read_notified = false; // resend missed events
@@ -688,15 +2668,30 @@ where
     }
 
     fn fsync(&mut self, fd: usize) -> SyscallResult<Option<usize>> {
+        let file = self
+            .files
+            .get_mut(&fd)
+            .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+        match *file {
+            SchemeFile::Setting(_) => Ok(Some(0)),
+            SchemeFile::Socket(ref mut file) => {
+                let mut iface = self.iface.borrow_mut();
+                let mut socket = iface.get_socket::<SocketT>(file.socket_handle);
+                SocketT::fsync(&mut socket, file)
+            }
+        }
+    }
+
+    fn seek(&mut self, fd: usize, _pos: isize, _whence: usize) -> SyscallResult<Option<isize>> {
+        if self.nulls.contains_key(&fd)
+            || self.files.contains_key(&fd)
+            || self.event_files.contains_key(&fd)
+            || self.lists.contains_key(&fd)
         {
-            let _file = self
-                .files
-                .get_mut(&fd)
-                .ok_or_else(|| SyscallError::new(syscall::EBADF))?;
+            Err(SyscallError::new(syscall::ESPIPE))
+        } else {
+            Err(SyscallError::new(syscall::EBADF))
         }
-        Ok(Some(0))
-        // TODO Implement fsyncing
-        // self.0.network_fsync()
     }
 
     fn fpath(&mut self, fd: usize, buf: &mut [u8]) -> SyscallResult<Option<usize>> {
@@ -712,6 +2707,16 @@ where
     }
 
     fn fcntl(&mut self, fd: usize, cmd: usize, arg: usize) -> SyscallResult<Option<usize>> {
+        if let Some(event_file) = self.event_files.get_mut(&fd) {
+            return match cmd {
+                syscall::F_GETFL => Ok(Some(event_file.flags)),
+                syscall::F_SETFL => {
+                    event_file.flags = arg & !syscall::O_ACCMODE;
+                    Ok(Some(0))
+                }
+                _ => Err(SyscallError::new(syscall::EINVAL)),
+            };
+        }
         if let Some(ref mut null) = self.nulls.get_mut(&fd) {
             match cmd {
                 syscall::F_GETFL => Ok(Some(null.flags)),
@@ -719,6 +2724,11 @@ where
                     null.flags = arg & !syscall::O_ACCMODE;
                     Ok(Some(0))
                 }
+                syscall::F_GETFD => Ok(Some(null.fd_flags)),
+                syscall::F_SETFD => {
+                    null.fd_flags = arg;
+                    Ok(Some(0))
+                }
                 _ => Err(SyscallError::new(syscall::EINVAL)),
             }
         } else {
@@ -734,6 +2744,11 @@ where
                         socket_file.flags = arg & !syscall::O_ACCMODE;
                         Ok(Some(0))
                     }
+                    syscall::F_GETFD => Ok(Some(socket_file.fd_flags)),
+                    syscall::F_SETFD => {
+                        socket_file.fd_flags = arg;
+                        Ok(Some(0))
+                    }
                     _ => Err(SyscallError::new(syscall::EINVAL)),
                 }
             } else {
@@ -743,15 +2758,508 @@ where
     }
 }
 
+/// Highest port that only root may bind
+static PRIVILEGED_PORT_MAX: AtomicUsize = AtomicUsize::new(1024);
+
+pub fn privileged_port_max() -> u16 {
+    PRIVILEGED_PORT_MAX.load(Ordering::Relaxed) as u16
+}
+
+pub fn set_privileged_port_max(port: u16) {
+    PRIVILEGED_PORT_MAX.store(usize::from(port), Ordering::Relaxed);
+}
+
+/// Fails with `EACCES` if `uid` isn't root and `port` is privileged. Port 0
+/// asks for an ephemeral port and is always allowed.
+pub fn check_bind_permission(port: u16, uid: u32) -> SyscallResult<()> {
+    if port > 0 && port <= privileged_port_max() && uid != 0 {
+        Err(SyscallError::new(syscall::EACCES))
+    } else {
+        Ok(())
+    }
+}
+
+/// Most sockets the interface holds at once across all schemes, 0 for no limit
+static MAX_SOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn max_sockets() -> usize {
+    MAX_SOCKETS.load(Ordering::Relaxed)
+}
+
+pub fn set_max_sockets(max: usize) {
+    MAX_SOCKETS.store(max, Ordering::Relaxed);
+}
+
+/// Socket and setting fds each scheme keeps open at most, past which opens
+/// and dups fail with `EMFILE`
+static MAX_FILES: AtomicUsize = AtomicUsize::new(65_536);
+
+pub fn set_max_files(max: usize) {
+    MAX_FILES.store(max, Ordering::Relaxed);
+}
+
+fn check_files_limit(open_files: usize) -> SyscallResult<()> {
+    if open_files >= MAX_FILES.load(Ordering::Relaxed) {
+        Err(SyscallError::new(syscall::EMFILE))
+    } else {
+        Ok(())
+    }
+}
+
+/// Transmit queues a socket can select, one per 802.1p priority. The network
+/// device sends every frame through its single queue, so the selection is
+/// only recorded until a multi-queue device comes along.
+const TX_QUEUES: u32 = 8;
+
+/// Packets a datagram socket buffers each way unless configured otherwise
+pub const DEFAULT_SOCKET_BUFFER_SIZE: usize = 128;
+/// Counts past this cost megabytes per socket and are most likely a mistake
+const LARGE_SOCKET_BUFFER_SIZE: usize = 4096;
+
+/// Packets each UDP, ICMP and raw IP socket opened from now on buffers for
+/// receiving and for sending. Every packet reserves an MTU worth of payload,
+/// so a socket takes `2 * size * mtu` bytes, which are traded against how
+/// large a burst it absorbs without dropping.
+static SOCKET_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_SOCKET_BUFFER_SIZE);
+
+pub fn socket_buffer_size() -> usize {
+    SOCKET_BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Fails with `EINVAL` for sockets that couldn't buffer a single packet.
+pub fn set_socket_buffer_size(size: usize) -> SyscallResult<()> {
+    if size == 0 {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+    if size > LARGE_SOCKET_BUFFER_SIZE {
+        warn!("socket buffers of {} packets take a lot of memory per socket", size);
+    }
+    SOCKET_BUFFER_SIZE.store(size, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Adds `socket` to the interface, failing with `ENOBUFS` once the socket
+/// limit is reached. The socket set grows as needed and reuses the slots of
+/// removed sockets.
+pub fn add_socket<SocketT: AnySocket<'static>>(
+    iface: &mut SmolnetInterface,
+    socket: SocketT,
+) -> SyscallResult<SocketHandle> {
+    let max = max_sockets();
+    if max != 0 && iface.sockets().count() >= max {
+        return Err(SyscallError::new(syscall::ENOBUFS));
+    }
+    Ok(iface.add_socket(socket))
+}
+
+/// The errno a smoltcp error is reported as, for every scheme alike.
+pub fn smoltcp_err_to_syscall(err: smoltcp::Error) -> SyscallError {
+    SyscallError::new(match err {
+        smoltcp::Error::Exhausted => syscall::EAGAIN,
+        smoltcp::Error::Illegal => syscall::EINVAL,
+        smoltcp::Error::Unaddressable => syscall::EADDRNOTAVAIL,
+        smoltcp::Error::Finished => syscall::EPIPE,
+        smoltcp::Error::Truncated => syscall::EMSGSIZE,
+        smoltcp::Error::Checksum | smoltcp::Error::Malformed | smoltcp::Error::Fragmented => {
+            syscall::EBADMSG
+        }
+        smoltcp::Error::Unrecognized => syscall::EPROTONOSUPPORT,
+        smoltcp::Error::Dropped => syscall::ENOBUFS,
+        smoltcp::Error::NotSupported => syscall::EOPNOTSUPP,
+        _ => syscall::EIO,
+    })
+}
+
+/// Copies `path` into `buf`, failing with `ERANGE` rather than truncating it.
+pub fn write_path(path: &str, buf: &mut [u8]) -> SyscallResult<usize> {
+    let path = path.as_bytes();
+    if buf.len() < path.len() {
+        return Err(SyscallError::new(syscall::ERANGE));
+    }
+    buf[..path.len()].copy_from_slice(path);
+    Ok(path.len())
+}
+
+/// Splits a little-endian u32 segment count, followed by a u32 length and the
+/// bytes of every segment, into the segments.
+pub fn parse_segments(buf: &[u8]) -> SyscallResult<Vec<&[u8]>> {
+    if buf.len() < 4 {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+    let count = LittleEndian::read_u32(&buf[..4]);
+    let mut rest = &buf[4..];
+    let mut segments = Vec::new();
+    for _ in 0..count {
+        if rest.len() < 4 {
+            return Err(SyscallError::new(syscall::EINVAL));
+        }
+        let len = LittleEndian::read_u32(&rest[..4]) as usize;
+        rest = &rest[4..];
+        if rest.len() < len {
+            return Err(SyscallError::new(syscall::EINVAL));
+        }
+        segments.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    if !rest.is_empty() {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+    Ok(segments)
+}
+
+pub fn read_timespec(timespec: &TimeSpec, buf: &mut [u8]) -> SyscallResult<usize> {
+    if buf.len() < mem::size_of::<TimeSpec>() {
+        Ok(0)
+    } else {
+        timespec
+            .deref()
+            .read(buf)
+            .map_err(|err| SyscallError::new(err.raw_os_error().unwrap_or(syscall::EIO)))
+    }
+}
+
+pub fn parse_timespec(buf: &[u8]) -> SyscallResult<(Option<TimeSpec>, usize)> {
+    if buf.len() < mem::size_of::<TimeSpec>() {
+        Ok((None, 0))
+    } else {
+        let mut timespec = TimeSpec::default();
+        let count = timespec
+            .deref_mut()
+            .write(buf)
+            .map_err(|err| SyscallError::new(err.raw_os_error().unwrap_or(syscall::EIO)))?;
+        Ok((Some(timespec), count))
+    }
+}
+
+pub fn time_before(a: &TimeSpec, b: &TimeSpec) -> bool {
+    a.tv_sec < b.tv_sec || (a.tv_sec == b.tv_sec && a.tv_nsec < b.tv_nsec)
+}
+
 fn add_time(a: &TimeSpec, b: &TimeSpec) -> TimeSpec {
-    let mut secs = a.tv_sec + b.tv_sec;
-    let mut nsecs = a.tv_nsec + b.tv_nsec;
+    // Widened, as clients may hand in a timeout with out of range nanoseconds
+    let nsecs = i64::from(a.tv_nsec) + i64::from(b.tv_nsec);
 
-    secs += i64::from(nsecs) / 1_000_000_000;
-    nsecs %= 1_000_000_000;
+    // Saturating, so that a huge timeout means never instead of wrapping
+    // around to a deadline in the past. Negative nanoseconds borrow from the
+    // seconds so that tv_nsec always ends up in 0..1_000_000_000.
+    let secs = a
+        .tv_sec
+        .saturating_add(b.tv_sec)
+        .saturating_add(nsecs.div_euclid(1_000_000_000));
 
     TimeSpec {
         tv_sec: secs,
-        tv_nsec: nsecs,
+        tv_nsec: nsecs.rem_euclid(1_000_000_000) as i32,
+    }
+}
+
+/// Whether the IPv4 packets of a socket carry the don't-fragment bit, as
+/// written through `df` if it was, otherwise unless path MTU discovery is off.
+fn dont_frag(
+    dont_frag: &BTreeMap<SocketHandle, bool>,
+    no_pmtud: &BTreeSet<SocketHandle>,
+    socket_handle: SocketHandle,
+) -> bool {
+    match dont_frag.get(&socket_handle) {
+        Some(&dont_frag) => dont_frag,
+        None => !no_pmtud.contains(&socket_handle),
+    }
+}
+
+/// Fails with `ENETUNREACH` unless `addr` is on the link of the interface, or
+/// with `allow_routed` reachable through one of its routes.
+fn check_egress(
+    iface: &mut SmolnetInterface,
+    addr: IpAddress,
+    allow_routed: bool,
+) -> SyscallResult<()> {
+    if iface.ip_addrs().iter().any(|cidr| cidr.contains_addr(&addr)) {
+        return Ok(());
+    }
+    let mut routed = false;
+    if allow_routed {
+        iface.routes_mut().update(|map| {
+            routed = map.keys().any(|cidr| cidr.contains_addr(&addr));
+        });
+    }
+    if routed {
+        Ok(())
+    } else {
+        Err(SyscallError::new(syscall::ENETUNREACH))
+    }
+}
+
+/// Pins the socket to the interface `name`, or unpins it if `name` is empty.
+/// `eth0` is the only interface there is.
+fn bind_to_device(
+    bound_device: &mut BTreeMap<SocketHandle, String>,
+    socket_handle: SocketHandle,
+    name: &str,
+) -> SyscallResult<()> {
+    match name {
+        "" => {
+            bound_device.remove(&socket_handle);
+        }
+        "eth0" => {
+            bound_device.insert(socket_handle, name.to_owned());
+        }
+        _ => return Err(SyscallError::new(syscall::ENODEV)),
+    }
+    Ok(())
+}
+
+/// Path a blocked dup was called with, still in the caller's memory
+fn dup_path(packet: &SyscallPacket) -> &[u8] {
+    unsafe { slice::from_raw_parts(packet.c as *const u8, packet.d) }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::socket::UdpSocket;
+
+    use super::super::harness::Harness;
+    use super::*;
+
+    fn time(tv_sec: i64, tv_nsec: i32) -> TimeSpec {
+        TimeSpec { tv_sec, tv_nsec }
+    }
+
+    fn assert_time(t: TimeSpec, tv_sec: i64, tv_nsec: i32) {
+        assert_eq!((t.tv_sec, t.tv_nsec), (tv_sec, tv_nsec));
+    }
+
+    fn assert_released(scheme: &SocketScheme<UdpSocket<'static>>, sockets: usize) {
+        assert_eq!(scheme.iface.borrow().sockets().count(), sockets);
+        assert!(scheme.refs.is_empty());
+        assert!((0..=65535).all(|port| !scheme.scheme_data.is_claimed(port)));
+    }
+
+    #[test]
+    fn smoltcp_errors_map_to_errno() {
+        let cases = [
+            (smoltcp::Error::Exhausted, syscall::EAGAIN),
+            (smoltcp::Error::Illegal, syscall::EINVAL),
+            (smoltcp::Error::Unaddressable, syscall::EADDRNOTAVAIL),
+            (smoltcp::Error::Finished, syscall::EPIPE),
+            (smoltcp::Error::Truncated, syscall::EMSGSIZE),
+            (smoltcp::Error::Checksum, syscall::EBADMSG),
+            (smoltcp::Error::Malformed, syscall::EBADMSG),
+            (smoltcp::Error::Fragmented, syscall::EBADMSG),
+            (smoltcp::Error::Unrecognized, syscall::EPROTONOSUPPORT),
+            (smoltcp::Error::Dropped, syscall::ENOBUFS),
+            (smoltcp::Error::NotSupported, syscall::EOPNOTSUPP),
+        ];
+        for &(err, errno) in cases.iter() {
+            assert_eq!(smoltcp_err_to_syscall(err).errno, errno, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn bind_permission() {
+        let check = |port, uid| check_bind_permission(port, uid).map_err(|err| err.errno);
+        assert_eq!(check(80, 0), Ok(()));
+        assert_eq!(check(80, 1000), Err(syscall::EACCES));
+        assert_eq!(check(1024, 0), Ok(()));
+        assert_eq!(check(1024, 1000), Err(syscall::EACCES));
+        assert_eq!(check(1025, 1000), Ok(()));
+        // An ephemeral port is never privileged
+        assert_eq!(check(0, 1000), Ok(()));
+
+        set_privileged_port_max(8080);
+        assert_eq!(check(8080, 1000), Err(syscall::EACCES));
+        assert_eq!(check(8081, 1000), Ok(()));
+        set_privileged_port_max(0);
+        assert_eq!(check(1, 1000), Ok(()));
+        set_privileged_port_max(1024);
+    }
+
+    #[test]
+    fn write_path_fits_or_fails() {
+        let mut buf = [0; 4];
+        assert_eq!(
+            write_path("udp:10.0.2.2:53/10.0.2.15:49152", &mut buf).unwrap_err().errno,
+            syscall::ERANGE
+        );
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(write_path("tcp:", &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"tcp:");
+        assert_eq!(write_path("", &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn fpath_into_short_buffer() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (SocketScheme<UdpSocket<'static>>, _) = harness.scheme();
+        let fd = scheme
+            .open("127.0.0.1:7051/127.0.0.1:7050", syscall::O_RDWR, 0, 0)
+            .unwrap()
+            .unwrap();
+
+        let mut buf = [0; 4];
+        assert_eq!(scheme.fpath(fd, &mut buf).unwrap_err().errno, syscall::ERANGE);
+        let mut buf = [0; 64];
+        let len = scheme.fpath(fd, &mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..len], b"udp:127.0.0.1:7051/127.0.0.1:7050");
+    }
+
+    #[test]
+    fn add_time_carries_nanoseconds() {
+        assert_time(add_time(&time(1, 500_000_000), &time(2, 400_000_000)), 3, 900_000_000);
+        assert_time(add_time(&time(1, 600_000_000), &time(2, 400_000_000)), 4, 0);
+        assert_time(add_time(&time(1, 999_999_999), &time(0, 999_999_999)), 2, 999_999_998);
+        // Out of range nanoseconds handed in by a client
+        assert_time(add_time(&time(0, ::std::i32::MAX), &time(10, 0)), 12, 147_483_647);
+    }
+
+    #[test]
+    fn add_time_borrows_for_negative_nanoseconds() {
+        assert_time(add_time(&time(5, 0), &time(0, -1)), 4, 999_999_999);
+        assert_time(add_time(&time(5, 100), &time(1, -1_000_000_100)), 5, 0);
+        assert_time(add_time(&time(5, 0), &time(0, ::std::i32::MIN)), 2, 852_516_352);
+    }
+
+    #[test]
+    fn add_time_saturates() {
+        let max = ::std::i64::MAX;
+        assert_time(add_time(&time(max - 1, 0), &time(10, 0)), max, 0);
+        assert_time(add_time(&time(max, 999_999_999), &time(0, 1)), max, 0);
+        assert_time(add_time(&time(max, 0), &time(max, 999_999_999)), max, 999_999_999);
+    }
+
+    #[test]
+    fn cancel_interrupts_blocked_read() {
+        let harness = Harness::new();
+        let (mut scheme, scheme_file): (SocketScheme<UdpSocket<'static>>, _) = harness.scheme();
+        let fd = scheme
+            .open("127.0.0.1:7041/127.0.0.1:7040", syscall::O_RDWR, 0, 0)
+            .unwrap()
+            .unwrap();
+
+        // Queued the way on_scheme_event does when the read would block
+        let mut buf = [0; 16];
+        let mut packet = SyscallPacket {
+            a: syscall::SYS_READ,
+            b: fd,
+            c: buf.as_mut_ptr() as usize,
+            d: buf.len(),
+            ..SyscallPacket::default()
+        };
+        assert_eq!(scheme.handle(&packet), None);
+        let until = scheme.handle_block(&mut packet).unwrap();
+        scheme.wait_queue.push(WaitHandle { until, packet });
+
+        let cancel = scheme.dup(fd, b"cancel").unwrap().unwrap();
+        let mut waiting = [0; 8];
+        assert_eq!(scheme.read(cancel, &mut waiting).unwrap(), Some(8));
+        assert_eq!(LittleEndian::read_u64(&waiting), 1);
+        assert_eq!(scheme.write(cancel, &[1]).unwrap(), Some(1));
+        assert_eq!(scheme.waiting(), 0);
+
+        let mut reply = SyscallPacket::default();
+        let len = scheme_file.open().read(&mut reply).unwrap();
+        assert_eq!(len, mem::size_of::<SyscallPacket>());
+        assert_eq!((reply.a, reply.b), ((-syscall::EINTR) as usize, fd));
+    }
+
+    #[test]
+    fn dup_outlives_closed_original() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (SocketScheme<UdpSocket<'static>>, _) = harness.scheme();
+        let flags = syscall::O_RDWR | syscall::O_NONBLOCK;
+        let server = scheme
+            .open("127.0.0.1:7081/127.0.0.1:7080", flags, 0, 0)
+            .unwrap()
+            .unwrap();
+        let original = scheme
+            .open("127.0.0.1:7080/127.0.0.1:7081", flags, 0, 0)
+            .unwrap()
+            .unwrap();
+        let dup = scheme.dup(original, b"").unwrap().unwrap();
+        assert_eq!(scheme.close(original).unwrap(), Some(0));
+
+        let mut buf = [0; 16];
+        assert_eq!(scheme.write(dup, b"ping").unwrap(), Some(4));
+        harness.poll();
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"ping");
+
+        assert_eq!(scheme.write(server, b"pong").unwrap(), Some(4));
+        harness.poll();
+        assert_eq!(scheme.read(dup, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"pong");
+        assert_eq!(scheme.read(original, &mut buf).unwrap_err().errno, syscall::EBADF);
+    }
+
+    #[test]
+    fn open_close_churn_leaks_nothing() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (SocketScheme<UdpSocket<'static>>, _) = harness.scheme();
+        let flags = syscall::O_RDWR | syscall::O_NONBLOCK;
+        let sockets = harness.iface.borrow().sockets().count();
+
+        for i in 0..2000 {
+            let fd = scheme.open("127.0.0.1:7100/0", flags, 0, 0).unwrap().unwrap();
+            // Setting fds and dups hold the socket as well, in any order
+            let hop_limit = scheme.dup(fd, b"hop_limit").unwrap().unwrap();
+            let dup = scheme.dup(fd, b"").unwrap().unwrap();
+            let fds = if i % 2 == 0 { [fd, hop_limit, dup] } else { [dup, fd, hop_limit] };
+            for &fd in fds.iter() {
+                assert_eq!(scheme.close(fd).unwrap(), Some(0));
+            }
+        }
+        assert!(scheme.files.is_empty());
+        assert_released(&scheme, sockets);
+    }
+
+    #[test]
+    fn scheme_eof_closes_all() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (SocketScheme<UdpSocket<'static>>, _) = harness.scheme();
+        let flags = syscall::O_RDWR | syscall::O_NONBLOCK;
+        let sockets = harness.iface.borrow().sockets().count();
+
+        let fd = scheme
+            .open("127.0.0.1:7111/127.0.0.1:7110", flags, 0, 0)
+            .unwrap()
+            .unwrap();
+        scheme.dup(fd, b"").unwrap().unwrap();
+        scheme.dup(fd, b"hop_limit").unwrap().unwrap();
+        scheme.open("127.0.0.1:7112/0", flags, 0, 0).unwrap().unwrap();
+        scheme.open("/0.0.0.0:7113", flags, 0, 0).unwrap().unwrap();
+
+        // Nothing was ever written to the scheme file, so it reads as closed
+        assert_eq!(scheme.on_scheme_event().unwrap(), Some(()));
+        assert!(scheme.files.is_empty());
+        assert_eq!(scheme.waiting(), 0);
+        assert_released(&scheme, sockets);
+    }
+
+    #[test]
+    fn dup_fires_write_event_again() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (SocketScheme<UdpSocket<'static>>, _) = harness.scheme();
+        let flags = syscall::O_RDWR | syscall::O_NONBLOCK;
+        let fd = scheme
+            .open("127.0.0.1:7121/127.0.0.1:7120", flags, 0, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(scheme.fevent(fd, EVENT_WRITE).unwrap(), Some(EVENT_WRITE));
+        // The edge of the original fd already fired
+        let events = scheme
+            .files
+            .get_mut(&fd)
+            .unwrap()
+            .events(&mut scheme.iface.borrow_mut());
+        assert_eq!(events, 0);
+
+        let dup = scheme.dup(fd, b"").unwrap().unwrap();
+        // Subscriptions aren't inherited, so nothing fires before fevent
+        let events = scheme
+            .files
+            .get_mut(&dup)
+            .unwrap()
+            .events(&mut scheme.iface.borrow_mut());
+        assert_eq!(events, 0);
+        assert_eq!(scheme.fevent(dup, EVENT_WRITE).unwrap(), Some(EVENT_WRITE));
     }
 }
@@ -1,24 +1,208 @@
-use smoltcp::socket::{TcpSocket, TcpSocketBuffer};
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian};
+use smoltcp;
+use smoltcp::socket::{TcpSocket, TcpSocketBuffer, TcpState};
 use smoltcp::iface::{SocketHandle};
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::str;
+use syscall::data::TimeSpec;
 use syscall::{Error as SyscallError, Result as SyscallResult};
 use syscall;
 
+use device::Flow;
 use port_set::PortSet;
-use super::socket::{DupResult, SchemeFile, SchemeSocket, SocketFile, SocketScheme};
-use super::{parse_endpoint, SmolnetInterface};
+use super::socket::{add_socket, check_bind_permission, parse_segments, parse_timespec,
+                    read_timespec, smoltcp_err_to_syscall, write_path, DupResult, SchemeFile,
+                    SchemeSocket, SocketFile, SocketScheme};
+use super::{parse_endpoint, split_open_path, split_query, SmolnetInterface};
 
 pub type TcpScheme = SocketScheme<TcpSocket<'static>>;
 
+const DEFAULT_BUFFER_SIZE: usize = 0xffff;
+/// Upper bound on the receive buffer a client can ask for with `?rwnd=`
+const MAX_RECV_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// Length prefixed to every record on the wire, as a big-endian u32
+const RECORD_HEADER_LEN: usize = 4;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TcpSetting {
+    UserTimeout,
+    /// Receive window we can currently offer, as a little-endian u32. smoltcp
+    /// keeps the window advertised by the peer to itself.
+    Window,
+    /// Smoothed round-trip time and its variance. smoltcp keeps its RTT
+    /// estimator private, so reading fails with `EOPNOTSUPP`.
+    Rtt,
+    /// Congestion window in bytes, which smoltcp doesn't implement, so
+    /// reading fails with `EOPNOTSUPP`.
+    CongestionWindow,
+    /// Initial congestion window in segments. Without congestion control in
+    /// smoltcp there's nothing to set, so it fails with `EOPNOTSUPP`, or with
+    /// `EINVAL` when written once the connection is established.
+    InitialCongestionWindow,
+    /// Whether data may ride on the SYN, as a single byte. smoltcp builds the
+    /// SYN itself without the Fast Open option, so it always reads 0 and
+    /// turning it on fails with `EOPNOTSUPP`.
+    FastOpen,
+}
+
+#[derive(Copy, Clone)]
+pub struct TcpData {
+    /// Local address the port was claimed for, which stays a wildcard even
+    /// once smoltcp picks the address of a connection
+    bound_addr: IpAddress,
+    /// Local port claimed at open, which smoltcp forgets once the connection
+    /// is reset
+    port: u16,
+    /// Peer the socket was opened to connect to, unspecified for listeners
+    /// and the connections they accept
+    remote_endpoint: IpEndpoint,
+    /// Every write goes out as one length-prefixed record and every read
+    /// returns one record. Only peers framing the same way understand it.
+    record: bool,
+    /// Bytes of a record too large to ever be received whole, still to be
+    /// thrown away as they arrive
+    skip: usize,
+    /// Writes carry a list of segments queued together, all or nothing, so
+    /// many small writes cost a single syscall
+    vectored: bool,
+}
+
+/// Whether the peer sent its FIN or the connection is gone, so nothing more
+/// will arrive past what is buffered.
+fn peer_closed(socket: &TcpSocket) -> bool {
+    match socket.state() {
+        TcpState::CloseWait
+        | TcpState::LastAck
+        | TcpState::Closing
+        | TcpState::TimeWait
+        | TcpState::Closed => true,
+        _ => false,
+    }
+}
+
+/// Queues `buf` as a single record, or nothing if the send buffer can't take
+/// all of it yet.
+fn send_record(socket: &mut TcpSocket, buf: &[u8]) -> SyscallResult<Option<usize>> {
+    if RECORD_HEADER_LEN + buf.len() > socket.send_capacity() || buf.len() > u32::max_value() as usize {
+        return Err(SyscallError::new(syscall::EMSGSIZE));
+    }
+    if socket.send_capacity() - socket.send_queue() < RECORD_HEADER_LEN + buf.len() {
+        return Ok(None);
+    }
+    let mut header = [0; RECORD_HEADER_LEN];
+    NetworkEndian::write_u32(&mut header, buf.len() as u32);
+    socket.send_slice(&header).map_err(smoltcp_err_to_syscall)?;
+    socket.send_slice(buf).map_err(smoltcp_err_to_syscall)?;
+    Ok(Some(buf.len()))
+}
+
+/// Queues every segment of a vectored write, or nothing if the send buffer
+/// can't take all of them yet.
+fn send_segments(socket: &mut TcpSocket, segments: &[&[u8]]) -> SyscallResult<Option<usize>> {
+    let len = segments.iter().map(|segment| segment.len()).sum::<usize>();
+    if len > socket.send_capacity() {
+        return Err(SyscallError::new(syscall::EMSGSIZE));
+    }
+    if socket.send_capacity() - socket.send_queue() < len {
+        return Ok(None);
+    }
+    for segment in segments {
+        socket.send_slice(segment).map_err(smoltcp_err_to_syscall)?;
+    }
+    Ok(Some(len))
+}
+
+/// Throws away up to `count` received bytes, returning how many there were.
+fn discard(socket: &mut TcpSocket, count: usize) -> SyscallResult<usize> {
+    let mut discarded = 0;
+    // The receive buffer is a ring, so it may take two passes
+    while discarded < count && socket.can_recv() {
+        let rest = count - discarded;
+        discarded += socket
+            .recv(|data| {
+                let n = cmp::min(rest, data.len());
+                (n, n)
+            })
+            .map_err(smoltcp_err_to_syscall)?;
+    }
+    Ok(discarded)
+}
+
+/// Dequeues the next record once all of it arrived, the part that doesn't
+/// fit in `buf` being discarded. A record larger than the receive buffer
+/// fails with `EMSGSIZE` once, then gets thrown away as it arrives, `skip`
+/// counting what's left of it.
+fn recv_record(
+    socket: &mut TcpSocket,
+    skip: &mut usize,
+    buf: &mut [u8],
+) -> SyscallResult<Option<usize>> {
+    *skip -= discard(socket, *skip)?;
+    if *skip > 0 {
+        return Ok(None);
+    }
+    let mut header = [0; RECORD_HEADER_LEN];
+    if socket.recv_queue() < RECORD_HEADER_LEN
+        || socket.peek_slice(&mut header).map_err(smoltcp_err_to_syscall)? < RECORD_HEADER_LEN
+    {
+        return Ok(None);
+    }
+    let len = NetworkEndian::read_u32(&header) as usize;
+    if RECORD_HEADER_LEN + len > socket.recv_capacity() {
+        // Could never be received whole
+        socket.recv_slice(&mut header).map_err(smoltcp_err_to_syscall)?;
+        *skip = len - discard(socket, len)?;
+        return Err(SyscallError::new(syscall::EMSGSIZE));
+    }
+    if socket.recv_queue() < RECORD_HEADER_LEN + len {
+        return Ok(None);
+    }
+    socket.recv_slice(&mut header).map_err(smoltcp_err_to_syscall)?;
+    let count = cmp::min(len, buf.len());
+    let mut received = 0;
+    while received < count {
+        received += socket
+            .recv_slice(&mut buf[received..count])
+            .map_err(smoltcp_err_to_syscall)?;
+    }
+    discard(socket, len - count)?;
+    Ok(Some(count))
+}
+
+fn duration_to_timespec(duration: Duration) -> TimeSpec {
+    let micros = duration.total_micros();
+    TimeSpec {
+        tv_sec: (micros / 1_000_000) as i64,
+        tv_nsec: ((micros % 1_000_000) * 1_000) as i32,
+    }
+}
+
+fn timespec_to_duration(timespec: &TimeSpec) -> SyscallResult<Duration> {
+    if timespec.tv_sec < 0 || timespec.tv_nsec < 0 {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+    let micros = (timespec.tv_sec as u64)
+        .saturating_mul(1_000_000)
+        .saturating_add(timespec.tv_nsec as u64 / 1_000);
+    Ok(Duration::from_micros(micros))
+}
+
 impl<'a> SchemeSocket for TcpSocket<'a> {
     type SchemeDataT = PortSet;
-    type DataT = ();
-    type SettingT = ();
+    type DataT = TcpData;
+    type SettingT = TcpSetting;
 
     fn new_scheme_data() -> Self::SchemeDataT {
         PortSet::new(49_152u16, 65_535u16).expect("Wrong TCP port numbers")
     }
 
+    fn port_claimed(port_set: &Self::SchemeDataT, port: u16) -> Option<bool> {
+        Some(port_set.is_claimed(port))
+    }
+
     fn can_send(&self) -> bool {
         self.can_send()
     }
@@ -27,32 +211,191 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
         self.can_recv()
     }
 
-    fn may_recv(&self) -> bool {
-        self.may_recv()
+    fn may_recv(&self, _data: &Self::DataT) -> bool {
+        // A connect in progress will have data to read, it isn't at EOF
+        self.state() == TcpState::SynSent || self.may_recv()
+    }
+
+    fn hop_limit(&self) -> Option<u8> {
+        self.hop_limit()
+    }
+
+    fn set_hop_limit(&mut self, hop_limit: Option<u8>) {
+        self.set_hop_limit(hop_limit);
+    }
+
+    fn ip_flow(&self, _data: &Self::DataT) -> Option<Flow> {
+        Some(Flow::new(IpProtocol::Tcp, self.local_endpoint(), self.remote_endpoint()))
+    }
+
+    fn is_bound(&self) -> bool {
+        true
+    }
+
+    fn remote_addr(&self, _data: &Self::DataT) -> Option<IpAddress> {
+        let addr = self.remote_endpoint().addr;
+        if addr.is_specified() {
+            Some(addr)
+        } else {
+            None
+        }
     }
 
-    fn hop_limit(&self) -> u8 {
-        self.hop_limit().unwrap_or(64)
+    fn parse_setting(name: &str) -> Option<Self::SettingT> {
+        match name {
+            "user_timeout" => Some(TcpSetting::UserTimeout),
+            "window" => Some(TcpSetting::Window),
+            "rtt" => Some(TcpSetting::Rtt),
+            "cwnd" => Some(TcpSetting::CongestionWindow),
+            "initcwnd" => Some(TcpSetting::InitialCongestionWindow),
+            "fastopen" => Some(TcpSetting::FastOpen),
+            _ => None,
+        }
     }
 
-    fn set_hop_limit(&mut self, hop_limit: u8) {
-        self.set_hop_limit(Some(hop_limit));
+    fn setting_names() -> &'static [&'static str] {
+        &["user_timeout", "window", "fastopen"]
     }
 
     fn get_setting(
+        &self,
         _file: &SocketFile<Self::DataT>,
-        _setting: Self::SettingT,
-        _buf: &mut [u8],
+        setting: Self::SettingT,
+        buf: &mut [u8],
     ) -> SyscallResult<usize> {
-        Ok(0)
+        match setting {
+            TcpSetting::UserTimeout => match self.timeout() {
+                Some(timeout) => read_timespec(&duration_to_timespec(timeout), buf),
+                None => Ok(0),
+            },
+            TcpSetting::Window => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                let window = self.recv_capacity() - self.recv_queue();
+                LittleEndian::write_u32(&mut buf[0..4], window as u32);
+                Ok(4)
+            }
+            TcpSetting::Rtt
+            | TcpSetting::CongestionWindow
+            | TcpSetting::InitialCongestionWindow => Err(SyscallError::new(syscall::EOPNOTSUPP)),
+            TcpSetting::FastOpen => {
+                if let Some(fastopen) = buf.get_mut(0) {
+                    *fastopen = 0;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+        }
     }
 
     fn set_setting(
+        &mut self,
         _file: &mut SocketFile<Self::DataT>,
-        _setting: Self::SettingT,
-        _buf: &[u8],
+        setting: Self::SettingT,
+        buf: &[u8],
     ) -> SyscallResult<usize> {
-        Ok(0)
+        match setting {
+            TcpSetting::UserTimeout => {
+                let (timespec, count) = parse_timespec(buf)?;
+                let timeout = match timespec {
+                    Some(ref timespec) => Some(timespec_to_duration(timespec)?),
+                    None => None,
+                };
+                self.set_timeout(timeout);
+                Ok(count)
+            }
+            TcpSetting::Window | TcpSetting::Rtt | TcpSetting::CongestionWindow => {
+                Err(SyscallError::new(syscall::EINVAL))
+            }
+            TcpSetting::InitialCongestionWindow => match self.state() {
+                // Too late once the handshake is done
+                TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::SynReceived => {
+                    Err(SyscallError::new(syscall::EOPNOTSUPP))
+                }
+                _ => Err(SyscallError::new(syscall::EINVAL)),
+            },
+            TcpSetting::FastOpen => match buf.get(0) {
+                Some(&0) => Ok(1),
+                Some(_) => Err(SyscallError::new(syscall::EOPNOTSUPP)),
+                None => Err(SyscallError::new(syscall::EIO)),
+            },
+        }
+    }
+
+    fn take_error(&mut self) -> Option<SyscallError> {
+        // smoltcp drops a connection that exceeded its user timeout straight into
+        // CLOSED without forgetting the peer, unlike a regular close or a reset.
+        // Clearing the timeout makes sure the error is only reported once.
+        if self.state() == TcpState::Closed && self.timeout().is_some()
+            && self.remote_endpoint().is_specified()
+        {
+            self.set_timeout(None);
+            Some(SyscallError::new(syscall::ETIMEDOUT))
+        } else {
+            None
+        }
+    }
+
+    fn recv_available(&mut self) -> Option<usize> {
+        Some(self.recv_queue())
+    }
+
+    fn send_queued(&self) -> Option<usize> {
+        Some(self.send_queue())
+    }
+
+    fn recv_lowat_met(&self, recv_lowat: usize) -> bool {
+        // A watermark beyond the receive buffer could never be reached
+        let recv_lowat = cmp::min(recv_lowat, self.recv_capacity());
+        self.recv_queue() >= recv_lowat || peer_closed(self)
+    }
+
+    fn send_lowat_met(&self, send_lowat: usize) -> bool {
+        let send_lowat = cmp::min(send_lowat, self.send_capacity());
+        self.send_capacity() - self.send_queue() >= send_lowat
+    }
+
+    fn flush_recv(&mut self) -> usize {
+        let mut count = 0;
+        // The receive buffer is a ring, so it may take two passes
+        while self.can_recv() {
+            match self.recv(|data| (data.len(), data.len())) {
+                Ok(len) => count += len,
+                Err(_) => break,
+            }
+        }
+        count
+    }
+
+    fn shutdown(&mut self, abort: bool) {
+        if abort {
+            self.abort();
+        } else {
+            self.close();
+        }
+    }
+
+    fn is_flushed(&self) -> bool {
+        // Our FIN only gets acknowledged after everything queued before it
+        match self.state() {
+            TcpState::FinWait2 | TcpState::TimeWait | TcpState::Closed => true,
+            _ => false,
+        }
+    }
+
+    fn is_terminated(&self) -> bool {
+        // Reset, timed out or aborted, a stream has nothing left to read or write
+        self.state() == TcpState::Closed
+    }
+
+    fn is_connecting(&self) -> bool {
+        self.state() == TcpState::SynSent
+    }
+
+    fn take_datagram(_data: &mut Self::DataT) -> Option<(IpEndpoint, usize)> {
+        None
     }
 
     fn new_socket(
@@ -62,45 +405,86 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
         port_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<(SocketHandle, Self::DataT)> {
         trace!("TCP open {}", path);
-        let mut parts = path.split('/');
-        let remote_endpoint = parse_endpoint(parts.next().unwrap_or(""));
-        let mut local_endpoint = parse_endpoint(parts.next().unwrap_or(""));
+        let (path, query) = split_query(path);
+        // smoltcp advertises a window as large as the receive buffer
+        let rx_size = match query.get("rwnd") {
+            None => DEFAULT_BUFFER_SIZE,
+            Some(rwnd) => match rwnd.parse::<usize>() {
+                Ok(size) if size > 0 && size <= MAX_RECV_BUFFER_SIZE => size,
+                _ => return Err(SyscallError::new(syscall::EINVAL)),
+            },
+        };
+        let (mode, remote, local) = split_open_path(path);
+        let remote_endpoint = parse_endpoint(remote)?;
+        mode.check(&remote_endpoint)?;
+        let mut local_endpoint = parse_endpoint(local)?;
 
-        if local_endpoint.port > 0 && local_endpoint.port <= 1024 && uid != 0 {
-            return Err(SyscallError::new(syscall::EACCES));
-        }
+        check_bind_permission(local_endpoint.port, uid)?;
 
-        let rx_packets = vec![0; 0xffff];
-        let tx_packets = vec![0; 0xffff];
+        let rx_packets = vec![0; rx_size];
+        let tx_packets = vec![0; DEFAULT_BUFFER_SIZE];
         let rx_buffer = TcpSocketBuffer::new(rx_packets);
         let tx_buffer = TcpSocketBuffer::new(tx_packets);
         let socket = TcpSocket::new(rx_buffer, tx_buffer);
 
-        if local_endpoint.port == 0 {
-            local_endpoint.port = port_set
-                .get_port()
-                .ok_or_else(|| SyscallError::new(syscall::EINVAL))?;
-        } else if !port_set.claim_port(local_endpoint.port) {
+        // An ephemeral port is claimed once the socket is added, so that a
+        // port it can't take gets traded for the next one
+        let ephemeral = local_endpoint.port == 0;
+        if !ephemeral && !port_set.claim_port(local_endpoint.addr, local_endpoint.port) {
             return Err(SyscallError::new(syscall::EADDRINUSE));
         }
 
-        let socket_handle = iface.add_socket(socket);
-
-        let (tcp_socket, cx) = iface.get_socket_and_context::<TcpSocket>(socket_handle);
+        let socket_handle = add_socket(iface, socket).map_err(|err| {
+            if !ephemeral {
+                port_set.release_port(local_endpoint.addr, local_endpoint.port);
+            }
+            err
+        })?;
 
-        if remote_endpoint.is_specified() {
-            trace!("Connecting tcp {} {}", local_endpoint, remote_endpoint);
-            tcp_socket
-                .connect(cx, remote_endpoint, local_endpoint)
-                .expect("Can't connect tcp socket ");
+        let addr = local_endpoint.addr;
+        let mut set_up = |port| {
+            let local_endpoint = IpEndpoint::new(addr, port);
+            let (tcp_socket, cx) = iface.get_socket_and_context::<TcpSocket>(socket_handle);
+            if remote_endpoint.is_specified() {
+                trace!("Connecting tcp {} {}", local_endpoint, remote_endpoint);
+                tcp_socket.connect(cx, remote_endpoint, local_endpoint)
+            } else {
+                trace!("Listening tcp {}", local_endpoint);
+                tcp_socket.listen(local_endpoint)
+            }
+        };
+        let result = if ephemeral {
+            port_set
+                .bind_ephemeral(addr, set_up)
+                .map(|port| local_endpoint.port = port)
         } else {
-            trace!("Listening tcp {}", local_endpoint);
-            tcp_socket
-                .listen(local_endpoint)
-                .expect("Can't listen on local endpoint");
+            set_up(local_endpoint.port).map_err(Some)
+        };
+        // Such as a remote port of 0 or no source address to reach the peer,
+        // which another local port doesn't fix either
+        if let Err(err) = result {
+            trace!("TCP can't set up socket {}", socket_handle);
+            iface.remove_socket(socket_handle);
+            if !ephemeral {
+                port_set.release_port(local_endpoint.addr, local_endpoint.port);
+            }
+            return Err(match err {
+                Some(err) => smoltcp_err_to_syscall(err),
+                None => SyscallError::new(syscall::EINVAL),
+            });
         }
 
-        Ok((socket_handle, ()))
+        Ok((
+            socket_handle,
+            TcpData {
+                bound_addr: local_endpoint.addr,
+                port: local_endpoint.port,
+                remote_endpoint,
+                record: false,
+                skip: 0,
+                vectored: false,
+            },
+        ))
     }
 
     fn close_file(
@@ -108,8 +492,8 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
         file: &SchemeFile<Self>,
         port_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<()> {
-        if let SchemeFile::Socket(_) = *file {
-            port_set.release_port(self.local_endpoint().port);
+        if let SchemeFile::Socket(ref file) = *file {
+            port_set.release_port(file.data.bound_addr, file.data.port);
         }
         Ok(())
     }
@@ -117,13 +501,34 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
     fn write_buf(
         &mut self,
         file: &mut SocketFile<Self::DataT>,
+        _mtu: usize,
         buf: &[u8],
     ) -> SyscallResult<Option<usize>> {
         if !self.is_active() {
             Err(SyscallError::new(syscall::ENOTCONN))
+        } else if file.data.record || file.data.vectored {
+            let sent = if !file.data.vectored {
+                send_record(self, buf)?
+            } else if file.data.record {
+                // The segments make up a single record
+                send_record(self, &parse_segments(buf)?.concat())?
+            } else {
+                send_segments(self, &parse_segments(buf)?)?
+            };
+            match sent {
+                // The whole vectored buffer was consumed
+                Some(_) if file.data.vectored => Ok(Some(buf.len())),
+                Some(count) => Ok(Some(count)),
+                None if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK => {
+                    Err(SyscallError::new(syscall::EAGAIN))
+                }
+                None => Ok(None), // internally scheduled to re-write
+            }
         } else if self.can_send() {
-            self.send_slice(buf).expect("Can't send slice");
-            Ok(Some(buf.len()))
+            // Only as much as fits in the send buffer gets queued, callers
+            // have to write the rest again
+            let count = self.send_slice(buf).map_err(smoltcp_err_to_syscall)?;
+            Ok(Some(count))
         } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
             Err(SyscallError::new(syscall::EAGAIN))
         } else {
@@ -136,12 +541,30 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
         file: &mut SocketFile<Self::DataT>,
         buf: &mut [u8],
     ) -> SyscallResult<Option<usize>> {
-        if !self.is_active() {
+        // Zero bytes only ever mean that the peer closed and everything it
+        // sent was read, a connect in progress blocks like no data yet
+        if self.state() == TcpState::Listen {
             Err(SyscallError::new(syscall::ENOTCONN))
-        } else if self.can_recv() {
-            let length = self.recv_slice(buf).expect("Can't receive slice");
+        } else if self.state() == TcpState::Closed {
+            // Reset or aborted, whatever was still buffered is lost
+            Err(SyscallError::new(syscall::ECONNRESET))
+        } else if file.data.record {
+            match recv_record(self, &mut file.data.skip, buf)? {
+                Some(count) => Ok(Some(count)),
+                // A record cut short by the peer closing is dropped
+                None if peer_closed(self) => Ok(Some(0)),
+                None if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK => {
+                    Err(SyscallError::new(syscall::EAGAIN))
+                }
+                None => Ok(None), // internally scheduled to re-read
+            }
+        } else if self.can_recv()
+            && (file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK
+                || self.recv_lowat_met(file.recv_lowat))
+        {
+            let length = self.recv_slice(buf).map_err(smoltcp_err_to_syscall)?;
             Ok(Some(length))
-        } else if !self.may_recv() {
+        } else if peer_closed(self) {
             Ok(Some(0))
         } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
             Err(SyscallError::new(syscall::EAGAIN))
@@ -150,21 +573,36 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
         }
     }
 
+    fn fsync(&mut self, file: &mut SocketFile<Self::DataT>) -> SyscallResult<Option<usize>> {
+        if self.send_queue() == 0 {
+            Ok(Some(0))
+        } else if !self.is_active() {
+            Err(SyscallError::new(syscall::ENOTCONN))
+        } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+            Err(SyscallError::new(syscall::EAGAIN))
+        } else {
+            Ok(None) // internally scheduled to re-check once the peer acked
+        }
+    }
+
     fn dup(
         iface: &mut SmolnetInterface,
         file: &mut SchemeFile<Self>,
         path: &str,
+        _query: &BTreeMap<&str, &str>,
         port_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<DupResult<Self>> {
         let socket_handle = file.socket_handle();
 
-        let (is_active, local_endpoint) = {
+        let (is_active, local_endpoint, rx_size) = {
             let socket = iface.get_socket::<TcpSocket>(socket_handle);
-            (socket.is_active(), socket.local_endpoint())
+            (socket.is_active(), socket.local_endpoint(), socket.recv_capacity())
         };
 
+        let mut bound_addr = local_endpoint.addr;
+        let mut port = local_endpoint.port;
         let file = match path {
-            "listen" => if let SchemeFile::Socket(ref tcp_handle) = *file {
+            "listen" | "accept" => if let SchemeFile::Socket(ref tcp_handle) = *file {
                 if !is_active {
                     if tcp_handle.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
                         return Err(SyscallError::new(syscall::EAGAIN));
@@ -173,52 +611,283 @@ impl<'a> SchemeSocket for TcpSocket<'a> {
                     }
                 }
                 trace!("TCP creating new listening socket");
-                let new_handle = SchemeFile::Socket(tcp_handle.clone_with_data(()));
+                let data = tcp_handle.data;
+                let new_handle = SchemeFile::Socket(tcp_handle.clone_with_data(data));
 
-                let rx_packets = vec![0; 0xffff];
-                let tx_packets = vec![0; 0xffff];
+                // The next listener keeps the receive window asked for at open
+                let rx_packets = vec![0; rx_size];
+                let tx_packets = vec![0; DEFAULT_BUFFER_SIZE];
                 let rx_buffer = TcpSocketBuffer::new(rx_packets);
                 let tx_buffer = TcpSocketBuffer::new(tx_packets);
                 let socket = TcpSocket::new(rx_buffer, tx_buffer);
-                let new_socket_handle = iface.add_socket(socket);
+                let new_socket_handle = add_socket(iface, socket)?;
+                let result = iface
+                    .get_socket::<TcpSocket>(new_socket_handle)
+                    .listen(local_endpoint);
+                if let Err(err) = result {
+                    iface.remove_socket(new_socket_handle);
+                    return Err(smoltcp_err_to_syscall(err));
+                }
+                port_set.acquire_port(data.bound_addr, local_endpoint.port);
+                return Ok(Some((new_handle, Some((new_socket_handle, data)))));
+            } else {
+                return Err(SyscallError::new(syscall::EBADF));
+            },
+            // Starts a new connection to the peer given at open, from the same
+            // local endpoint. The fd keeps its number and moves to the new
+            // connection, while the returned fd holds the old socket until
+            // it's closed, so callers close it right away.
+            "reconnect" => if let SchemeFile::Socket(ref tcp_handle) = *file {
+                let data = tcp_handle.data;
+                if !data.remote_endpoint.is_specified() {
+                    return Err(SyscallError::new(syscall::EINVAL));
+                }
                 {
-                    let tcp_socket = iface.get_socket::<TcpSocket>(new_socket_handle);
-                    tcp_socket
-                        .listen(local_endpoint)
-                        .expect("Can't listen on local endpoint");
+                    let socket = iface.get_socket::<TcpSocket>(socket_handle);
+                    match socket.state() {
+                        TcpState::Closed => (),
+                        // Once the peer closed, nothing may still match the
+                        // connection being replaced
+                        TcpState::CloseWait
+                        | TcpState::LastAck
+                        | TcpState::Closing
+                        | TcpState::TimeWait => socket.abort(),
+                        _ => return Err(SyscallError::new(syscall::EISCONN)),
+                    }
                 }
-                port_set.acquire_port(local_endpoint.port);
-                return Ok(Some((new_handle, Some((new_socket_handle, ())))));
+                trace!("TCP reconnecting to {}", data.remote_endpoint);
+                let new_handle = SchemeFile::Socket(tcp_handle.clone_with_data(data));
+
+                let rx_packets = vec![0; rx_size];
+                let tx_packets = vec![0; DEFAULT_BUFFER_SIZE];
+                let rx_buffer = TcpSocketBuffer::new(rx_packets);
+                let tx_buffer = TcpSocketBuffer::new(tx_packets);
+                let socket = TcpSocket::new(rx_buffer, tx_buffer);
+                let new_socket_handle = add_socket(iface, socket)?;
+                let result = {
+                    let (tcp_socket, cx) =
+                        iface.get_socket_and_context::<TcpSocket>(new_socket_handle);
+                    let local_endpoint = IpEndpoint::new(data.bound_addr, data.port);
+                    tcp_socket.connect(cx, data.remote_endpoint, local_endpoint)
+                };
+                if let Err(err) = result {
+                    iface.remove_socket(new_socket_handle);
+                    return Err(smoltcp_err_to_syscall(err));
+                }
+                port_set.acquire_port(data.bound_addr, data.port);
+                // The fd keeps its number and port but now refers to the new
+                // connection, the returned one to the old socket
+                return Ok(Some((new_handle, Some((new_socket_handle, data)))));
             } else {
                 return Err(SyscallError::new(syscall::EBADF));
             },
+            "writev" => match *file {
+                SchemeFile::Socket(ref tcp_handle) => {
+                    bound_addr = tcp_handle.data.bound_addr;
+                    port = tcp_handle.data.port;
+                    let mut data = tcp_handle.data;
+                    data.vectored = true;
+                    SchemeFile::Socket(tcp_handle.clone_with_data(data))
+                }
+                _ => return Err(SyscallError::new(syscall::EBADF)),
+            },
+            "connect" => match *file {
+                SchemeFile::Socket(ref tcp_handle) => {
+                    match iface.get_socket::<TcpSocket>(socket_handle).state() {
+                        TcpState::SynSent | TcpState::SynReceived => {
+                            if tcp_handle.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+                                return Err(SyscallError::new(syscall::EINPROGRESS));
+                            } else {
+                                return Ok(None);
+                            }
+                        }
+                        TcpState::Listen => return Err(SyscallError::new(syscall::EINVAL)),
+                        // Reset before the handshake completed
+                        TcpState::Closed => return Err(SyscallError::new(syscall::ECONNREFUSED)),
+                        _ => {}
+                    }
+                    bound_addr = tcp_handle.data.bound_addr;
+                    port = tcp_handle.data.port;
+                    SchemeFile::Socket(tcp_handle.clone_with_data(tcp_handle.data))
+                }
+                _ => return Err(SyscallError::new(syscall::EBADF)),
+            },
+            "record" => match *file {
+                SchemeFile::Socket(ref tcp_handle) => {
+                    bound_addr = tcp_handle.data.bound_addr;
+                    port = tcp_handle.data.port;
+                    let mut data = tcp_handle.data;
+                    data.record = true;
+                    SchemeFile::Socket(tcp_handle.clone_with_data(data))
+                }
+                _ => return Err(SyscallError::new(syscall::EBADF)),
+            },
             _ => {
                 trace!("TCP dup unknown {}", path);
                 if let SchemeFile::Socket(ref tcp_handle) = *file {
-                    SchemeFile::Socket(tcp_handle.clone_with_data(()))
+                    bound_addr = tcp_handle.data.bound_addr;
+                    port = tcp_handle.data.port;
+                    SchemeFile::Socket(tcp_handle.clone_with_data(tcp_handle.data))
                 } else {
-                    SchemeFile::Socket(SocketFile::new_with_data(socket_handle, ()))
+                    SchemeFile::Socket(SocketFile::new_with_data(
+                        socket_handle,
+                        TcpData {
+                            bound_addr,
+                            port,
+                            remote_endpoint: IpEndpoint::default(),
+                            record: false,
+                            skip: 0,
+                            vectored: false,
+                        },
+                    ))
                 }
             }
         };
 
         if let SchemeFile::Socket(_) = file {
-            port_set.acquire_port(local_endpoint.port);
+            port_set.acquire_port(bound_addr, port);
         }
 
         Ok(Some((file, None)))
     }
 
+    fn describe(&self, _file: &SocketFile<Self::DataT>) -> String {
+        format!(
+            "tcp {} {} {}",
+            self.local_endpoint(),
+            self.remote_endpoint(),
+            self.state()
+        )
+    }
+
     fn fpath(&self, _: &SchemeFile<Self>, buf: &mut [u8]) -> SyscallResult<usize> {
         let path = format!("tcp:{}/{}", self.remote_endpoint(), self.local_endpoint());
-        let path = path.as_bytes();
+        write_path(&path, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use smoltcp::socket::Socket;
+    use syscall::{self, SchemeBlockMut};
+
+    use super::super::harness::Harness;
+    use super::TcpScheme;
+
+    const FLAGS: usize = syscall::O_RDWR | syscall::O_NONBLOCK;
+
+    /// A connection on the loopback network to a listener on `port` opened
+    /// with `query`, as the connecting fd and the accepted one.
+    fn connection(
+        harness: &Harness,
+        scheme: &mut TcpScheme,
+        port: u16,
+        query: &str,
+    ) -> (usize, usize) {
+        let listen = format!("/127.0.0.1:{}{}", port, query);
+        let listener = scheme.open(&listen, FLAGS, 0, 0).unwrap().unwrap();
+        let connect = format!("127.0.0.1:{}", port);
+        let client = scheme.open(&connect, FLAGS, 0, 0).unwrap().unwrap();
+        harness.poll();
+        let server = scheme.dup(listener, b"accept").unwrap().unwrap();
+        (client, server)
+    }
 
-        let mut i = 0;
-        while i < buf.len() && i < path.len() {
-            buf[i] = path[i];
-            i += 1;
+    /// Reads `fd` as soon as it stops failing with `EAGAIN`, leaving delayed
+    /// ACKs and window updates the time to go out in between.
+    fn read_ready(
+        harness: &Harness,
+        scheme: &mut TcpScheme,
+        fd: usize,
+        buf: &mut [u8],
+    ) -> syscall::Result<Option<usize>> {
+        for _ in 0..50 {
+            harness.poll();
+            match scheme.read(fd, buf) {
+                Err(ref err) if err.errno == syscall::EAGAIN => (),
+                result => return result,
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("fd {} never got ready to read", fd);
+    }
+
+    #[test]
+    fn oversized_record_is_skipped() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (TcpScheme, _) = harness.scheme();
+        let (client, server) = connection(&harness, &mut scheme, 8000, "?rwnd=1024");
+        let client = scheme.dup(client, b"record").unwrap().unwrap();
+        let server = scheme.dup(server, b"record").unwrap().unwrap();
+
+        assert_eq!(scheme.write(client, &[7; 2000]).unwrap(), Some(2000));
+        assert_eq!(scheme.write(client, b"ping").unwrap(), Some(4));
+
+        let mut buf = [0; 16];
+        assert_eq!(
+            read_ready(&harness, &mut scheme, server, &mut buf).unwrap_err().errno,
+            syscall::EMSGSIZE
+        );
+        // The stream moves on to the next record
+        assert_eq!(read_ready(&harness, &mut scheme, server, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"ping");
+    }
+
+    #[test]
+    fn reset_fails_pending_read() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (TcpScheme, _) = harness.scheme();
+        let (client, server) = connection(&harness, &mut scheme, 8010, "");
+
+        let mut buf = [0; 16];
+        assert_eq!(scheme.read(client, &mut buf).unwrap_err().errno, syscall::EAGAIN);
+
+        scheme.dup(server, b"abort").unwrap().unwrap();
+        assert_eq!(
+            read_ready(&harness, &mut scheme, client, &mut buf).unwrap_err().errno,
+            syscall::ECONNRESET
+        );
+    }
+
+    #[test]
+    fn large_write_is_short() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (TcpScheme, _) = harness.scheme();
+        let (client, _server) = connection(&harness, &mut scheme, 8020, "");
+
+        let data = vec![0; 1 << 20];
+        let count = scheme.write(client, &data).unwrap().unwrap();
+        assert!(count > 0 && count <= super::DEFAULT_BUFFER_SIZE);
+        // The send buffer is full until the peer acknowledges some of it
+        assert_eq!(scheme.write(client, &data).unwrap_err().errno, syscall::EAGAIN);
+    }
+
+    #[test]
+    fn peer_close_reads_end_of_file() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (TcpScheme, _) = harness.scheme();
+        let (client, server) = connection(&harness, &mut scheme, 8030, "");
+
+        let mut buf = [0; 16];
+        assert_eq!(scheme.read(server, &mut buf).unwrap_err().errno, syscall::EAGAIN);
+
+        assert_eq!(scheme.write(client, b"data").unwrap(), Some(4));
+        // Closing the fd drops the socket right away, a FIN needs a close of
+        // the socket itself
+        for (_, socket) in harness.iface.borrow_mut().sockets_mut() {
+            if let Socket::Tcp(ref mut socket) = *socket {
+                if socket.remote_endpoint().port == 8030 {
+                    socket.close();
+                }
+            }
         }
 
-        Ok(i)
+        assert_eq!(read_ready(&harness, &mut scheme, server, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"data");
+        assert_eq!(read_ready(&harness, &mut scheme, server, &mut buf).unwrap(), Some(0));
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(0));
     }
 }
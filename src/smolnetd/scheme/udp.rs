@@ -1,26 +1,191 @@
+use byteorder::{ByteOrder, LittleEndian};
+use smoltcp;
 use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
 use smoltcp::iface::{SocketHandle};
-use smoltcp::wire::IpEndpoint;
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol, ETHERNET_HEADER_LEN, IPV4_HEADER_LEN,
+                    UDP_HEADER_LEN};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::str;
 use syscall;
 use syscall::{Error as SyscallError, Result as SyscallResult};
 
-use super::socket::{DupResult, SchemeFile, SchemeSocket, SocketFile, SocketScheme};
-use super::{parse_endpoint, Smolnetd, SmolnetInterface};
-use device::NetworkDevice;
+use super::socket::{add_socket, check_bind_permission, parse_segments, smoltcp_err_to_syscall,
+                    socket_buffer_size, write_path, DupResult, SchemeFile, SchemeSocket,
+                    SocketFile, SocketScheme};
+use super::{parse_endpoint, parse_local_endpoint, split_open_path, split_query,
+            SmolnetInterface};
+use device::Flow;
 use port_set::PortSet;
 
 pub type UdpScheme = SocketScheme<UdpSocket<'static>>;
 
+/// Largest payload that fits in a single frame, as the stack doesn't fragment.
+fn max_datagram_size(mtu: usize) -> usize {
+    mtu - ETHERNET_HEADER_LEN - IPV4_HEADER_LEN - UDP_HEADER_LEN
+}
+
+/// Length and source framing every datagram of a batched read
+const BATCH_HEADER_LEN: usize = 10;
+
+/// Drops the datagrams queued ahead that come from peers a connected socket
+/// doesn't talk to.
+fn drop_foreign(socket: &mut UdpSocket, data: &UdpData) {
+    if !data.remote_endpoint.is_specified() {
+        return;
+    }
+    loop {
+        let source = match socket.peek() {
+            Ok((_, &source)) => source,
+            Err(_) => break,
+        };
+        if data.accepts_from(source) {
+            break;
+        }
+        trace!("UDP drop datagram from {}", source);
+        let _ = socket.recv();
+    }
+}
+
+/// Sends `buf` as consecutive datagrams of at most `segment_size` bytes, as
+/// many as the send buffer takes. Unlike a single datagram this isn't atomic:
+/// a short count means only the leading part of `buf` went out.
+fn send_split(
+    socket: &mut UdpSocket,
+    buf: &[u8],
+    segment_size: usize,
+    remote_endpoint: IpEndpoint,
+) -> SyscallResult<usize> {
+    let mut sent = 0;
+    while sent < buf.len() && socket.can_send() {
+        let len = cmp::min(segment_size, buf.len() - sent);
+        match socket.send(len, remote_endpoint) {
+            Ok(payload) => payload.copy_from_slice(&buf[sent..sent + len]),
+            // Not enough payload space left for the next datagram yet
+            Err(smoltcp::Error::Exhausted) => break,
+            Err(err) if sent == 0 => return Err(smoltcp_err_to_syscall(err)),
+            Err(_) => break,
+        }
+        sent += len;
+    }
+    Ok(sent)
+}
+
+/// Fills `buf` with as many whole datagrams as fit, each behind a u32 length,
+/// the IPv4 source address and the u16 source port, lengths and ports being
+/// little-endian. Only a first datagram larger than the buffer gets truncated.
+fn recv_batch(socket: &mut UdpSocket, data: &UdpData, buf: &mut [u8]) -> SyscallResult<usize> {
+    if buf.len() < BATCH_HEADER_LEN {
+        return Err(SyscallError::new(syscall::EINVAL));
+    }
+    let mut offset = 0;
+    loop {
+        drop_foreign(socket, data);
+        let len = match socket.peek() {
+            Ok((payload, _)) => payload.len(),
+            Err(_) => break,
+        };
+        let space = buf.len() - offset;
+        if space < BATCH_HEADER_LEN || (offset > 0 && space < BATCH_HEADER_LEN + len) {
+            break;
+        }
+        let (payload, source) = socket.recv().map_err(smoltcp_err_to_syscall)?;
+        let len = cmp::min(payload.len(), space - BATCH_HEADER_LEN);
+        {
+            let header = &mut buf[offset..offset + BATCH_HEADER_LEN];
+            LittleEndian::write_u32(&mut header[0..4], len as u32);
+            match source.addr {
+                IpAddress::Ipv4(addr) => header[4..8].copy_from_slice(addr.as_bytes()),
+                _ => header[4..8].copy_from_slice(&[0; 4]),
+            }
+            LittleEndian::write_u16(&mut header[8..10], source.port);
+        }
+        offset += BATCH_HEADER_LEN;
+        buf[offset..offset + len].copy_from_slice(&payload[..len]);
+        offset += len;
+    }
+    Ok(offset)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum UdpSetting {
+    /// Stops delivering datagrams, reads return end of file from then on
+    ShutdownRead,
+    /// Remote endpoint of the socket fd as `host:port` text, writing one
+    /// retargets it in place and an unspecified one disconnects it
+    Connect,
+    /// Local endpoint of the socket fd as `host:port` text, including the
+    /// ephemeral port picked at open time and a port reserved for a deferred bind
+    LocalAddr,
+    /// Whether the datagram returned by the last read of the socket fd was
+    /// cut to fit the buffer, as a single byte, like `MSG_TRUNC`
+    Truncated,
+    /// Largest datagram a write of the socket fd gets split into, as a
+    /// little-endian u32, zero sending every write as a single datagram
+    Segment,
+}
+
+#[derive(Copy, Clone)]
+pub struct UdpData {
+    remote_endpoint: IpEndpoint,
+    /// Local endpoint reserved at open time but only bound on the first send,
+    /// which signals `EVENT_WRITE` once more on every fd of the socket
+    deferred_endpoint: Option<IpEndpoint>,
+    read_shutdown: bool,
+    /// Writes carry a list of segments sent together as one datagram
+    vectored: bool,
+    /// Reads return as many framed datagrams as fit in the buffer
+    batched: bool,
+    /// The last read lost the end of a datagram
+    truncated: bool,
+    /// Source and full length of the first datagram returned by the last
+    /// read, which its receive time is looked up by
+    last_datagram: Option<(IpEndpoint, usize)>,
+    /// Writes are split into datagrams of at most this many bytes, bounded by
+    /// the MTU, when not zero
+    segment_size: usize,
+}
+
+impl UdpData {
+    fn new(remote_endpoint: IpEndpoint) -> UdpData {
+        UdpData {
+            remote_endpoint,
+            deferred_endpoint: None,
+            read_shutdown: false,
+            vectored: false,
+            batched: false,
+            truncated: false,
+            last_datagram: None,
+            segment_size: 0,
+        }
+    }
+
+    fn accepts_from(&self, source: IpEndpoint) -> bool {
+        source.addr == self.remote_endpoint.addr
+            && (self.remote_endpoint.port == 0 || source.port == self.remote_endpoint.port)
+    }
+
+    fn local_endpoint(&self, socket: &UdpSocket) -> IpEndpoint {
+        match self.deferred_endpoint {
+            Some(endpoint) if !socket.is_open() => endpoint,
+            _ => socket.endpoint(),
+        }
+    }
+}
+
 impl<'a> SchemeSocket for UdpSocket<'a> {
     type SchemeDataT = PortSet;
-    type DataT = IpEndpoint;
-    type SettingT = ();
+    type DataT = UdpData;
+    type SettingT = UdpSetting;
 
     fn new_scheme_data() -> Self::SchemeDataT {
         PortSet::new(49_152u16, 65_535u16).expect("Wrong UDP port numbers")
     }
 
+    fn port_claimed(port_set: &Self::SchemeDataT, port: u16) -> Option<bool> {
+        Some(port_set.is_claimed(port))
+    }
+
     fn can_send(&self) -> bool {
         self.can_send()
     }
@@ -29,32 +194,174 @@ impl<'a> SchemeSocket for UdpSocket<'a> {
         self.can_recv()
     }
 
-    fn may_recv(&self) -> bool {
-        true
+    fn may_recv(&self, data: &Self::DataT) -> bool {
+        !data.read_shutdown
+    }
+
+    fn hop_limit(&self) -> Option<u8> {
+        self.hop_limit()
     }
 
-    fn hop_limit(&self) -> u8 {
-        self.hop_limit().unwrap_or(64)
+    fn set_hop_limit(&mut self, hop_limit: Option<u8>) {
+        self.set_hop_limit(hop_limit);
     }
 
-    fn set_hop_limit(&mut self, hop_limit: u8) {
-        self.set_hop_limit(Some(hop_limit));
+    fn ip_flow(&self, data: &Self::DataT) -> Option<Flow> {
+        // Fds of one socket may each talk to another peer, and the port of
+        // a deferred bind is only known from the fd
+        Some(Flow::new(IpProtocol::Udp, data.local_endpoint(self), IpEndpoint::default()))
+    }
+
+    fn is_bound(&self) -> bool {
+        self.is_open()
+    }
+
+    fn remote_addr(&self, data: &Self::DataT) -> Option<IpAddress> {
+        if data.remote_endpoint.addr.is_specified() {
+            Some(data.remote_endpoint.addr)
+        } else {
+            None
+        }
+    }
+
+    fn parse_setting(name: &str) -> Option<Self::SettingT> {
+        match name {
+            "shutdown_read" => Some(UdpSetting::ShutdownRead),
+            "connect" => Some(UdpSetting::Connect),
+            "local_addr" => Some(UdpSetting::LocalAddr),
+            "truncated" => Some(UdpSetting::Truncated),
+            "segment" => Some(UdpSetting::Segment),
+            _ => None,
+        }
+    }
+
+    fn setting_names() -> &'static [&'static str] {
+        &["shutdown_read", "connect", "local_addr", "truncated", "segment"]
     }
 
     fn get_setting(
-        _file: &SocketFile<Self::DataT>,
-        _setting: Self::SettingT,
-        _buf: &mut [u8],
+        &self,
+        file: &SocketFile<Self::DataT>,
+        setting: Self::SettingT,
+        buf: &mut [u8],
     ) -> SyscallResult<usize> {
-        Ok(0)
+        match setting {
+            UdpSetting::ShutdownRead => {
+                if let Some(shutdown) = buf.get_mut(0) {
+                    *shutdown = file.data.read_shutdown as u8;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            UdpSetting::Connect => write_path(&file.data.remote_endpoint.to_string(), buf),
+            UdpSetting::LocalAddr => write_path(&file.data.local_endpoint(self).to_string(), buf),
+            UdpSetting::Truncated => {
+                if let Some(truncated) = buf.get_mut(0) {
+                    *truncated = file.data.truncated as u8;
+                    Ok(1)
+                } else {
+                    Err(SyscallError::new(syscall::EIO))
+                }
+            }
+            UdpSetting::Segment => {
+                if buf.len() < 4 {
+                    return Ok(0);
+                }
+                LittleEndian::write_u32(&mut buf[0..4], file.data.segment_size as u32);
+                Ok(4)
+            }
+        }
     }
 
     fn set_setting(
-        _file: &mut SocketFile<Self::DataT>,
-        _setting: Self::SettingT,
-        _buf: &[u8],
+        &mut self,
+        file: &mut SocketFile<Self::DataT>,
+        setting: Self::SettingT,
+        buf: &[u8],
     ) -> SyscallResult<usize> {
-        Ok(0)
+        match setting {
+            UdpSetting::ShutdownRead => match buf.get(0) {
+                // A shut down read side can't be reopened
+                Some(&0) => Err(SyscallError::new(syscall::EINVAL)),
+                Some(_) => {
+                    file.data.read_shutdown = true;
+                    Ok(1)
+                }
+                None => Err(SyscallError::new(syscall::EIO)),
+            },
+            UdpSetting::Connect => {
+                let endpoint = str::from_utf8(buf)
+                    .map_err(|_| SyscallError::new(syscall::EINVAL))?;
+                file.data.remote_endpoint = parse_endpoint(endpoint.trim())?;
+                Ok(buf.len())
+            }
+            // Rebinding would have to move the port reservation
+            UdpSetting::LocalAddr | UdpSetting::Truncated => {
+                Err(SyscallError::new(syscall::EINVAL))
+            }
+            UdpSetting::Segment => {
+                if buf.len() < 4 {
+                    return Err(SyscallError::new(syscall::EIO));
+                }
+                let segment_size = LittleEndian::read_u32(&buf[0..4]);
+                if segment_size > u32::from(::std::u16::MAX) {
+                    return Err(SyscallError::new(syscall::EINVAL));
+                }
+                file.data.segment_size = segment_size as usize;
+                Ok(4)
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<SyscallError> {
+        None
+    }
+
+    fn recv_available(&mut self) -> Option<usize> {
+        match self.peek() {
+            Ok((payload, _)) => Some(payload.len()),
+            Err(_) => Some(0),
+        }
+    }
+
+    fn send_queued(&self) -> Option<usize> {
+        // smoltcp doesn't expose how full the UDP transmit buffer is
+        None
+    }
+
+    fn recv_lowat_met(&self, _recv_lowat: usize) -> bool {
+        true
+    }
+
+    fn send_lowat_met(&self, _send_lowat: usize) -> bool {
+        true
+    }
+
+    fn flush_recv(&mut self) -> usize {
+        let mut count = 0;
+        while let Ok((payload, _)) = self.recv() {
+            count += payload.len();
+        }
+        count
+    }
+
+    fn shutdown(&mut self, _abort: bool) {}
+
+    fn is_flushed(&self) -> bool {
+        true
+    }
+
+    fn is_terminated(&self) -> bool {
+        false
+    }
+
+    fn is_connecting(&self) -> bool {
+        false
+    }
+
+    fn take_datagram(data: &mut Self::DataT) -> Option<(IpEndpoint, usize)> {
+        data.last_datagram.take()
     }
 
     fn new_socket(
@@ -64,42 +371,99 @@ impl<'a> SchemeSocket for UdpSocket<'a> {
         port_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<(SocketHandle, Self::DataT)> {
         trace!("UDP open {}", path);
-        let mut parts = path.split('/');
-        let remote_endpoint = parse_endpoint(parts.next().unwrap_or(""));
-        let mut local_endpoint = parse_endpoint(parts.next().unwrap_or(""));
+        let (path, query) = split_query(path);
+        let defer_bind = match query.get("deferbind") {
+            None | Some(&"0") => false,
+            Some(&"1") => true,
+            Some(_) => return Err(SyscallError::new(syscall::EINVAL)),
+        };
+        let (mode, remote, local) = split_open_path(path);
+        let remote_endpoint = parse_endpoint(remote)?;
+        mode.check(&remote_endpoint)?;
+        let (mut local_endpoint, port_range) = parse_local_endpoint(local)?;
 
-        if local_endpoint.port > 0 && local_endpoint.port <= 1024 && uid != 0 {
-            return Err(SyscallError::new(syscall::EACCES));
+        check_bind_permission(local_endpoint.port, uid)?;
+        if let Some((lo, _)) = port_range {
+            check_bind_permission(lo, uid)?;
         }
 
+        // A wildcard address binds to all interfaces, a specific one must be ours
+        if local_endpoint.addr.is_specified() && !iface.has_ip_addr(local_endpoint.addr) {
+            return Err(SyscallError::new(syscall::EADDRNOTAVAIL));
+        }
+
+        let mtu = iface.device().get_ref().mtu();
+        let buffer_size = socket_buffer_size();
         let rx_buffer = UdpSocketBuffer::new(
-            vec![UdpPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-            vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+            vec![UdpPacketMetadata::EMPTY; buffer_size],
+            vec![0; mtu * buffer_size],
         );
         let tx_buffer = UdpSocketBuffer::new(
-            vec![UdpPacketMetadata::EMPTY; Smolnetd::SOCKET_BUFFER_SIZE],
-            vec![0; NetworkDevice::MTU * Smolnetd::SOCKET_BUFFER_SIZE],
+            vec![UdpPacketMetadata::EMPTY; buffer_size],
+            vec![0; mtu * buffer_size],
         );
         let udp_socket = UdpSocket::new(rx_buffer, tx_buffer);
 
-        if local_endpoint.port == 0 {
+        let ephemeral = port_range.is_none() && local_endpoint.port == 0 && !defer_bind;
+        if let Some((lo, hi)) = port_range {
             local_endpoint.port = port_set
-                .get_port()
+                .get_port_in_range(local_endpoint.addr, lo, hi)
+                .ok_or_else(|| SyscallError::new(syscall::EADDRINUSE))?;
+        } else if ephemeral {
+            // Claimed once the socket is added, so that a port it can't bind
+            // to gets traded for the next one
+        } else if local_endpoint.port == 0 {
+            local_endpoint.port = port_set
+                .get_port(local_endpoint.addr)
                 .ok_or_else(|| SyscallError::new(syscall::EINVAL))?;
-        } else if !port_set.claim_port(local_endpoint.port) {
+        } else if !port_set.claim_port(local_endpoint.addr, local_endpoint.port) {
             return Err(SyscallError::new(syscall::EADDRINUSE));
         }
 
-        let socket_handle = iface.add_socket(udp_socket);
+        let socket_handle = add_socket(iface, udp_socket).map_err(|err| {
+            if !ephemeral {
+                port_set.release_port(local_endpoint.addr, local_endpoint.port);
+            }
+            err
+        })?;
         trace!("UDP add socket {}", socket_handle);
 
-        let udp_socket = iface.get_socket::<UdpSocket>(socket_handle);
-        udp_socket
-            .bind(local_endpoint)
-            .expect("Can't bind udp socket to local endpoint");
-        trace!("UDP bind socket {}", socket_handle);
+        let mut data = UdpData::new(remote_endpoint);
+        if defer_bind {
+            // The port stays reserved, but the source address is only picked
+            // once the first datagram gets routed
+            data.deferred_endpoint = Some(local_endpoint);
+            trace!("UDP deferred bind socket {}", socket_handle);
+        } else if ephemeral {
+            let addr = local_endpoint.addr;
+            let result = port_set.bind_ephemeral(addr, |port| {
+                let endpoint = IpEndpoint::new(addr, port);
+                iface.get_socket::<UdpSocket>(socket_handle).bind(endpoint)
+            });
+            match result {
+                Ok(port) => local_endpoint.port = port,
+                Err(err) => {
+                    trace!("UDP can't bind socket {} to an ephemeral port", socket_handle);
+                    iface.remove_socket(socket_handle);
+                    return Err(SyscallError::new(match err {
+                        Some(_) => syscall::EADDRINUSE,
+                        None => syscall::EINVAL,
+                    }));
+                }
+            }
+            trace!("UDP bind socket {}", socket_handle);
+        } else {
+            let result = iface.get_socket::<UdpSocket>(socket_handle).bind(local_endpoint);
+            if let Err(err) = result {
+                trace!("UDP can't bind socket {}: {}", socket_handle, err);
+                iface.remove_socket(socket_handle);
+                port_set.release_port(local_endpoint.addr, local_endpoint.port);
+                return Err(SyscallError::new(syscall::EADDRNOTAVAIL));
+            }
+            trace!("UDP bind socket {}", socket_handle);
+        }
 
-        Ok((socket_handle, remote_endpoint))
+        Ok((socket_handle, data))
     }
 
     fn close_file(
@@ -107,8 +471,9 @@ impl<'a> SchemeSocket for UdpSocket<'a> {
         file: &SchemeFile<Self>,
         port_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<()> {
-        if let SchemeFile::Socket(_) = *file {
-            port_set.release_port(self.endpoint().port);
+        if let SchemeFile::Socket(ref file) = *file {
+            let endpoint = file.data.local_endpoint(self);
+            port_set.release_port(endpoint.addr, endpoint.port);
         }
         Ok(())
     }
@@ -116,15 +481,58 @@ impl<'a> SchemeSocket for UdpSocket<'a> {
     fn write_buf(
         &mut self,
         file: &mut SocketFile<Self::DataT>,
+        mtu: usize,
         buf: &[u8],
     ) -> SyscallResult<Option<usize>> {
-        if !file.data.is_specified() {
+        if !file.data.remote_endpoint.is_specified() {
             return Err(SyscallError::new(syscall::EADDRNOTAVAIL));
         }
-        if self.can_send() {
-            self.send_slice(buf, file.data).expect("Can't send slice");
-            Ok(Some(buf.len()))
-        } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
+        let segments = if file.data.vectored {
+            parse_segments(buf)?
+        } else {
+            vec![buf]
+        };
+        let len = segments.iter().map(|segment| segment.len()).sum::<usize>();
+        let segment_size = cmp::min(file.data.segment_size, max_datagram_size(mtu));
+        let split = segment_size > 0 && !file.data.vectored && len > 0;
+        if split {
+            if segment_size > self.payload_send_capacity() {
+                return Err(SyscallError::new(syscall::EMSGSIZE));
+            }
+        } else if len > max_datagram_size(mtu) || len > self.payload_send_capacity() {
+            return Err(SyscallError::new(syscall::EMSGSIZE));
+        }
+        if !self.is_open() {
+            if let Some(endpoint) = file.data.deferred_endpoint {
+                // A wildcard address lets the interface select the source
+                // address from the route to the destination on dispatch
+                self.bind(endpoint).map_err(smoltcp_err_to_syscall)?;
+                trace!("UDP deferred bind to {}", endpoint);
+            }
+        }
+        if split {
+            let sent = send_split(self, buf, segment_size, file.data.remote_endpoint)?;
+            if sent > 0 {
+                return Ok(Some(sent));
+            }
+        } else if self.can_send() {
+            // An empty buffer goes out as a zero-length datagram, which is no
+            // different to smoltcp than any other
+            match self.send(len, file.data.remote_endpoint) {
+                Ok(payload) => {
+                    let mut offset = 0;
+                    for segment in segments {
+                        payload[offset..offset + segment.len()].copy_from_slice(segment);
+                        offset += segment.len();
+                    }
+                    return Ok(Some(buf.len()));
+                }
+                // Not enough payload space left for this datagram yet
+                Err(smoltcp::Error::Exhausted) => (),
+                Err(err) => return Err(smoltcp_err_to_syscall(err)),
+            }
+        }
+        if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
             Err(SyscallError::new(syscall::EAGAIN))
         } else {
             Ok(None) // internally scheduled to re-read
@@ -136,8 +544,30 @@ impl<'a> SchemeSocket for UdpSocket<'a> {
         file: &mut SocketFile<Self::DataT>,
         buf: &mut [u8],
     ) -> SyscallResult<Option<usize>> {
-        if self.can_recv() {
-            let (length, _) = self.recv_slice(buf).expect("Can't receive slice");
+        file.data.last_datagram = None;
+        if file.data.read_shutdown {
+            return Ok(Some(0));
+        }
+        // Connected sockets drop datagrams from any other peer
+        drop_foreign(self, &file.data);
+        let first = self
+            .peek()
+            .map(|(payload, &source)| (source, payload.len()))
+            .ok();
+        if self.can_recv() && file.data.batched {
+            // Only the first datagram of a batch can get cut
+            let first_len = first.map_or(0, |(_, len)| len);
+            let count = recv_batch(self, &file.data, buf)?;
+            file.data.truncated = BATCH_HEADER_LEN + first_len > buf.len();
+            file.data.last_datagram = first;
+            Ok(Some(count))
+        } else if self.can_recv() {
+            // A zero-length datagram reads as 0 bytes, while having nothing
+            // to read blocks or fails with EAGAIN
+            let datagram_len = first.map_or(0, |(_, len)| len);
+            let (length, _) = self.recv_slice(buf).map_err(smoltcp_err_to_syscall)?;
+            file.data.truncated = datagram_len > length;
+            file.data.last_datagram = first;
             Ok(Some(length))
         } else if file.flags & syscall::O_NONBLOCK == syscall::O_NONBLOCK {
             Err(SyscallError::new(syscall::EAGAIN))
@@ -146,57 +576,272 @@ impl<'a> SchemeSocket for UdpSocket<'a> {
         }
     }
 
+    fn fsync(&mut self, _file: &mut SocketFile<Self::DataT>) -> SyscallResult<Option<usize>> {
+        Ok(Some(0))
+    }
+
     fn dup(
         iface: &mut SmolnetInterface,
         file: &mut SchemeFile<Self>,
         path: &str,
+        _query: &BTreeMap<&str, &str>,
         port_set: &mut Self::SchemeDataT,
     ) -> SyscallResult<DupResult<Self>> {
         trace!("duping...");
         let socket_handle = file.socket_handle();
         let file = match path {
+            "writev" => match *file {
+                SchemeFile::Socket(ref udp_handle) => {
+                    let mut data = udp_handle.data;
+                    data.vectored = true;
+                    SchemeFile::Socket(udp_handle.clone_with_data(data))
+                }
+                _ => return Err(SyscallError::new(syscall::EBADF)),
+            },
+            "recvmmsg" => match *file {
+                SchemeFile::Socket(ref udp_handle) => {
+                    let mut data = udp_handle.data;
+                    data.batched = true;
+                    SchemeFile::Socket(udp_handle.clone_with_data(data))
+                }
+                _ => return Err(SyscallError::new(syscall::EBADF)),
+            },
             _ => {
-                let remote_endpoint = parse_endpoint(path);
+                let remote_endpoint = parse_endpoint(path)?;
                 if let SchemeFile::Socket(ref udp_handle) = *file {
-                    SchemeFile::Socket(udp_handle.clone_with_data(
-                        if remote_endpoint.is_specified() {
-                            remote_endpoint
-                        } else {
-                            udp_handle.data
-                        },
-                    ))
+                    let mut data = udp_handle.data;
+                    if remote_endpoint.is_specified() {
+                        data.remote_endpoint = remote_endpoint;
+                    }
+                    SchemeFile::Socket(udp_handle.clone_with_data(data))
                 } else {
-                    SchemeFile::Socket(SocketFile::new_with_data(socket_handle, remote_endpoint))
+                    SchemeFile::Socket(SocketFile::new_with_data(
+                        socket_handle,
+                        UdpData::new(remote_endpoint),
+                    ))
                 }
             }
         };
 
         let endpoint = {
             let socket = iface.get_socket::<UdpSocket>(socket_handle);
-            socket.endpoint()
+            match file {
+                SchemeFile::Socket(ref udp_handle) => udp_handle.data.local_endpoint(socket),
+                _ => socket.endpoint(),
+            }
         };
 
         if let SchemeFile::Socket(_) = file {
-            port_set.acquire_port(endpoint.port);
+            port_set.acquire_port(endpoint.addr, endpoint.port);
         }
 
         Ok(Some((file, None)))
     }
 
+    fn describe(&self, file: &SocketFile<Self::DataT>) -> String {
+        format!(
+            "udp {} {} {}",
+            file.data.local_endpoint(self),
+            file.data.remote_endpoint,
+            if self.is_open() { "BOUND" } else { "UNBOUND" }
+        )
+    }
+
     fn fpath(&self, file: &SchemeFile<Self>, buf: &mut [u8]) -> SyscallResult<usize> {
         if let SchemeFile::Socket(ref socket_file) = *file {
-            let path = format!("udp:{}/{}", socket_file.data, self.endpoint());
-            let path = path.as_bytes();
-
-            let mut i = 0;
-            while i < buf.len() && i < path.len() {
-                buf[i] = path[i];
-                i += 1;
-            }
-
-            Ok(i)
+            let path = format!(
+                "udp:{}/{}",
+                socket_file.data.remote_endpoint,
+                socket_file.data.local_endpoint(self)
+            );
+            write_path(&path, buf)
         } else {
             Err(SyscallError::new(syscall::EBADF))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::str;
+    use syscall::data::TimeSpec;
+    use syscall::{self, SchemeBlockMut};
+
+    use super::super::harness::Harness;
+    use super::super::socket::{parse_timespec, time_before};
+    use super::UdpScheme;
+
+    const FLAGS: usize = syscall::O_RDWR | syscall::O_NONBLOCK;
+
+    /// Two sockets on the loopback network connected to each other.
+    fn socket_pair(scheme: &mut UdpScheme, port: u16) -> (usize, usize) {
+        let first = format!("127.0.0.1:{}/127.0.0.1:{}", port + 1, port);
+        let second = format!("127.0.0.1:{}/127.0.0.1:{}", port, port + 1);
+        (
+            scheme.open(&first, FLAGS, 0, 0).unwrap().unwrap(),
+            scheme.open(&second, FLAGS, 0, 0).unwrap().unwrap(),
+        )
+    }
+
+    #[test]
+    fn udp_echo() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let (server, client) = socket_pair(&mut scheme, 7000);
+
+        let mut buf = [0; 16];
+        assert_eq!(
+            scheme.read(server, &mut buf).unwrap_err().errno,
+            syscall::EAGAIN
+        );
+
+        assert_eq!(scheme.write(client, b"ping").unwrap(), Some(4));
+        harness.poll();
+        let count = scheme.read(server, &mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..count], b"ping");
+
+        assert_eq!(scheme.write(server, &buf[..count]).unwrap(), Some(4));
+        harness.poll();
+        let mut reply = [0; 16];
+        assert_eq!(scheme.read(client, &mut reply).unwrap(), Some(4));
+        assert_eq!(&reply[..4], b"ping");
+    }
+
+    #[test]
+    fn empty_datagram_round_trip() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let (server, client) = socket_pair(&mut scheme, 7010);
+
+        assert_eq!(scheme.write(client, &[]).unwrap(), Some(0));
+        harness.poll();
+        let mut buf = [0; 16];
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(0));
+        // The empty datagram was read, not a lack of one
+        assert_eq!(
+            scheme.read(server, &mut buf).unwrap_err().errno,
+            syscall::EAGAIN
+        );
+
+        assert_eq!(scheme.write(server, &[]).unwrap(), Some(0));
+        harness.poll();
+        assert_eq!(scheme.read(client, &mut buf).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn empty_datagram_is_timestamped() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let (server, client) = socket_pair(&mut scheme, 7020);
+        let timestamping = scheme.dup(server, b"timestamping").unwrap().unwrap();
+        assert_eq!(scheme.write(timestamping, &[1]).unwrap(), Some(1));
+
+        assert_eq!(scheme.write(client, &[]).unwrap(), Some(0));
+        assert_eq!(scheme.write(client, b"data").unwrap(), Some(4));
+        harness.poll();
+
+        let header = mem::size_of::<TimeSpec>();
+        let mut buf = [0; 64];
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(header));
+        let empty_time = parse_timespec(&buf[..header]).unwrap().0.unwrap();
+        assert!(time_before(&TimeSpec::default(), &empty_time));
+
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(header + 4));
+        assert_eq!(&buf[header..header + 4], b"data");
+        let data_time = parse_timespec(&buf[..header]).unwrap().0.unwrap();
+        assert!(!time_before(&data_time, &empty_time));
+    }
+
+    #[test]
+    fn datagram_over_ip_limit() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let (_server, client) = socket_pair(&mut scheme, 7060);
+
+        assert_eq!(
+            scheme.write(client, &vec![0; 70000]).unwrap_err().errno,
+            syscall::EMSGSIZE
+        );
+        // Nothing was queued in its place
+        assert_eq!(scheme.write(client, b"ping").unwrap(), Some(4));
+    }
+
+    #[test]
+    fn truncated_datagram() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let (server, client) = socket_pair(&mut scheme, 7070);
+        let truncated = scheme.dup(server, b"truncated").unwrap().unwrap();
+
+        // The largest datagram the loopback carries, as it never fragments
+        let mtu = harness.iface.borrow().device().get_ref().mtu();
+        let len = super::max_datagram_size(mtu);
+        assert_eq!(scheme.write(client, &vec![7; len]).unwrap(), Some(len));
+        assert_eq!(scheme.write(client, b"ping").unwrap(), Some(4));
+        harness.poll();
+
+        let mut buf = [0; 100];
+        let mut flag = [0xff];
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(100));
+        assert_eq!(&buf[..], &[7; 100][..]);
+        assert_eq!(scheme.read(truncated, &mut flag).unwrap(), Some(1));
+        assert_eq!(flag[0], 1);
+
+        // The rest of the datagram is gone, not read next
+        assert_eq!(scheme.read(server, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"ping");
+        assert_eq!(scheme.read(truncated, &mut flag).unwrap(), Some(1));
+        assert_eq!(flag[0], 0);
+    }
+
+    #[test]
+    fn ephemeral_local_port() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let fd = scheme.open("8.8.8.8:53/0", FLAGS, 0, 0).unwrap().unwrap();
+
+        let local_addr = scheme.dup(fd, b"local_addr").unwrap().unwrap();
+        let mut buf = [0; 64];
+        let len = scheme.read(local_addr, &mut buf).unwrap().unwrap();
+        let local = str::from_utf8(&buf[..len]).unwrap().to_string();
+        let port = local.rsplit(':').next().unwrap().parse::<u16>().unwrap();
+        assert!(port >= 49152, "{}", local);
+
+        let len = scheme.fpath(fd, &mut buf).unwrap().unwrap();
+        assert_eq!(
+            str::from_utf8(&buf[..len]).unwrap(),
+            format!("udp:8.8.8.8:53/{}", local)
+        );
+    }
+
+    #[test]
+    fn oversized_datagram_with_dont_fragment() {
+        let harness = Harness::new();
+        let (mut scheme, _scheme_file): (UdpScheme, _) = harness.scheme();
+        let (_server, client) = socket_pair(&mut scheme, 7030);
+        let df = scheme.dup(client, b"df").unwrap().unwrap();
+        let no_pmtud = scheme.dup(client, b"no_pmtud").unwrap().unwrap();
+
+        // Clearing the bit is kept apart from turning path MTU discovery off
+        let mut flag = [0xff];
+        assert_eq!(scheme.write(df, &[0]).unwrap(), Some(1));
+        assert_eq!(scheme.read(df, &mut flag).unwrap(), Some(1));
+        assert_eq!(flag[0], 0);
+        assert_eq!(scheme.read(no_pmtud, &mut flag).unwrap(), Some(1));
+        assert_eq!(flag[0], 0);
+
+        assert_eq!(scheme.write(df, &[1]).unwrap(), Some(1));
+        assert_eq!(scheme.write(no_pmtud, &[1]).unwrap(), Some(1));
+        assert_eq!(scheme.read(df, &mut flag).unwrap(), Some(1));
+        assert_eq!(flag[0], 1);
+
+        let mtu = harness.iface.borrow().device().get_ref().mtu();
+        let max = super::max_datagram_size(mtu);
+        assert_eq!(
+            scheme.write(client, &vec![0; max + 1]).unwrap_err().errno,
+            syscall::EMSGSIZE
+        );
+        assert_eq!(scheme.write(client, &vec![0; max]).unwrap(), Some(max));
+    }
+}